@@ -1,25 +1,117 @@
 use crate::balance::Balance;
+use crate::balance::BalanceConstraints;
 use crate::balance::BalanceDiff;
+use crate::balance::BalanceError;
+use crate::balance::ConstraintViolation;
+use crate::currency::Currency;
+use crate::deficit_policy::DeficitPolicy;
+use crate::dispute_expiry::DisputeDuration;
+use crate::dispute_expiry::DisputeExpiry;
+use crate::dispute_expiry::ExpiryAction;
+use crate::dispute_policy::DepositsOnly;
+use crate::dispute_policy::DisputableKind;
+use crate::dispute_policy::DisputePolicy;
+use crate::dispute_policy::DisputeStep;
+use crate::freeze_level::AccountOperation;
+use crate::freeze_level::FreezeLevel;
 use crate::funds::Funds;
 use crate::funds::FundsOpError;
+use crate::journal::AccountEvent;
 use crate::transaction::ClientID;
+use crate::transaction::Timestamp;
 use crate::transaction::TransactionID;
+use crate::transaction::TransferLeg;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::sync::Arc;
 use thiserror::Error;
 
 /// Represents the state of a deposit for traking disputes
 ///
 /// When a new deposit is made it starts in the `Undisputed` state.
 /// After a dispute transaction is processed, it moves to the `InDispute` state.
-/// Then it can move to either the `Resolve` or `Chargedback` state. These two states
-/// are considered terminal to avoid double spend. Disputes for transactions in these
-/// states will fail and be a no-op
-#[derive(Debug, PartialEq)]
+/// Disputes can be for part of the amount, in which case the rest can still be disputed
+/// by later disputes while the first ones are open. Resolves and chargebacks settle part or all
+/// of the disputed amount. Once all of it has been disputed and settled, the deposit moves to
+/// either the `Resolved` or `Chargedback` state (whichever settled it last). These two states
+/// are considered terminal to avoid double spend: a settled part can't be disputed again, and
+/// disputes for transactions in these states will fail and be a no-op
+///
+/// The one way out of them is re-presenting what was charged back, which moves the transaction
+/// to `Represented`. From there the chargeback is either reversed (`ChargebackReversed`), or
+/// contested with pre-arbitration (`PreArbitration`) and then reversed or upheld
+/// (`ChargebackUpheld`). A chargeback can only be re-presented once
+///
+/// Refunds return what's left undisputed of a deposit to the client. The deposit moves to the
+/// terminal `Refunded` state once all of it is refunded, while partial refunds leave the rest
+/// of it disputable
+///
+/// Withdrawals go through the same states when the account's `DisputePolicy` allows disputing them
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 enum DepositState {
+    /// Nothing is in dispute, holds the amount that can still be disputed
     Undisputed(Funds),
-    InDispute(Funds),
+    /// `disputed` is held by open disputes, `undisputed` can still be disputed
+    InDispute {
+        disputed: Funds,
+        undisputed: Funds,
+    },
     Resolved,
     Chargedback,
+    /// Holds the re-presented amount, i.e. everything that was charged back
+    Represented(Funds),
+    /// Holds the re-presented amount
+    PreArbitration(Funds),
+    ChargebackReversed,
+    ChargebackUpheld,
+    Refunded,
+}
+
+/// A transaction that can be disputed, along with the asset it moved
+///
+/// Disputes always apply to the asset of the original transaction
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+struct Disputable {
+    currency: Currency,
+    state: DepositState,
+    /// Part of the amount that was charged back and hasn't been reversed
+    #[serde(default)]
+    charged_back: Funds,
+    /// When the transaction happened, unknown for transactions applied without going through
+    /// `advance` (or saved before timestamps were recorded)
+    #[serde(default)]
+    timestamp: Option<Timestamp>,
+    /// When the transaction went into dispute, only set while it's `InDispute`
+    #[serde(default)]
+    disputed_since: Option<Instant>,
+}
+
+/// A point in the history of an account, by both of the clocks it keeps
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+struct Instant {
+    transactions: u64,
+    time: Option<Timestamp>,
+}
+
+/// Funds reserved by an authorization until they're captured, voided or the hold expires
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+struct Hold {
+    currency: Currency,
+    /// Part of the authorized amount that hasn't been captured yet
+    amount: Funds,
+    /// Value of the account's transaction counter from which the hold has expired
+    expires_at: Option<u64>,
+}
+
+/// Funds of a deposit that are pending until it clears
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+struct Uncleared {
+    currency: Currency,
+    amount: Funds,
+    /// Time from which the deposit has cleared, `None` if it only clears when told to
+    clears_at: Option<Timestamp>,
 }
 
 #[derive(Debug, PartialEq, Eq, Error)]
@@ -28,339 +120,2540 @@ pub enum AccountUpdateError {
     TransactionNotDisputable(TransactionID),
     #[error("Transaction {0} is not in dispute")]
     TransactionNotInDispute(TransactionID),
+    #[error("Dispute amounts must be positive")]
+    NonPositiveDisputeAmount,
+    #[error("Dispute exceeds the undisputed amount of transaction {0}")]
+    DisputeExceedsUndisputed(TransactionID),
+    #[error("Dispute of transaction {0} would leave the available funds negative")]
+    DisputeCausesDeficit(TransactionID),
+    #[error("Transaction {0} is past its dispute window")]
+    DisputeWindowClosed(TransactionID),
+    #[error("Amount exceeds the funds in dispute for transaction {0}")]
+    SettlementExceedsDispute(TransactionID),
+    #[error("Transaction {0} has no chargeback to re-present")]
+    TransactionNotChargedBack(TransactionID),
+    #[error("Transaction {0} can't be re-presented until all of it has been settled")]
+    ChargebackNotSettled(TransactionID),
+    #[error("Chargeback of transaction {0} was already re-presented")]
+    ChargebackAlreadyRepresented(TransactionID),
+    #[error("Chargeback of transaction {0} has not been re-presented")]
+    TransactionNotRepresented(TransactionID),
+    #[error("Transaction {0} is already in pre-arbitration")]
+    PreArbitrationAlreadyOpen(TransactionID),
+    #[error("Re-presentment of transaction {0} can't be charged back before pre-arbitration")]
+    TransactionNotInPreArbitration(TransactionID),
+    #[error("Transaction {0} can't be refunded (not a deposit or nothing left undisputed)")]
+    TransactionNotRefundable(TransactionID),
+    #[error("Refunds must be positive")]
+    NonPositiveRefund,
+    #[error("Refund exceeds what's left undisputed of transaction {0}")]
+    RefundExceedsRemaining(TransactionID),
     #[error("Deposit {0} already processed")]
     DepositAlreadyProcessed(TransactionID),
-    #[error("Insufficient funds")]
-    InsufficientFunds,
+    #[error("Deposit {0} has no pending funds to clear")]
+    DepositNotPending(TransactionID),
+    #[error("Deposit {0} hasn't cleared yet")]
+    DepositNotCleared(TransactionID),
+    #[error("Withdrawal {0} already processed")]
+    WithdrawalAlreadyProcessed(TransactionID),
+    #[error("{0}")]
+    ConstraintViolation(#[from] ConstraintViolation),
     #[error("Failed to update balance: {0}")]
     BalanceError(#[from] FundsOpError),
     #[error("Account is frozen")]
     AccountIsFrozen,
+    #[error("Account is not frozen")]
+    AccountIsNotFrozen,
+    #[error("Account is closed")]
+    AccountIsClosed,
+    #[error("Accounts can't be locked to the active level, use unlock instead")]
+    InvalidLockLevel,
     #[error("Negative deposits not allowed, use withdrawal instead")]
     NegativeDeposit,
     #[error("Negative withdrawals not allowed, use deposit instead")]
     NegativeWithdrawal,
+    #[error("Negative transfers not allowed, transfer from the other account instead")]
+    NegativeTransfer,
+    #[error("Authorizations must be positive")]
+    NonPositiveAuthorization,
+    #[error("Captures must be positive")]
+    NonPositiveCapture,
+    #[error("Authorization {0} already processed")]
+    AuthorizationAlreadyProcessed(TransactionID),
+    #[error("Authorization {0} has no open hold (unknown, fully captured, voided or expired)")]
+    HoldNotOpen(TransactionID),
+    #[error("Capture exceeds the funds still held by authorization {0}")]
+    CaptureExceedsHold(TransactionID),
+}
+
+impl From<BalanceError> for AccountUpdateError {
+    fn from(e: BalanceError) -> Self {
+        match e {
+            BalanceError::Funds(e) => AccountUpdateError::BalanceError(e),
+            BalanceError::Violation(v) => AccountUpdateError::ConstraintViolation(v),
+        }
+    }
+}
+
+/// An account's side of a transfer that has been checked but not applied yet
+///
+/// Carries the balance the account ends up with, computed with `Balance::apply` without touching
+/// the account, so both sides of a transfer can be checked before either of them is applied
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct PendingTransfer {
+    client: ClientID,
+    transaction: TransactionID,
+    leg: TransferLeg,
+    counterparty: ClientID,
+    currency: Currency,
+    diff: BalanceDiff,
+    balance: Balance,
+}
+
+impl PendingTransfer {
+    pub fn client(&self) -> ClientID {
+        self.client
+    }
+
+    pub fn transaction(&self) -> TransactionID {
+        self.transaction
+    }
+
+    pub fn leg(&self) -> TransferLeg {
+        self.leg
+    }
 }
 
 /// Represents a client's account and processes transactions
 ///
-/// Keeps track of the balance and disputes for an account.
-/// Note that we only allow for disputing deposits since disputing withdrawals
-/// could lead to double spend by increasing an account's available funds after
-/// they might have already been withdrawn.
+/// Keeps track of the balance of each asset the client holds and the disputes for the account.
+/// Every successful operation returns the `AccountEvent`s describing what changed.
+/// Which transactions can be disputed, and how disputes affect the balance, is decided by the
+/// account's `DisputePolicy`. By default only deposits can be disputed (see `DepositsOnly`).
 /// Also note that despoits in terminal states (`Resolved` or `Chargedback`) cannot
-/// be disputed again.
-#[derive(Debug)]
+/// be disputed again. Disputes, resolves and chargebacks can be for part of a transaction, up to
+/// what's left undisputed or in dispute respectively.
+///
+/// Authorizations move funds from available to held until they're captured (in one or more
+/// parts), voided or expire. Only holds that are still open are kept. Expiry is measured in
+/// transactions: every transaction processed for the account advances its counter (see `advance`)
+///
+/// Deposits can be made with a clearing period, during which their funds are pending rather
+/// than available (see `deposit_uncleared`). They clear once `advance` reaches their clearing
+/// time, or when told to with `clear`, and can't be disputed or refunded until then
+///
+/// `advance` also gives the account the time of the transaction being processed. Disputable
+/// transactions record it, so disputes can be limited to a window after the transaction (see
+/// `with_dispute_window`). Disputes can also be given a maximum duration, in transactions or
+/// time, after which `advance` settles them automatically (see `with_dispute_expiry`)
+///
+/// Which operations are allowed is controlled by the account's `FreezeLevel`, which applies to
+/// every asset in the account. Chargebacks raise the level to the configured chargeback level
+/// (by default only blocking withdrawals).
+///
+/// Disputes of funds that have already been spent leave the available funds negative. Whether
+/// that's allowed is decided by the account's `DeficitPolicy` (by default it is).
+///
+/// Accounts can be serialized for snapshots. Their `AccountConfig` is configuration rather than
+/// state, so it's left out and reset to the default when deserializing.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Account {
     client: ClientID,
-    balance: Balance,
-    deposits: HashMap<TransactionID, DepositState>,
-    frozen: bool,
+    balances: BTreeMap<Currency, Balance>,
+    deposits: HashMap<TransactionID, Disputable>,
+    withdrawals: HashMap<TransactionID, Disputable>,
+    freeze_level: FreezeLevel,
+    #[serde(skip)]
+    config: AccountConfig,
+    /// Whether a dispute left the account in deficit under `DeficitPolicy::Flag`
+    #[serde(default)]
+    in_deficit: bool,
+    /// Number of disputes settled automatically because they expired
+    #[serde(default)]
+    expired_disputes: u64,
+    /// Reason given by the last administrative `lock` or `unlock`
+    admin_reason: Option<String>,
+    #[serde(default)]
+    holds: HashMap<TransactionID, Hold>,
+    /// Deposits whose funds are still pending
+    #[serde(default)]
+    uncleared: HashMap<TransactionID, Uncleared>,
+    /// Limits every balance of the account is kept within
+    #[serde(default)]
+    constraints: BalanceConstraints,
+    /// Number of transactions applied to the account, the clock holds expire by
+    #[serde(default)]
+    transactions_processed: u64,
+    /// Time of the transaction being processed, as given to `advance`
+    #[serde(skip)]
+    time: Option<Timestamp>,
+}
+
+/// How an account handles disputes and chargebacks
+///
+/// Unlike the rest of an account this isn't part of its state, so it's usually built once and
+/// applied to every account with `Account::configure`
+#[derive(Debug, Clone)]
+pub struct AccountConfig {
+    dispute_policy: Arc<dyn DisputePolicy>,
+    chargeback_freeze_level: FreezeLevel,
+    unfreeze_on_reversal: bool,
+    deficit_policy: DeficitPolicy,
+    /// How many seconds after a transaction it can still be disputed
+    dispute_window: Option<u64>,
+    dispute_expiry: Option<DisputeExpiry>,
+}
+
+impl AccountConfig {
+    pub fn new() -> Self {
+        Self {
+            dispute_policy: Arc::new(DepositsOnly),
+            chargeback_freeze_level: FreezeLevel::WithdrawalsBlocked,
+            unfreeze_on_reversal: false,
+            deficit_policy: DeficitPolicy::default(),
+            dispute_window: None,
+            dispute_expiry: None,
+        }
+    }
+
+    pub fn with_dispute_policy(self, dispute_policy: Arc<dyn DisputePolicy>) -> Self {
+        Self {
+            dispute_policy,
+            ..self
+        }
+    }
+
+    /// Sets the level chargebacks freeze the account to
+    pub fn with_chargeback_freeze_level(self, chargeback_freeze_level: FreezeLevel) -> Self {
+        Self {
+            chargeback_freeze_level,
+            ..self
+        }
+    }
+
+    /// Sets whether reversing a chargeback can unfreeze the account (see
+    /// `Account::reverse_chargeback`)
+    pub fn with_unfreeze_on_reversal(self, unfreeze_on_reversal: bool) -> Self {
+        Self {
+            unfreeze_on_reversal,
+            ..self
+        }
+    }
+
+    /// Sets what happens to disputes that would leave the available funds negative
+    pub fn with_deficit_policy(self, deficit_policy: DeficitPolicy) -> Self {
+        Self {
+            deficit_policy,
+            ..self
+        }
+    }
+
+    /// Sets how many seconds after a transaction it can still be disputed, `None` for no limit
+    ///
+    /// Transactions with no recorded time can always be disputed
+    pub fn with_dispute_window(self, dispute_window: Option<u64>) -> Self {
+        Self {
+            dispute_window,
+            ..self
+        }
+    }
+
+    /// Sets how long disputes can stay open before `Account::advance` settles them, `None` for
+    /// no limit
+    pub fn with_dispute_expiry(self, dispute_expiry: Option<DisputeExpiry>) -> Self {
+        Self {
+            dispute_expiry,
+            ..self
+        }
+    }
+}
+
+impl Default for AccountConfig {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Account {
     pub fn new(client: ClientID) -> Self {
         Self {
             client,
-            balance: Balance::new(),
+            balances: BTreeMap::new(),
             deposits: HashMap::new(),
-            frozen: false,
+            withdrawals: HashMap::new(),
+            freeze_level: FreezeLevel::Active,
+            config: AccountConfig::new(),
+            in_deficit: false,
+            expired_disputes: 0,
+            admin_reason: None,
+            holds: HashMap::new(),
+            uncleared: HashMap::new(),
+            constraints: BalanceConstraints::new(),
+            transactions_processed: 0,
+            time: None,
+        }
+    }
+
+    pub fn with_config(self, config: AccountConfig) -> Self {
+        Self { config, ..self }
+    }
+
+    pub fn with_dispute_policy(self, dispute_policy: Arc<dyn DisputePolicy>) -> Self {
+        Self {
+            config: self.config.with_dispute_policy(dispute_policy),
+            ..self
+        }
+    }
+
+    /// Sets the level chargebacks freeze the account to
+    pub fn with_chargeback_freeze_level(self, chargeback_freeze_level: FreezeLevel) -> Self {
+        Self {
+            config: self
+                .config
+                .with_chargeback_freeze_level(chargeback_freeze_level),
+            ..self
+        }
+    }
+
+    /// Sets the limits the account's balances are kept within, the same for every asset
+    pub fn with_constraints(self, constraints: BalanceConstraints) -> Self {
+        Self {
+            constraints,
+            ..self
+        }
+    }
+
+    /// Sets whether reversing a chargeback can unfreeze the account (see `reverse_chargeback`)
+    pub fn with_unfreeze_on_reversal(self, unfreeze_on_reversal: bool) -> Self {
+        Self {
+            config: self.config.with_unfreeze_on_reversal(unfreeze_on_reversal),
+            ..self
+        }
+    }
+
+    /// Sets what happens to disputes that would leave the available funds negative
+    pub fn with_deficit_policy(self, deficit_policy: DeficitPolicy) -> Self {
+        Self {
+            config: self.config.with_deficit_policy(deficit_policy),
+            ..self
+        }
+    }
+
+    /// Sets how many seconds after a transaction it can still be disputed, `None` for no limit
+    ///
+    /// Transactions with no recorded time can always be disputed
+    pub fn with_dispute_window(self, dispute_window: Option<u64>) -> Self {
+        Self {
+            config: self.config.with_dispute_window(dispute_window),
+            ..self
+        }
+    }
+
+    /// Sets how long disputes can stay open before `advance` settles them, `None` for no limit
+    pub fn with_dispute_expiry(self, dispute_expiry: Option<DisputeExpiry>) -> Self {
+        Self {
+            config: self.config.with_dispute_expiry(dispute_expiry),
+            ..self
         }
     }
 
+    /// Same as `with_config`, for accounts that are borrowed rather than owned
+    pub fn configure(&mut self, config: &AccountConfig) {
+        self.config = config.clone();
+    }
+
     pub fn client_id(&self) -> ClientID {
         self.client
     }
 
     pub fn is_frozen(&self) -> bool {
-        self.frozen
+        self.freeze_level.is_frozen()
+    }
+
+    pub fn freeze_level(&self) -> FreezeLevel {
+        self.freeze_level
+    }
+
+    /// Whether the account was flagged by a dispute that left it in deficit
+    ///
+    /// Only set under `DeficitPolicy::Flag`, and stays set for the account to be reviewed
+    pub fn is_in_deficit(&self) -> bool {
+        self.in_deficit
+    }
+
+    /// Number of disputes on the account that were settled automatically because they expired
+    pub fn expired_disputes(&self) -> u64 {
+        self.expired_disputes
+    }
+
+    /// Balance of `currency`, which is zero for assets the client never held
+    pub fn balance(&self, currency: &Currency) -> Balance {
+        self.balances.get(currency).copied().unwrap_or_default()
+    }
+
+    /// Balance of every asset the client holds or held, ordered by currency
+    pub fn balances(&self) -> impl Iterator<Item = (&Currency, Balance)> {
+        self.balances
+            .iter()
+            .map(|(currency, balance)| (currency, *balance))
+    }
+
+    /// Asset of a transaction that can be disputed (whether or not it's disputable any more)
+    pub fn disputable_currency(&self, transaction_id: TransactionID) -> Option<&Currency> {
+        self.disputable(transaction_id)
+            .map(|(_, disputable)| &disputable.currency)
+    }
+
+    /// Asset of the funds held by an authorization that's still open
+    pub fn hold_currency(&self, transaction_id: TransactionID) -> Option<&Currency> {
+        self.holds.get(&transaction_id).map(|hold| &hold.currency)
+    }
+
+    pub fn constraints(&self) -> &BalanceConstraints {
+        &self.constraints
+    }
+
+    pub fn admin_reason(&self) -> Option<&str> {
+        self.admin_reason.as_deref()
+    }
+
+    pub fn deposit(
+        &mut self,
+        transaction_id: TransactionID,
+        currency: &Currency,
+        amount: Funds,
+    ) -> Result<Vec<AccountEvent>, AccountUpdateError> {
+        self.credit_deposit(
+            transaction_id,
+            currency,
+            amount,
+            BalanceDiff::new().with_available(amount),
+        )
+    }
+
+    /// Deposits `amount` as pending funds, which become available once the time given to
+    /// `advance` reaches `clears_at` or the deposit is `clear`ed
+    ///
+    /// Deposits without a `clears_at` only clear when told to
+    pub fn deposit_uncleared(
+        &mut self,
+        transaction_id: TransactionID,
+        currency: &Currency,
+        amount: Funds,
+        clears_at: Option<Timestamp>,
+    ) -> Result<Vec<AccountEvent>, AccountUpdateError> {
+        let events = self.credit_deposit(
+            transaction_id,
+            currency,
+            amount,
+            BalanceDiff::new().with_pending(amount),
+        )?;
+        self.uncleared.insert(
+            transaction_id,
+            Uncleared {
+                currency: currency.clone(),
+                amount,
+                clears_at,
+            },
+        );
+
+        Ok(events)
+    }
+
+    /// Makes the pending funds of a deposit available without waiting for it to clear
+    ///
+    /// Clearing settles funds the client already deposited, so it's allowed whatever the
+    /// account's freeze level
+    pub fn clear(
+        &mut self,
+        transaction_id: TransactionID,
+    ) -> Result<Vec<AccountEvent>, AccountUpdateError> {
+        let uncleared = self
+            .uncleared
+            .get(&transaction_id)
+            .ok_or(AccountUpdateError::DepositNotPending(transaction_id))?;
+        let currency = uncleared.currency.clone();
+        let diff = BalanceDiff::new()
+            .with_available(uncleared.amount)
+            .with_pending(-uncleared.amount);
+        self.apply(&currency, diff)?;
+        self.uncleared.remove(&transaction_id);
+
+        Ok(vec![AccountEvent::Cleared {
+            transaction: transaction_id,
+            currency,
+            diff,
+        }])
+    }
+
+    /// Applies a deposit of `amount` that credits the balance with `diff`
+    fn credit_deposit(
+        &mut self,
+        transaction_id: TransactionID,
+        currency: &Currency,
+        amount: Funds,
+        diff: BalanceDiff,
+    ) -> Result<Vec<AccountEvent>, AccountUpdateError> {
+        self.check_allowed(AccountOperation::Deposit)?;
+
+        if self.deposits.contains_key(&transaction_id) {
+            return Err(AccountUpdateError::DepositAlreadyProcessed(transaction_id));
+        }
+
+        if amount.is_negative() {
+            return Err(AccountUpdateError::NegativeDeposit);
+        }
+
+        self.apply(currency, diff)?;
+        self.deposits.insert(
+            transaction_id,
+            Disputable {
+                currency: currency.clone(),
+                state: DepositState::Undisputed(amount),
+                charged_back: Funds::ZERO,
+                timestamp: self.time,
+                disputed_since: None,
+            },
+        );
+
+        Ok(vec![AccountEvent::Deposited {
+            transaction: transaction_id,
+            currency: currency.clone(),
+            diff,
+        }])
+    }
+
+    pub fn withdraw(
+        &mut self,
+        transaction_id: TransactionID,
+        currency: &Currency,
+        amount: Funds,
+    ) -> Result<Vec<AccountEvent>, AccountUpdateError> {
+        self.check_allowed(AccountOperation::Withdraw)?;
+
+        if self.withdrawals.contains_key(&transaction_id) {
+            return Err(AccountUpdateError::WithdrawalAlreadyProcessed(
+                transaction_id,
+            ));
+        }
+
+        if amount.is_negative() {
+            return Err(AccountUpdateError::NegativeWithdrawal);
+        }
+
+        let diff = BalanceDiff::new().with_available(-amount);
+        self.apply(currency, diff)?;
+        // Withdrawals are only tracked if they can be disputed later on
+        if self
+            .config
+            .dispute_policy
+            .is_disputable(DisputableKind::Withdrawal)
+        {
+            self.withdrawals.insert(
+                transaction_id,
+                Disputable {
+                    currency: currency.clone(),
+                    state: DepositState::Undisputed(amount),
+                    charged_back: Funds::ZERO,
+                    timestamp: self.time,
+                    disputed_since: None,
+                },
+            );
+        }
+
+        Ok(vec![AccountEvent::Withdrew {
+            transaction: transaction_id,
+            currency: currency.clone(),
+            diff,
+        }])
+    }
+
+    /// Disputes `amount` of a transaction, or all of what's left undisputed if `None`
+    ///
+    /// Fails if the transaction is past the account's dispute window. If the dispute would leave
+    /// the available funds negative, it's handled according to the account's `DeficitPolicy`
+    pub fn dispute(
+        &mut self,
+        transaction_id: TransactionID,
+        amount: Option<Funds>,
+    ) -> Result<Vec<AccountEvent>, AccountUpdateError> {
+        self.check_allowed(AccountOperation::Dispute)?;
+        if self.uncleared.contains_key(&transaction_id) {
+            return Err(AccountUpdateError::DepositNotCleared(transaction_id));
+        }
+
+        let (kind, disputable) = match self.disputable(transaction_id) {
+            Some((kind, disputable)) if self.config.dispute_policy.is_disputable(kind) => {
+                (kind, disputable)
+            }
+            _ => return Err(AccountUpdateError::TransactionNotDisputable(transaction_id)),
+        };
+        if let (Some(window), Some(at), Some(now)) =
+            (self.config.dispute_window, disputable.timestamp, self.time)
+        {
+            if now.saturating_sub(at) > window {
+                return Err(AccountUpdateError::DisputeWindowClosed(transaction_id));
+            }
+        }
+        let currency = disputable.currency.clone();
+        let (disputed, undisputed) = match disputable.state {
+            DepositState::Undisputed(undisputed) => (Funds::ZERO, undisputed),
+            DepositState::InDispute {
+                disputed,
+                undisputed,
+            } if undisputed != Funds::ZERO => (disputed, undisputed),
+            _ => return Err(AccountUpdateError::TransactionNotDisputable(transaction_id)),
+        };
+        let amount = amount.unwrap_or(undisputed);
+        if amount <= Funds::ZERO {
+            return Err(AccountUpdateError::NonPositiveDisputeAmount);
+        }
+        if amount > undisputed {
+            return Err(AccountUpdateError::DisputeExceedsUndisputed(transaction_id));
+        }
+        let (amount, flag) = self.limit_deficit(transaction_id, kind, &currency, amount)?;
+
+        let state = DepositState::InDispute {
+            disputed: disputed.add(amount)?,
+            undisputed: undisputed.sub(amount)?,
+        };
+        let diff = self
+            .config
+            .dispute_policy
+            .balance_diff(kind, DisputeStep::Dispute, amount);
+        self.apply_dispute_step(&currency, diff)?;
+        self.set_state(kind, transaction_id, state);
+
+        let mut events = vec![AccountEvent::DisputeOpened {
+            transaction: transaction_id,
+            currency: currency.clone(),
+            diff,
+        }];
+        if flag && !self.in_deficit {
+            self.in_deficit = true;
+            events.push(AccountEvent::FlaggedInDeficit {
+                transaction: transaction_id,
+                currency,
+            });
+        }
+
+        Ok(events)
+    }
+
+    /// Resolves `amount` of the funds in dispute for a transaction, or all of them if `None`
+    pub fn resolve(
+        &mut self,
+        transaction_id: TransactionID,
+        amount: Option<Funds>,
+    ) -> Result<Vec<AccountEvent>, AccountUpdateError> {
+        self.check_allowed(AccountOperation::Resolve)?;
+
+        let (_, currency, diff) = self.settle(transaction_id, amount, DisputeStep::Resolve)?;
+        Ok(vec![AccountEvent::DisputeResolved {
+            transaction: transaction_id,
+            currency,
+            diff,
+        }])
+    }
+
+    /// Charges back `amount` of the funds in dispute for a transaction, or all of them if `None`
+    pub fn chargeback(
+        &mut self,
+        transaction_id: TransactionID,
+        amount: Option<Funds>,
+    ) -> Result<Vec<AccountEvent>, AccountUpdateError> {
+        self.check_allowed(AccountOperation::Chargeback)?;
+
+        // A chargeback in pre-arbitration upholds the original one, the amount doesn't matter
+        // since the whole re-presented amount is settled
+        match self.disputable(transaction_id).map(|(_, d)| &d.state) {
+            Some(DepositState::PreArbitration(_)) => return self.uphold_chargeback(transaction_id),
+            Some(DepositState::Represented(_)) => {
+                return Err(AccountUpdateError::TransactionNotInPreArbitration(
+                    transaction_id,
+                ))
+            }
+            _ => {}
+        }
+        let (kind, currency, diff) =
+            self.settle(transaction_id, amount, DisputeStep::Chargeback)?;
+        let mut events = vec![AccountEvent::ChargedBack {
+            transaction: transaction_id,
+            currency,
+            diff,
+        }];
+        if self.config.dispute_policy.freezes_on_chargeback(kind) {
+            let level = self
+                .freeze_level
+                .escalate(self.config.chargeback_freeze_level);
+            if level != self.freeze_level {
+                self.freeze_level = level;
+                events.push(AccountEvent::Frozen {
+                    level,
+                    reason: None,
+                });
+            }
+        }
+
+        Ok(events)
+    }
+
+    /// Returns `amount` of a deposit to the client, or all of what's left undisputed if `None`
+    ///
+    /// Unlike a chargeback this doesn't freeze the account. Funds in dispute can't be refunded
+    pub fn refund(
+        &mut self,
+        transaction_id: TransactionID,
+        amount: Option<Funds>,
+    ) -> Result<Vec<AccountEvent>, AccountUpdateError> {
+        self.check_allowed(AccountOperation::Refund)?;
+        if self.uncleared.contains_key(&transaction_id) {
+            return Err(AccountUpdateError::DepositNotCleared(transaction_id));
+        }
+
+        let disputable = self
+            .deposits
+            .get(&transaction_id)
+            .ok_or(AccountUpdateError::TransactionNotRefundable(transaction_id))?;
+        let (disputed, undisputed) = match disputable.state {
+            DepositState::Undisputed(undisputed) => (Funds::ZERO, undisputed),
+            DepositState::InDispute {
+                disputed,
+                undisputed,
+            } => (disputed, undisputed),
+            _ => return Err(AccountUpdateError::TransactionNotRefundable(transaction_id)),
+        };
+        if undisputed == Funds::ZERO {
+            return Err(AccountUpdateError::TransactionNotRefundable(transaction_id));
+        }
+        let amount = amount.unwrap_or(undisputed);
+        if amount <= Funds::ZERO {
+            return Err(AccountUpdateError::NonPositiveRefund);
+        }
+        if amount > undisputed {
+            return Err(AccountUpdateError::RefundExceedsRemaining(transaction_id));
+        }
+        let currency = disputable.currency.clone();
+
+        let undisputed = undisputed.sub(amount)?;
+        let state = if disputed != Funds::ZERO {
+            DepositState::InDispute {
+                disputed,
+                undisputed,
+            }
+        } else if undisputed != Funds::ZERO {
+            DepositState::Undisputed(undisputed)
+        } else {
+            DepositState::Refunded
+        };
+        let diff = BalanceDiff::new().with_available(-amount);
+        self.apply(&currency, diff)?;
+        self.set_state(DisputableKind::Deposit, transaction_id, state);
+
+        Ok(vec![AccountEvent::Refunded {
+            transaction: transaction_id,
+            currency,
+            diff,
+        }])
+    }
+
+    /// Re-presents the funds charged back for a transaction, holding them again until the
+    /// chargeback is reversed or upheld
+    ///
+    /// Only possible once the transaction has been fully settled, and only once
+    pub fn represent(
+        &mut self,
+        transaction_id: TransactionID,
+    ) -> Result<Vec<AccountEvent>, AccountUpdateError> {
+        self.check_allowed(AccountOperation::Chargeback)?;
+
+        let (kind, disputable) = self.disputable(transaction_id).ok_or(
+            AccountUpdateError::TransactionNotChargedBack(transaction_id),
+        )?;
+        let amount = disputable.charged_back;
+        match disputable.state {
+            DepositState::Represented(_)
+            | DepositState::PreArbitration(_)
+            | DepositState::ChargebackReversed
+            | DepositState::ChargebackUpheld => {
+                return Err(AccountUpdateError::ChargebackAlreadyRepresented(
+                    transaction_id,
+                ))
+            }
+            _ if amount == Funds::ZERO => {
+                return Err(AccountUpdateError::TransactionNotChargedBack(
+                    transaction_id,
+                ))
+            }
+            DepositState::Undisputed(_) | DepositState::InDispute { .. } => {
+                return Err(AccountUpdateError::ChargebackNotSettled(transaction_id))
+            }
+            DepositState::Resolved | DepositState::Chargedback | DepositState::Refunded => {}
+        }
+
+        let currency = disputable.currency.clone();
+        let diff = self
+            .config
+            .dispute_policy
+            .balance_diff(kind, DisputeStep::Represent, amount);
+        self.apply_dispute_step(&currency, diff)?;
+        self.set_state(kind, transaction_id, DepositState::Represented(amount));
+
+        Ok(vec![AccountEvent::ChargebackRepresented {
+            transaction: transaction_id,
+            currency,
+            diff,
+        }])
+    }
+
+    /// Contests a re-presentment, after which the chargeback is either reversed or upheld
+    pub fn pre_arbitration(
+        &mut self,
+        transaction_id: TransactionID,
+    ) -> Result<Vec<AccountEvent>, AccountUpdateError> {
+        self.check_allowed(AccountOperation::Chargeback)?;
+
+        let (kind, currency, amount) = match self.disputable(transaction_id) {
+            Some((kind, disputable)) => match disputable.state {
+                DepositState::Represented(amount) => (kind, disputable.currency.clone(), amount),
+                DepositState::PreArbitration(_) => {
+                    return Err(AccountUpdateError::PreArbitrationAlreadyOpen(
+                        transaction_id,
+                    ))
+                }
+                _ => {
+                    return Err(AccountUpdateError::TransactionNotRepresented(
+                        transaction_id,
+                    ))
+                }
+            },
+            None => {
+                return Err(AccountUpdateError::TransactionNotRepresented(
+                    transaction_id,
+                ))
+            }
+        };
+        self.set_state(kind, transaction_id, DepositState::PreArbitration(amount));
+
+        Ok(vec![AccountEvent::PreArbitrationOpened {
+            transaction: transaction_id,
+            currency,
+        }])
+    }
+
+    /// Settles a re-presented chargeback in the client's favour
+    ///
+    /// With `with_unfreeze_on_reversal`, this also unfreezes the account if it's at the level
+    /// chargebacks freeze it to and no other chargeback stands
+    pub fn reverse_chargeback(
+        &mut self,
+        transaction_id: TransactionID,
+    ) -> Result<Vec<AccountEvent>, AccountUpdateError> {
+        self.check_allowed(AccountOperation::Chargeback)?;
+
+        let (kind, currency, amount) = match self.disputable(transaction_id) {
+            Some((
+                kind,
+                &Disputable {
+                    ref currency,
+                    state: DepositState::Represented(amount) | DepositState::PreArbitration(amount),
+                    ..
+                },
+            )) => (kind, currency.clone(), amount),
+            _ => {
+                return Err(AccountUpdateError::TransactionNotRepresented(
+                    transaction_id,
+                ))
+            }
+        };
+
+        let diff =
+            self.config
+                .dispute_policy
+                .balance_diff(kind, DisputeStep::ReverseChargeback, amount);
+        self.apply_dispute_step(&currency, diff)?;
+        self.set_state(kind, transaction_id, DepositState::ChargebackReversed);
+        if let Some(disputable) = self.disputable_mut(kind, transaction_id) {
+            disputable.charged_back = Funds::ZERO;
+        }
+
+        let mut events = vec![AccountEvent::ChargebackReversed {
+            transaction: transaction_id,
+            currency,
+            diff,
+        }];
+        if self.config.unfreeze_on_reversal
+            && self.config.dispute_policy.freezes_on_chargeback(kind)
+            && self.freeze_level.is_frozen()
+            && self.freeze_level == self.config.chargeback_freeze_level
+            && !self.has_standing_chargebacks()
+        {
+            self.freeze_level = FreezeLevel::Active;
+            events.push(AccountEvent::Unfrozen { reason: None });
+        }
+
+        Ok(events)
+    }
+
+    /// Reserves `amount` of `currency` by moving it from available to held
+    ///
+    /// The hold expires once `expires_after` more transactions have been processed for the account,
+    /// or never if it's `None`
+    pub fn authorize(
+        &mut self,
+        transaction_id: TransactionID,
+        currency: &Currency,
+        amount: Funds,
+        expires_after: Option<u64>,
+    ) -> Result<Vec<AccountEvent>, AccountUpdateError> {
+        self.check_allowed(AccountOperation::Authorize)?;
+
+        if self.holds.contains_key(&transaction_id) {
+            return Err(AccountUpdateError::AuthorizationAlreadyProcessed(
+                transaction_id,
+            ));
+        }
+
+        if amount <= Funds::ZERO {
+            return Err(AccountUpdateError::NonPositiveAuthorization);
+        }
+
+        let diff = BalanceDiff::new().with_available(-amount).with_held(amount);
+        self.apply(currency, diff)?;
+        self.holds.insert(
+            transaction_id,
+            Hold {
+                currency: currency.clone(),
+                amount,
+                expires_at: expires_after.map(|n| self.current_transaction().saturating_add(n)),
+            },
+        );
+
+        Ok(vec![AccountEvent::Authorized {
+            transaction: transaction_id,
+            currency: currency.clone(),
+            diff,
+        }])
+    }
+
+    /// Settles `amount` of the funds held by an authorization, or all of them if `None`
+    ///
+    /// Whatever isn't captured stays on hold, to be captured later on, voided or to expire
+    pub fn capture(
+        &mut self,
+        transaction_id: TransactionID,
+        amount: Option<Funds>,
+    ) -> Result<Vec<AccountEvent>, AccountUpdateError> {
+        self.check_allowed(AccountOperation::Capture)?;
+
+        let hold = self
+            .holds
+            .get(&transaction_id)
+            .ok_or(AccountUpdateError::HoldNotOpen(transaction_id))?;
+        let amount = amount.unwrap_or(hold.amount);
+        if amount <= Funds::ZERO {
+            return Err(AccountUpdateError::NonPositiveCapture);
+        }
+        if amount > hold.amount {
+            return Err(AccountUpdateError::CaptureExceedsHold(transaction_id));
+        }
+
+        let currency = hold.currency.clone();
+        let remaining = hold.amount.sub(amount)?;
+        let diff = BalanceDiff::new().with_held(-amount);
+        self.apply(&currency, diff)?;
+        if remaining == Funds::ZERO {
+            self.holds.remove(&transaction_id);
+        } else if let Some(hold) = self.holds.get_mut(&transaction_id) {
+            hold.amount = remaining;
+        }
+
+        Ok(vec![AccountEvent::Captured {
+            transaction: transaction_id,
+            currency,
+            diff,
+        }])
     }
 
-    pub fn balance(&self) -> Balance {
-        self.balance
+    /// Releases the funds still held by an authorization
+    pub fn void(
+        &mut self,
+        transaction_id: TransactionID,
+    ) -> Result<Vec<AccountEvent>, AccountUpdateError> {
+        self.check_allowed(AccountOperation::Void)?;
+
+        let (currency, diff) = self.release(transaction_id)?;
+        Ok(vec![AccountEvent::Voided {
+            transaction: transaction_id,
+            currency,
+            diff,
+        }])
+    }
+
+    /// Moves the account to the transaction about to be processed at `time`, clearing the deposits,
+    /// releasing the holds and settling the disputes that are due by then
+    ///
+    /// Should be called before the transaction is applied, whether it succeeds or not. The
+    /// transaction only counts towards expiry once it's applied (see `count_transaction`), so a
+    /// failed one leaves the account as it was unless something was due
+    pub fn advance(&mut self, time: Timestamp) -> Vec<AccountEvent> {
+        self.settle_due(time, self.current_transaction())
+    }
+
+    /// Clears the deposits and settles the disputes that are due by `time`, without a transaction
+    /// being processed
+    ///
+    /// Deadlines counted in transactions were already checked by the last transaction, so this
+    /// only catches up with the ones counted in time, e.g. before reporting an idle account
+    pub fn catch_up(&mut self, time: Timestamp) -> Vec<AccountEvent> {
+        self.settle_due(time, self.transactions_processed)
+    }
+
+    /// Clears the deposits, releases the holds and settles the disputes that are due by `time`
+    /// and the `now`th transaction applied to the account
+    fn settle_due(&mut self, time: Timestamp, now: u64) -> Vec<AccountEvent> {
+        self.time = Some(time);
+
+        let mut cleared: Vec<TransactionID> = self
+            .uncleared
+            .iter()
+            .filter(|(_, uncleared)| uncleared.clears_at.is_some_and(|at| at <= time))
+            .map(|(&id, _)| id)
+            .collect();
+        cleared.sort_unstable();
+        // A deposit that can't be cleared stays pending, and is retried on the next transaction
+        let mut events: Vec<AccountEvent> = cleared
+            .into_iter()
+            .filter_map(|transaction_id| self.clear(transaction_id).ok())
+            .flatten()
+            .collect();
+
+        let mut expired: Vec<(u64, TransactionID)> = self
+            .holds
+            .iter()
+            .filter_map(|(&id, hold)| hold.expires_at.map(|at| (at, id)))
+            .filter(|&(at, _)| at <= now)
+            .collect();
+        // Release them in a deterministic order
+        expired.sort_unstable();
+
+        for (_, transaction_id) in expired {
+            // A hold that can't be released stays open, and is retried on the next transaction
+            if let Ok((currency, diff)) = self.release(transaction_id) {
+                events.push(AccountEvent::HoldExpired {
+                    transaction: transaction_id,
+                    currency,
+                    diff,
+                });
+            }
+        }
+        events.extend(self.expire_disputes(now));
+
+        events
+    }
+
+    /// Counts a transaction applied to the account, see `advance`
+    pub fn count_transaction(&mut self) {
+        self.transactions_processed += 1;
+    }
+
+    /// Number of the transaction being processed, counting from the first one applied
+    fn current_transaction(&self) -> u64 {
+        self.transactions_processed.saturating_add(1)
+    }
+
+    /// Settles the disputes that have been open for longer than the account's dispute expiry by
+    /// the `transactions`th transaction applied to the account
+    fn expire_disputes(&mut self, transactions: u64) -> Vec<AccountEvent> {
+        let expiry = match self.config.dispute_expiry {
+            Some(expiry) => expiry,
+            None => return vec![],
+        };
+        let now = Instant {
+            transactions,
+            time: self.time,
+        };
+        let mut expired: Vec<(TransactionID, Currency)> = self
+            .deposits
+            .iter()
+            .chain(self.withdrawals.iter())
+            .filter(|(_, disputable)| match disputable.disputed_since {
+                Some(since) => has_expired(expiry.max_duration(), since, now),
+                None => false,
+            })
+            .map(|(&id, disputable)| (id, disputable.currency.clone()))
+            .collect();
+        // Settle them in a deterministic order
+        expired.sort_unstable();
+
+        let mut events = vec![];
+        for (transaction_id, currency) in expired {
+            let settled = match expiry.action() {
+                ExpiryAction::Resolve => self.resolve(transaction_id, None),
+                ExpiryAction::Chargeback => self.chargeback(transaction_id, None),
+            };
+            // A dispute that can't be settled (e.g. on a closed account) stays open, and is
+            // retried on the next transaction
+            if let Ok(settled) = settled {
+                self.expired_disputes += 1;
+                events.push(AccountEvent::DisputeExpired {
+                    transaction: transaction_id,
+                    currency,
+                    action: expiry.action(),
+                });
+                events.extend(settled);
+            }
+        }
+
+        events
+    }
+
+    /// Checks this account's side of a transfer of `amount` with `counterparty`
+    ///
+    /// The account is left untouched, the transfer is only applied by `commit_transfer`
+    pub fn prepare_transfer(
+        &self,
+        transaction_id: TransactionID,
+        leg: TransferLeg,
+        counterparty: ClientID,
+        currency: &Currency,
+        amount: Funds,
+    ) -> Result<PendingTransfer, AccountUpdateError> {
+        let (operation, diff) = match leg {
+            TransferLeg::Debit => (
+                AccountOperation::Withdraw,
+                BalanceDiff::new().with_available(-amount),
+            ),
+            TransferLeg::Credit => (
+                AccountOperation::Deposit,
+                BalanceDiff::new().with_available(amount),
+            ),
+        };
+        self.check_allowed(operation)?;
+
+        if amount.is_negative() {
+            return Err(AccountUpdateError::NegativeTransfer);
+        }
+
+        let balance = self.balance(currency);
+        Ok(PendingTransfer {
+            client: self.client,
+            transaction: transaction_id,
+            leg,
+            counterparty,
+            currency: currency.clone(),
+            diff,
+            balance: balance.apply(diff, &self.constraints)?,
+        })
+    }
+
+    /// Applies a transfer checked by `prepare_transfer`
+    ///
+    /// The account must not have changed since the transfer was prepared, otherwise those changes
+    /// are overwritten
+    pub fn commit_transfer(&mut self, transfer: PendingTransfer) -> Vec<AccountEvent> {
+        debug_assert_eq!(transfer.client, self.client);
+        self.balances
+            .insert(transfer.currency.clone(), transfer.balance);
+
+        let (transaction, currency, diff) =
+            (transfer.transaction, transfer.currency, transfer.diff);
+        vec![match transfer.leg {
+            TransferLeg::Debit => AccountEvent::TransferredOut {
+                transaction,
+                to: transfer.counterparty,
+                currency,
+                diff,
+            },
+            TransferLeg::Credit => AccountEvent::TransferredIn {
+                transaction,
+                from: transfer.counterparty,
+                currency,
+                diff,
+            },
+        }]
+    }
+
+    /// Moves the account to `level` on request of an operator
+    ///
+    /// Unlike chargebacks, locks can also lower the level of an already frozen account
+    /// (e.g. from `FullyLocked` to `WithdrawalsBlocked`)
+    pub fn lock(
+        &mut self,
+        reason: String,
+        level: FreezeLevel,
+    ) -> Result<Vec<AccountEvent>, AccountUpdateError> {
+        if level == FreezeLevel::Active {
+            return Err(AccountUpdateError::InvalidLockLevel);
+        }
+
+        match self.freeze_level {
+            FreezeLevel::Closed => return Err(AccountUpdateError::AccountIsClosed),
+            current if current == level => return Err(AccountUpdateError::AccountIsFrozen),
+            _ => {}
+        }
+
+        self.freeze_level = level;
+        self.admin_reason = Some(reason.clone());
+
+        Ok(vec![AccountEvent::Frozen {
+            level,
+            reason: Some(reason),
+        }])
+    }
+
+    /// Reinstates a frozen account, e.g. after a chargeback has been settled out of band
+    pub fn unlock(&mut self, reason: String) -> Result<Vec<AccountEvent>, AccountUpdateError> {
+        match self.freeze_level {
+            FreezeLevel::Active => return Err(AccountUpdateError::AccountIsNotFrozen),
+            FreezeLevel::Closed => return Err(AccountUpdateError::AccountIsClosed),
+            _ => {}
+        }
+
+        self.freeze_level = FreezeLevel::Active;
+        self.admin_reason = Some(reason.clone());
+
+        Ok(vec![AccountEvent::Unfrozen {
+            reason: Some(reason),
+        }])
+    }
+
+    fn check_allowed(&self, operation: AccountOperation) -> Result<(), AccountUpdateError> {
+        match self.freeze_level {
+            level if level.allows(operation) => Ok(()),
+            FreezeLevel::Closed => Err(AccountUpdateError::AccountIsClosed),
+            _ => Err(AccountUpdateError::AccountIsFrozen),
+        }
+    }
+
+    /// Applies the `DeficitPolicy` to a dispute of `amount`
+    ///
+    /// Returns the amount to dispute and whether the account should be flagged
+    fn limit_deficit(
+        &self,
+        transaction_id: TransactionID,
+        kind: DisputableKind,
+        currency: &Currency,
+        amount: Funds,
+    ) -> Result<(Funds, bool), AccountUpdateError> {
+        let debit = self
+            .config
+            .dispute_policy
+            .balance_diff(kind, DisputeStep::Dispute, amount)
+            .available()
+            .unwrap_or(Funds::ZERO);
+        let available = self.balance(currency).available();
+        if !available.add(debit)?.is_negative() {
+            return Ok((amount, false));
+        }
+
+        match self.config.deficit_policy {
+            DeficitPolicy::Allow => Ok((amount, false)),
+            DeficitPolicy::Flag => Ok((amount, true)),
+            DeficitPolicy::Cap if available > Funds::ZERO => Ok((available, false)),
+            DeficitPolicy::Reject | DeficitPolicy::Cap => {
+                Err(AccountUpdateError::DisputeCausesDeficit(transaction_id))
+            }
+        }
+    }
+
+    /// Applies `diff` to the balance of `currency`, leaving it untouched if that fails
+    fn apply(&mut self, currency: &Currency, diff: BalanceDiff) -> Result<(), BalanceError> {
+        self.apply_with(currency, diff, self.constraints)
+    }
+
+    /// Applies a step of a dispute, which is imposed on the client rather than requested by them
+    ///
+    /// These aren't held to the limits on available funds, e.g. disputing a deposit that has
+    /// already been withdrawn leaves the available funds negative
+    fn apply_dispute_step(
+        &mut self,
+        currency: &Currency,
+        diff: BalanceDiff,
+    ) -> Result<(), BalanceError> {
+        self.apply_with(currency, diff, self.constraints.without_available_limits())
+    }
+
+    fn apply_with(
+        &mut self,
+        currency: &Currency,
+        diff: BalanceDiff,
+        constraints: BalanceConstraints,
+    ) -> Result<(), BalanceError> {
+        let balance = self.balance(currency).apply(diff, &constraints)?;
+        self.balances.insert(currency.clone(), balance);
+        Ok(())
+    }
+
+    /// Moves the funds still held by an authorization back to available and drops the hold
+    fn release(
+        &mut self,
+        transaction_id: TransactionID,
+    ) -> Result<(Currency, BalanceDiff), AccountUpdateError> {
+        let hold = self
+            .holds
+            .get(&transaction_id)
+            .ok_or(AccountUpdateError::HoldNotOpen(transaction_id))?;
+        let currency = hold.currency.clone();
+        let diff = BalanceDiff::new()
+            .with_available(hold.amount)
+            .with_held(-hold.amount);
+        self.apply(&currency, diff)?;
+        self.holds.remove(&transaction_id);
+
+        Ok((currency, diff))
+    }
+
+    /// Resolves or charges back part of the funds in dispute for a transaction
+    ///
+    /// The transaction only reaches a terminal state once nothing is left in dispute or undisputed
+    fn settle(
+        &mut self,
+        transaction_id: TransactionID,
+        amount: Option<Funds>,
+        step: DisputeStep,
+    ) -> Result<(DisputableKind, Currency, BalanceDiff), AccountUpdateError> {
+        let (kind, disputable) = self
+            .disputable(transaction_id)
+            .ok_or(AccountUpdateError::TransactionNotInDispute(transaction_id))?;
+        let (disputed, undisputed) = match disputable.state {
+            DepositState::InDispute {
+                disputed,
+                undisputed,
+            } => (disputed, undisputed),
+            _ => return Err(AccountUpdateError::TransactionNotInDispute(transaction_id)),
+        };
+        let amount = amount.unwrap_or(disputed);
+        if amount <= Funds::ZERO {
+            return Err(AccountUpdateError::NonPositiveDisputeAmount);
+        }
+        if amount > disputed {
+            return Err(AccountUpdateError::SettlementExceedsDispute(transaction_id));
+        }
+
+        let currency = disputable.currency.clone();
+        let charged_back = match step {
+            DisputeStep::Chargeback => disputable.charged_back.add(amount)?,
+            _ => disputable.charged_back,
+        };
+        let disputed = disputed.sub(amount)?;
+        let state = if disputed != Funds::ZERO {
+            DepositState::InDispute {
+                disputed,
+                undisputed,
+            }
+        } else if undisputed != Funds::ZERO {
+            DepositState::Undisputed(undisputed)
+        } else if step == DisputeStep::Chargeback {
+            DepositState::Chargedback
+        } else {
+            DepositState::Resolved
+        };
+        let diff = self.config.dispute_policy.balance_diff(kind, step, amount);
+        self.apply_dispute_step(&currency, diff)?;
+        self.set_state(kind, transaction_id, state);
+        if let Some(disputable) = self.disputable_mut(kind, transaction_id) {
+            disputable.charged_back = charged_back;
+        }
+
+        Ok((kind, currency, diff))
+    }
+
+    /// Settles a chargeback in pre-arbitration against the client
+    fn uphold_chargeback(
+        &mut self,
+        transaction_id: TransactionID,
+    ) -> Result<Vec<AccountEvent>, AccountUpdateError> {
+        let (kind, currency, amount) = match self.disputable(transaction_id) {
+            Some((
+                kind,
+                &Disputable {
+                    ref currency,
+                    state: DepositState::PreArbitration(amount),
+                    ..
+                },
+            )) => (kind, currency.clone(), amount),
+            _ => return Err(AccountUpdateError::TransactionNotInDispute(transaction_id)),
+        };
+
+        let diff =
+            self.config
+                .dispute_policy
+                .balance_diff(kind, DisputeStep::UpholdChargeback, amount);
+        self.apply_dispute_step(&currency, diff)?;
+        self.set_state(kind, transaction_id, DepositState::ChargebackUpheld);
+
+        Ok(vec![AccountEvent::ChargebackUpheld {
+            transaction: transaction_id,
+            currency,
+            diff,
+        }])
+    }
+
+    /// Whether a chargeback that froze the account still stands
+    fn has_standing_chargebacks(&self) -> bool {
+        [
+            (DisputableKind::Deposit, &self.deposits),
+            (DisputableKind::Withdrawal, &self.withdrawals),
+        ]
+        .into_iter()
+        .any(|(kind, disputables)| {
+            self.config.dispute_policy.freezes_on_chargeback(kind)
+                && disputables
+                    .values()
+                    .any(|disputable| disputable.charged_back != Funds::ZERO)
+        })
+    }
+
+    fn disputable(&self, transaction_id: TransactionID) -> Option<(DisputableKind, &Disputable)> {
+        self.deposits
+            .get(&transaction_id)
+            .map(|d| (DisputableKind::Deposit, d))
+            .or_else(|| {
+                self.withdrawals
+                    .get(&transaction_id)
+                    .map(|d| (DisputableKind::Withdrawal, d))
+            })
+    }
+
+    fn disputable_mut(
+        &mut self,
+        kind: DisputableKind,
+        transaction_id: TransactionID,
+    ) -> Option<&mut Disputable> {
+        match kind {
+            DisputableKind::Deposit => self.deposits.get_mut(&transaction_id),
+            DisputableKind::Withdrawal => self.withdrawals.get_mut(&transaction_id),
+        }
+    }
+
+    fn set_state(
+        &mut self,
+        kind: DisputableKind,
+        transaction_id: TransactionID,
+        state: DepositState,
+    ) {
+        let now = Instant {
+            transactions: self.current_transaction(),
+            time: self.time,
+        };
+        if let Some(disputable) = self.disputable_mut(kind, transaction_id) {
+            // Later partial disputes don't restart the clock of the ones already open
+            disputable.disputed_since = match state {
+                DepositState::InDispute { .. } => disputable.disputed_since.or(Some(now)),
+                _ => None,
+            };
+            disputable.state = state;
+        }
+    }
+}
+
+/// Whether a dispute open since `since` has lasted `max_duration` by `now`
+fn has_expired(max_duration: DisputeDuration, since: Instant, now: Instant) -> bool {
+    match max_duration {
+        DisputeDuration::Transactions(transactions) => {
+            now.transactions >= since.transactions.saturating_add(transactions)
+        }
+        DisputeDuration::Seconds(seconds) => match (since.time, now.time) {
+            (Some(since), Some(now)) => now.saturating_sub(since) >= seconds,
+            _ => false,
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dispute_policy::WithdrawalsDisputable;
+    use crate::funds::Funds;
+    use rust_decimal_macros::dec;
+
+    fn usd() -> Currency {
+        Currency::new("USD")
+    }
+
+    #[test]
+    fn test_deposit() {
+        let mut account = Account::new(42);
+        account
+            .deposit(1, &usd(), Funds::new(dec!(1.5)))
+            .expect("Deposit to succeed");
+        assert_eq!(account.balance(&usd()).available(), Funds::new(dec!(1.5)));
+    }
+
+    #[test]
+    fn test_negative_deposit() {
+        let mut account = Account::new(42);
+        assert_eq!(
+            account.deposit(1, &usd(), Funds::new(dec!(-1.5))),
+            Err(AccountUpdateError::NegativeDeposit),
+        );
+        assert_eq!(account.balance(&usd()).available(), Funds::new(0));
+    }
+
+    #[test]
+    fn test_withdrawal() {
+        let mut account = Account::new(42);
+        account
+            .deposit(1, &usd(), Funds::new(dec!(1.5)))
+            .expect("Deposit to succeed");
+        account
+            .withdraw(2, &usd(), Funds::new(dec!(1.0)))
+            .expect("Withrawal to succeed");
+        assert_eq!(account.balance(&usd()).available(), Funds::new(dec!(0.5)));
+    }
+
+    #[test]
+    fn negative_withdrawal() {
+        let mut account = Account::new(42);
+        account
+            .deposit(1, &usd(), Funds::new(dec!(1.5)))
+            .expect("Deposit to succeed");
+        assert_eq!(
+            account.withdraw(2, &usd(), Funds::new(dec!(-1.0))),
+            Err(AccountUpdateError::NegativeWithdrawal),
+        );
+        assert_eq!(account.balance(&usd()).available(), Funds::new(dec!(1.5)));
+    }
+
+    #[test]
+    fn test_withdrawal_insufficient_funds() {
+        let mut account = Account::new(42);
+        assert_eq!(
+            account.withdraw(1, &usd(), Funds::new(dec!(1.5))),
+            Err(AccountUpdateError::ConstraintViolation(
+                ConstraintViolation::OverdraftLimitExceeded(Funds::ZERO)
+            )),
+        );
+    }
+
+    #[test]
+    fn test_dispute() {
+        let mut account = Account::new(42);
+        account
+            .deposit(1, &usd(), Funds::new(dec!(1.5)))
+            .expect("Deposit to succeed");
+        assert_eq!(
+            account.deposits.get(&1).map(|d| &d.state),
+            Some(&DepositState::Undisputed(Funds::new(dec!(1.5))))
+        );
+
+        account.dispute(1, None).expect("Dispute to succeed");
+        assert_eq!(
+            account.deposits.get(&1).map(|d| &d.state),
+            Some(&DepositState::InDispute {
+                disputed: Funds::new(dec!(1.5)),
+                undisputed: Funds::ZERO,
+            })
+        );
+        assert_eq!(account.balance(&usd()).available(), Funds::new(dec!(0.0)));
+        assert_eq!(account.balance(&usd()).held(), Funds::new(dec!(1.5)));
+    }
+
+    #[test]
+    fn test_invalid_dispute_non_existent_transaction() {
+        let mut account = Account::new(42);
+        assert_eq!(
+            account.dispute(1, None),
+            Err(AccountUpdateError::TransactionNotDisputable(1))
+        );
+    }
+
+    #[test]
+    fn test_resolve() {
+        let mut account = Account::new(42);
+        account
+            .deposit(1, &usd(), Funds::new(dec!(1.5)))
+            .expect("Deposit to succeed");
+        assert_eq!(
+            account.deposits.get(&1).map(|d| &d.state),
+            Some(&DepositState::Undisputed(Funds::new(dec!(1.5))))
+        );
+
+        account.dispute(1, None).expect("Dispute to succeed");
+        account.resolve(1, None).expect("Resolve to succeed");
+        assert_eq!(
+            account.deposits.get(&1).map(|d| &d.state),
+            Some(&DepositState::Resolved)
+        );
+        assert_eq!(account.balance(&usd()).available(), Funds::new(dec!(1.5)));
+        assert_eq!(account.balance(&usd()).held(), Funds::new(dec!(0.0)));
+    }
+
+    #[test]
+    fn test_resolve_not_in_dispute() {
+        let mut account = Account::new(42);
+        account
+            .deposit(1, &usd(), Funds::new(dec!(1.5)))
+            .expect("Deposit to succeed");
+        assert_eq!(
+            account.deposits.get(&1).map(|d| &d.state),
+            Some(&DepositState::Undisputed(Funds::new(dec!(1.5))))
+        );
+
+        assert_eq!(
+            account.resolve(1, None),
+            Err(AccountUpdateError::TransactionNotInDispute(1)),
+        );
+        assert_eq!(
+            account.deposits.get(&1).map(|d| &d.state),
+            Some(&DepositState::Undisputed(Funds::new(dec!(1.5))))
+        );
+    }
+
+    #[test]
+    fn test_chargeback() {
+        let mut account = Account::new(42);
+        account
+            .deposit(1, &usd(), Funds::new(dec!(1.5)))
+            .expect("Deposit to succeed");
+        assert_eq!(
+            account.deposits.get(&1).map(|d| &d.state),
+            Some(&DepositState::Undisputed(Funds::new(dec!(1.5))))
+        );
+
+        account.dispute(1, None).expect("Dispute to succeed");
+        account.chargeback(1, None).expect("Chargeback to succeed");
+        assert_eq!(
+            account.deposits.get(&1).map(|d| &d.state),
+            Some(&DepositState::Chargedback)
+        );
+        assert_eq!(account.balance(&usd()).available(), Funds::new(dec!(0.0)));
+        assert_eq!(account.balance(&usd()).held(), Funds::new(dec!(0.0)));
+        assert!(account.is_frozen());
+    }
+
+    #[test]
+    fn test_partial_disputes() {
+        let mut account = Account::new(42);
+        account
+            .deposit(1, &usd(), Funds::new(dec!(10.0)))
+            .expect("Deposit to succeed");
+        account
+            .dispute(1, Some(Funds::new(dec!(4.0))))
+            .expect("Dispute to succeed");
+        account
+            .dispute(1, Some(Funds::new(dec!(3.0))))
+            .expect("Second dispute to succeed");
+        assert_eq!(
+            account.dispute(1, Some(Funds::new(dec!(3.5)))),
+            Err(AccountUpdateError::DisputeExceedsUndisputed(1)),
+        );
+        assert_eq!(
+            account.dispute(1, Some(Funds::new(dec!(-1.0)))),
+            Err(AccountUpdateError::NonPositiveDisputeAmount),
+        );
+        assert_eq!(
+            account.deposits.get(&1).map(|d| &d.state),
+            Some(&DepositState::InDispute {
+                disputed: Funds::new(dec!(7.0)),
+                undisputed: Funds::new(dec!(3.0)),
+            })
+        );
+        assert_eq!(account.balance(&usd()).available(), Funds::new(dec!(3.0)));
+        assert_eq!(account.balance(&usd()).held(), Funds::new(dec!(7.0)));
+
+        assert_eq!(
+            account.resolve(1, Some(Funds::new(dec!(7.5)))),
+            Err(AccountUpdateError::SettlementExceedsDispute(1)),
+        );
+        account
+            .resolve(1, Some(Funds::new(dec!(4.0))))
+            .expect("Resolve to succeed");
+        account
+            .chargeback(1, Some(Funds::new(dec!(1.0))))
+            .expect("Chargeback to succeed");
+        assert_eq!(account.balance(&usd()).available(), Funds::new(dec!(7.0)));
+        assert_eq!(account.balance(&usd()).held(), Funds::new(dec!(2.0)));
+        assert!(account.is_frozen());
+
+        // Settled parts can't be disputed again, only what was never disputed
+        account.resolve(1, None).expect("Resolve to succeed");
+        assert_eq!(
+            account.deposits.get(&1).map(|d| &d.state),
+            Some(&DepositState::Undisputed(Funds::new(dec!(3.0))))
+        );
+        account.dispute(1, None).expect("Dispute to succeed");
+        account.resolve(1, None).expect("Resolve to succeed");
+        assert_eq!(
+            account.deposits.get(&1).map(|d| &d.state),
+            Some(&DepositState::Resolved)
+        );
+        assert_eq!(
+            account.dispute(1, None),
+            Err(AccountUpdateError::TransactionNotDisputable(1))
+        );
+        assert_eq!(account.balance(&usd()).available(), Funds::new(dec!(9.0)));
+        assert_eq!(account.balance(&usd()).held(), Funds::new(0));
+    }
+
+    #[test]
+    fn test_represent_and_reverse() {
+        let mut account = Account::new(42).with_unfreeze_on_reversal(true);
+        account
+            .deposit(1, &usd(), Funds::new(dec!(10.0)))
+            .expect("Deposit to succeed");
+        assert_eq!(
+            account.represent(1),
+            Err(AccountUpdateError::TransactionNotChargedBack(1))
+        );
+        account
+            .dispute(1, Some(Funds::new(dec!(4.0))))
+            .expect("Dispute to succeed");
+        account.chargeback(1, None).expect("Chargeback to succeed");
+        assert!(account.is_frozen());
+        // The rest of the deposit could still be disputed
+        assert_eq!(
+            account.represent(1),
+            Err(AccountUpdateError::ChargebackNotSettled(1))
+        );
+        account.dispute(1, None).expect("Dispute to succeed");
+        account.resolve(1, None).expect("Resolve to succeed");
+
+        account.represent(1).expect("Re-presentment to succeed");
+        assert_eq!(
+            account.deposits.get(&1).map(|d| &d.state),
+            Some(&DepositState::Represented(Funds::new(dec!(4.0))))
+        );
+        assert_eq!(account.balance(&usd()).available(), Funds::new(dec!(6.0)));
+        assert_eq!(account.balance(&usd()).held(), Funds::new(dec!(4.0)));
+        assert_eq!(
+            account.chargeback(1, None),
+            Err(AccountUpdateError::TransactionNotInPreArbitration(1))
+        );
+
+        assert_eq!(
+            account.reverse_chargeback(1),
+            Ok(vec![
+                AccountEvent::ChargebackReversed {
+                    transaction: 1,
+                    currency: usd(),
+                    diff: BalanceDiff::new()
+                        .with_available(Funds::new(dec!(4.0)))
+                        .with_held(Funds::new(dec!(-4.0))),
+                },
+                AccountEvent::Unfrozen { reason: None },
+            ])
+        );
+        assert_eq!(account.balance(&usd()).available(), Funds::new(dec!(10.0)));
+        assert!(!account.is_frozen());
+        assert_eq!(
+            account.represent(1),
+            Err(AccountUpdateError::ChargebackAlreadyRepresented(1))
+        );
+        assert_eq!(
+            account.reverse_chargeback(1),
+            Err(AccountUpdateError::TransactionNotRepresented(1))
+        );
+    }
+
+    #[test]
+    fn test_pre_arbitration() {
+        let mut account = Account::new(42).with_unfreeze_on_reversal(true);
+        for id in [1, 2] {
+            account
+                .deposit(id, &usd(), Funds::new(dec!(1.5)))
+                .expect("Deposit to succeed");
+            account.dispute(id, None).expect("Dispute to succeed");
+            account.chargeback(id, None).expect("Chargeback to succeed");
+        }
+        assert_eq!(
+            account.pre_arbitration(1),
+            Err(AccountUpdateError::TransactionNotRepresented(1))
+        );
+        account.represent(1).expect("Re-presentment to succeed");
+        account
+            .pre_arbitration(1)
+            .expect("Pre-arbitration to succeed");
+        assert_eq!(
+            account.pre_arbitration(1),
+            Err(AccountUpdateError::PreArbitrationAlreadyOpen(1))
+        );
+        account.chargeback(1, None).expect("Chargeback to succeed");
+        assert_eq!(
+            account.deposits.get(&1).map(|d| &d.state),
+            Some(&DepositState::ChargebackUpheld)
+        );
+        assert_eq!(account.balance(&usd()).available(), Funds::new(0));
+        assert_eq!(account.balance(&usd()).held(), Funds::new(0));
+
+        // The other chargeback still stands, so the account stays frozen
+        account.represent(2).expect("Re-presentment to succeed");
+        account
+            .pre_arbitration(2)
+            .expect("Pre-arbitration to succeed");
+        account.reverse_chargeback(2).expect("Reversal to succeed");
+        assert_eq!(account.balance(&usd()).available(), Funds::new(dec!(1.5)));
+        assert!(account.is_frozen());
     }
 
-    pub fn deposit(
-        &mut self,
-        transaction_id: TransactionID,
-        amount: Funds,
-    ) -> Result<(), AccountUpdateError> {
-        if self.deposits.contains_key(&transaction_id) {
-            return Err(AccountUpdateError::DepositAlreadyProcessed(transaction_id));
-        }
+    #[test]
+    fn test_refund() {
+        let mut account = Account::new(42);
+        account
+            .deposit(1, &usd(), Funds::new(dec!(10.0)))
+            .expect("Deposit to succeed");
+        account
+            .withdraw(2, &usd(), Funds::new(dec!(7.0)))
+            .expect("Withdrawal to succeed");
+        assert_eq!(
+            account.refund(1, Some(Funds::new(dec!(4.0)))),
+            Err(AccountUpdateError::ConstraintViolation(
+                ConstraintViolation::OverdraftLimitExceeded(Funds::ZERO)
+            ))
+        );
+        assert_eq!(
+            account.refund(2, None),
+            Err(AccountUpdateError::TransactionNotRefundable(2))
+        );
 
-        if amount.is_negative() {
-            return Err(AccountUpdateError::NegativeDeposit);
-        }
+        account
+            .refund(1, Some(Funds::new(dec!(2.0))))
+            .expect("Refund to succeed");
+        assert_eq!(account.balance(&usd()).available(), Funds::new(dec!(1.0)));
+        assert_eq!(
+            account.refund(1, Some(Funds::new(dec!(9.0)))),
+            Err(AccountUpdateError::RefundExceedsRemaining(1))
+        );
+        // Only what wasn't refunded can be disputed
+        assert_eq!(
+            account.dispute(1, Some(Funds::new(dec!(9.0)))),
+            Err(AccountUpdateError::DisputeExceedsUndisputed(1))
+        );
+        account
+            .dispute(1, Some(Funds::new(dec!(1.0))))
+            .expect("Dispute to succeed");
 
-        self.balance = self
-            .balance
-            .apply(BalanceDiff::new().with_available(amount))?;
-        self.deposits
-            .insert(transaction_id, DepositState::Undisputed(amount));
+        account
+            .deposit(3, &usd(), Funds::new(dec!(10.0)))
+            .expect("Deposit to succeed");
+        account.refund(1, None).expect("Refund to succeed");
+        account.resolve(1, None).expect("Resolve to succeed");
+        assert_eq!(account.balance(&usd()).available(), Funds::new(dec!(4.0)));
+        assert_eq!(
+            account.deposits.get(&1).map(|d| &d.state),
+            Some(&DepositState::Resolved)
+        );
 
-        Ok(())
+        let mut account = Account::new(42);
+        account
+            .deposit(3, &usd(), Funds::new(dec!(10.0)))
+            .expect("Deposit to succeed");
+        account.refund(3, None).expect("Refund to succeed");
+        assert_eq!(account.balance(&usd()).available(), Funds::new(0));
+        assert_eq!(
+            account.deposits.get(&3).map(|d| &d.state),
+            Some(&DepositState::Refunded)
+        );
+        assert_eq!(
+            account.dispute(3, None),
+            Err(AccountUpdateError::TransactionNotDisputable(3))
+        );
+        assert_eq!(
+            account.refund(3, None),
+            Err(AccountUpdateError::TransactionNotRefundable(3))
+        );
+        assert!(!account.is_frozen());
     }
 
-    pub fn withdraw(&mut self, amount: Funds) -> Result<(), AccountUpdateError> {
-        if self.frozen {
-            return Err(AccountUpdateError::AccountIsFrozen);
-        }
+    #[test]
+    fn test_deficit_policy() {
+        // Deposits 2.0 and withdraws 1.5 of it, leaving 0.5 available
+        let spent = |policy: DeficitPolicy| {
+            let mut account = Account::new(42).with_deficit_policy(policy);
+            account
+                .deposit(1, &usd(), Funds::new(dec!(2.0)))
+                .expect("Deposit to succeed");
+            account
+                .withdraw(2, &usd(), Funds::new(dec!(1.5)))
+                .expect("Withdrawal to succeed");
+            account
+        };
 
-        if amount.is_negative() {
-            return Err(AccountUpdateError::NegativeWithdrawal);
-        }
+        let mut account = spent(DeficitPolicy::Allow);
+        account.dispute(1, None).expect("Dispute to succeed");
+        assert_eq!(account.balance(&usd()).available(), Funds::new(dec!(-1.5)));
+        assert!(!account.is_in_deficit());
 
-        if self.balance.available() < amount {
-            return Err(AccountUpdateError::InsufficientFunds);
-        }
+        let mut account = spent(DeficitPolicy::Reject);
+        assert_eq!(
+            account.dispute(1, None),
+            Err(AccountUpdateError::DisputeCausesDeficit(1))
+        );
+        // Disputes that leave enough available still go through
+        account
+            .dispute(1, Some(Funds::new(dec!(0.5))))
+            .expect("Dispute to succeed");
+        assert_eq!(account.balance(&usd()).available(), Funds::ZERO);
 
-        self.balance = self
-            .balance
-            .apply(BalanceDiff::new().with_available(-amount))?;
+        let mut account = spent(DeficitPolicy::Cap);
+        account.dispute(1, None).expect("Dispute to succeed");
+        assert_eq!(account.balance(&usd()).available(), Funds::ZERO);
+        assert_eq!(account.balance(&usd()).held(), Funds::new(dec!(0.5)));
+        assert_eq!(
+            account.deposits.get(&1).map(|d| &d.state),
+            Some(&DepositState::InDispute {
+                disputed: Funds::new(dec!(0.5)),
+                undisputed: Funds::new(dec!(1.5)),
+            })
+        );
+        // Nothing left to hold
+        assert_eq!(
+            account.dispute(1, None),
+            Err(AccountUpdateError::DisputeCausesDeficit(1))
+        );
 
-        Ok(())
+        let mut account = spent(DeficitPolicy::Flag);
+        assert_eq!(
+            account.dispute(1, None),
+            Ok(vec![
+                AccountEvent::DisputeOpened {
+                    transaction: 1,
+                    currency: usd(),
+                    diff: BalanceDiff::new()
+                        .with_available(Funds::new(dec!(-2.0)))
+                        .with_held(Funds::new(dec!(2.0))),
+                },
+                AccountEvent::FlaggedInDeficit {
+                    transaction: 1,
+                    currency: usd(),
+                },
+            ])
+        );
+        assert_eq!(account.balance(&usd()).available(), Funds::new(dec!(-1.5)));
+        assert!(account.is_in_deficit());
     }
 
-    pub fn dispute(&mut self, transaction_id: TransactionID) -> Result<(), AccountUpdateError> {
-        if let Some(&DepositState::Undisputed(amount)) = self.deposits.get(&transaction_id) {
-            self.balance = self
-                .balance
-                .apply(BalanceDiff::new().with_available(-amount).with_held(amount))?;
-            self.deposits
-                .insert(transaction_id, DepositState::InDispute(amount));
+    #[test]
+    fn test_dispute_window() {
+        let mut account = Account::new(42).with_dispute_window(Some(100));
+        account.advance(1_000);
+        account
+            .deposit(1, &usd(), Funds::new(dec!(2.0)))
+            .expect("Deposit to succeed");
+        // Applied without a time, so there's nothing to measure the window from
+        account.time = None;
+        account
+            .deposit(2, &usd(), Funds::new(dec!(1.0)))
+            .expect("Deposit to succeed");
 
-            Ok(())
-        } else {
-            Err(AccountUpdateError::TransactionNotDisputable(transaction_id))
-        }
+        account.advance(1_100);
+        account
+            .dispute(1, Some(Funds::new(dec!(1.0))))
+            .expect("Dispute to succeed");
+        account.advance(1_101);
+        assert_eq!(
+            account.dispute(1, None),
+            Err(AccountUpdateError::DisputeWindowClosed(1))
+        );
+        // Disputes that are already open can still be settled
+        account.resolve(1, None).expect("Resolve to succeed");
+        account.dispute(2, None).expect("Dispute to succeed");
     }
 
-    pub fn resolve(&mut self, transaction_id: TransactionID) -> Result<(), AccountUpdateError> {
-        if let Some(&DepositState::InDispute(amount)) = self.deposits.get(&transaction_id) {
-            self.balance = self
-                .balance
-                .apply(BalanceDiff::new().with_available(amount).with_held(-amount))?;
-            self.deposits.insert(transaction_id, DepositState::Resolved);
+    #[test]
+    fn test_dispute_expiry() {
+        let mut account = Account::new(42).with_dispute_expiry(Some(DisputeExpiry::new(
+            DisputeDuration::Transactions(2),
+            ExpiryAction::Resolve,
+        )));
+        account.advance(0);
+        account
+            .deposit(1, &usd(), Funds::new(dec!(2.0)))
+            .expect("Deposit to succeed");
+        account.count_transaction();
+        account.advance(0);
+        account
+            .dispute(1, Some(Funds::new(dec!(1.0))))
+            .expect("Dispute to succeed");
+        account.count_transaction();
+        // A later partial dispute expires along with the first one
+        assert!(account.advance(0).is_empty());
+        account.dispute(1, None).expect("Dispute to succeed");
+        account.count_transaction();
 
-            Ok(())
-        } else {
-            Err(AccountUpdateError::TransactionNotInDispute(transaction_id))
-        }
+        assert_eq!(
+            account.advance(0),
+            vec![
+                AccountEvent::DisputeExpired {
+                    transaction: 1,
+                    currency: usd(),
+                    action: ExpiryAction::Resolve,
+                },
+                AccountEvent::DisputeResolved {
+                    transaction: 1,
+                    currency: usd(),
+                    diff: BalanceDiff::new()
+                        .with_available(Funds::new(dec!(2.0)))
+                        .with_held(Funds::new(dec!(-2.0))),
+                },
+            ]
+        );
+        assert_eq!(account.balance(&usd()).held(), Funds::ZERO);
+        assert_eq!(account.expired_disputes(), 1);
+
+        let mut account = Account::new(42).with_dispute_expiry(Some(DisputeExpiry::new(
+            DisputeDuration::Seconds(60),
+            ExpiryAction::Chargeback,
+        )));
+        account.advance(1_000);
+        account
+            .deposit(1, &usd(), Funds::new(dec!(2.0)))
+            .expect("Deposit to succeed");
+        account.dispute(1, None).expect("Dispute to succeed");
+        assert!(account.advance(1_059).is_empty());
+        let events = account.advance(1_060);
+        assert_eq!(events.len(), 3);
+        assert_eq!(
+            events[0],
+            AccountEvent::DisputeExpired {
+                transaction: 1,
+                currency: usd(),
+                action: ExpiryAction::Chargeback,
+            }
+        );
+        assert_eq!(account.balance(&usd()).available(), Funds::ZERO);
+        assert_eq!(account.balance(&usd()).held(), Funds::ZERO);
+        assert!(account.is_frozen());
     }
 
-    pub fn chargeback(&mut self, transaction_id: TransactionID) -> Result<(), AccountUpdateError> {
-        if let Some(&DepositState::InDispute(amount)) = self.deposits.get(&transaction_id) {
-            self.balance = self.balance.apply(BalanceDiff::new().with_held(-amount))?;
-            self.deposits
-                .insert(transaction_id, DepositState::Chargedback);
-            self.frozen = true;
+    #[test]
+    fn test_catch_up() {
+        let mut account = Account::new(42).with_dispute_expiry(Some(DisputeExpiry::new(
+            DisputeDuration::Seconds(60),
+            ExpiryAction::Resolve,
+        )));
+        account.advance(1_000);
+        account
+            .deposit(1, &usd(), Funds::new(dec!(2.0)))
+            .expect("Deposit to succeed");
+        account
+            .deposit(4, &usd(), Funds::new(dec!(1.0)))
+            .expect("Deposit to succeed");
+        account
+            .deposit_uncleared(2, &usd(), Funds::new(dec!(1.0)), Some(1_100))
+            .expect("Deposit to succeed");
+        account.dispute(1, None).expect("Dispute to succeed");
+        account
+            .authorize(3, &usd(), Funds::new(dec!(1.0)), Some(1))
+            .expect("Authorization to succeed");
+        account.count_transaction();
 
-            Ok(())
-        } else {
-            Err(AccountUpdateError::TransactionNotInDispute(transaction_id))
-        }
+        assert!(account.catch_up(1_059).is_empty());
+        let events = account.catch_up(1_100);
+        assert_eq!(events.len(), 3);
+        assert_eq!(
+            events[1],
+            AccountEvent::DisputeExpired {
+                transaction: 1,
+                currency: usd(),
+                action: ExpiryAction::Resolve,
+            }
+        );
+        let balance = account.balance(&usd());
+        assert_eq!(balance.available(), Funds::new(dec!(3.0)));
+        assert_eq!(balance.pending(), Funds::ZERO);
+        // Holds expire by transactions, so they wait for the next one
+        assert_eq!(balance.held(), Funds::new(dec!(1.0)));
     }
-}
-
-#[cfg(test)]
-mod test {
-    use super::*;
-    use crate::funds::Funds;
-    use rust_decimal_macros::dec;
 
     #[test]
-    fn test_deposit() {
+    fn test_uncleared_deposit() {
         let mut account = Account::new(42);
+        account.advance(1_000);
+        account
+            .deposit_uncleared(1, &usd(), Funds::new(dec!(2.0)), Some(1_100))
+            .expect("Deposit to succeed");
+        account
+            .deposit_uncleared(2, &usd(), Funds::new(dec!(1.0)), None)
+            .expect("Deposit to succeed");
+        let balance = account.balance(&usd());
+        assert_eq!(balance.available(), Funds::ZERO);
+        assert_eq!(balance.pending(), Funds::new(dec!(3.0)));
+        assert_eq!(balance.total(), Ok(Funds::new(dec!(3.0))));
+
+        // Pending funds can't be used, disputed or refunded
+        assert_eq!(
+            account.withdraw(3, &usd(), Funds::new(dec!(1.0))),
+            Err(AccountUpdateError::ConstraintViolation(
+                ConstraintViolation::OverdraftLimitExceeded(Funds::ZERO)
+            )),
+        );
+        assert_eq!(
+            account.dispute(1, None),
+            Err(AccountUpdateError::DepositNotCleared(1))
+        );
+        assert_eq!(
+            account.refund(2, None),
+            Err(AccountUpdateError::DepositNotCleared(2))
+        );
+
+        assert!(account.advance(1_099).is_empty());
+        assert_eq!(
+            account.advance(1_100),
+            vec![AccountEvent::Cleared {
+                transaction: 1,
+                currency: usd(),
+                diff: BalanceDiff::new()
+                    .with_available(Funds::new(dec!(2.0)))
+                    .with_pending(Funds::new(dec!(-2.0))),
+            }]
+        );
+        account.clear(2).expect("Clear to succeed");
+        assert_eq!(
+            account.clear(2),
+            Err(AccountUpdateError::DepositNotPending(2))
+        );
+        let balance = account.balance(&usd());
+        assert_eq!(balance.available(), Funds::new(dec!(3.0)));
+        assert_eq!(balance.pending(), Funds::ZERO);
+        account.dispute(1, None).expect("Dispute to succeed");
+    }
+
+    #[test]
+    fn test_overdraft() {
+        let mut account = Account::new(42).with_constraints(
+            BalanceConstraints::new().with_overdraft_limit(Some(Funds::new(dec!(5.0)))),
+        );
         account
-            .deposit(1, Funds::new(dec!(1.5)))
+            .deposit(1, &usd(), Funds::new(dec!(1.0)))
             .expect("Deposit to succeed");
-        assert_eq!(account.balance.available(), Funds::new(dec!(1.5)));
+        account
+            .withdraw(2, &usd(), Funds::new(dec!(4.0)))
+            .expect("Withdrawal to succeed");
+        assert_eq!(account.balance(&usd()).available(), Funds::new(dec!(-3.0)));
+        assert_eq!(
+            account.withdraw(3, &usd(), Funds::new(dec!(2.5))),
+            Err(AccountUpdateError::ConstraintViolation(
+                ConstraintViolation::OverdraftLimitExceeded(Funds::new(dec!(5.0)))
+            )),
+        );
+        assert_eq!(
+            account.authorize(3, &usd(), Funds::new(dec!(2.5)), None),
+            Err(AccountUpdateError::ConstraintViolation(
+                ConstraintViolation::OverdraftLimitExceeded(Funds::new(dec!(5.0)))
+            )),
+        );
+
+        // Disputes aren't held to the limits on available funds
+        account.dispute(1, None).expect("Dispute to succeed");
+        assert_eq!(account.balance(&usd()).available(), Funds::new(dec!(-4.0)));
     }
 
     #[test]
-    fn test_negative_deposit() {
+    fn test_withdraw_from_frozen_account_fails() {
         let mut account = Account::new(42);
+        account
+            .deposit(1, &usd(), Funds::new(dec!(1.5)))
+            .expect("Deposit to succeed");
+        // Make sure we have sufficient funds for potential withdrawal
+        account
+            .deposit(2, &usd(), Funds::new(dec!(3.0)))
+            .expect("Deposit to succeed");
+
+        account.dispute(1, None).expect("Dispute to succeed");
+        account.chargeback(1, None).expect("Chargeback to succeed");
         assert_eq!(
-            account.deposit(1, Funds::new(dec!(-1.5))),
-            Err(AccountUpdateError::NegativeDeposit),
+            account.withdraw(3, &usd(), Funds::new(dec!(1.0))),
+            Err(AccountUpdateError::AccountIsFrozen),
         );
-        assert_eq!(account.balance.available(), Funds::new(0));
     }
 
     #[test]
-    fn test_withdrawal() {
+    fn test_deposit_into_frozen_account_succeeds() {
         let mut account = Account::new(42);
         account
-            .deposit(1, Funds::new(dec!(1.5)))
+            .deposit(1, &usd(), Funds::new(dec!(1.5)))
             .expect("Deposit to succeed");
+
+        account.dispute(1, None).expect("Dispute to succeed");
+        account.chargeback(1, None).expect("Chargeback to succeed");
         account
-            .withdraw(Funds::new(dec!(1.0)))
-            .expect("Withrawal to succeed");
-        assert_eq!(account.balance.available(), Funds::new(dec!(0.5)));
+            .deposit(2, &usd(), Funds::new(dec!(1.0)))
+            .expect("Deposit to succeed");
     }
 
     #[test]
-    fn negative_withdrawal() {
+    fn test_chargeback_not_in_dispute() {
         let mut account = Account::new(42);
         account
-            .deposit(1, Funds::new(dec!(1.5)))
+            .deposit(1, &usd(), Funds::new(dec!(1.5)))
             .expect("Deposit to succeed");
         assert_eq!(
-            account.withdraw(Funds::new(dec!(-1.0))),
-            Err(AccountUpdateError::NegativeWithdrawal),
+            account.deposits.get(&1).map(|d| &d.state),
+            Some(&DepositState::Undisputed(Funds::new(dec!(1.5))))
         );
-        assert_eq!(account.balance.available(), Funds::new(dec!(1.5)));
-    }
 
+        assert_eq!(
+            account.chargeback(1, None),
+            Err(AccountUpdateError::TransactionNotInDispute(1)),
+        );
+        assert_eq!(
+            account.deposits.get(&1).map(|d| &d.state),
+            Some(&DepositState::Undisputed(Funds::new(dec!(1.5))))
+        );
+    }
 
     #[test]
-    fn test_withdrawal_insufficient_funds() {
+    fn test_withdrawal_not_disputable_by_default() {
         let mut account = Account::new(42);
+        account
+            .deposit(1, &usd(), Funds::new(dec!(1.5)))
+            .expect("Deposit to succeed");
+        account
+            .withdraw(2, &usd(), Funds::new(dec!(1.0)))
+            .expect("Withdrawal to succeed");
         assert_eq!(
-            account.withdraw(Funds::new(dec!(1.5))),
-            Err(AccountUpdateError::InsufficientFunds),
+            account.dispute(2, None),
+            Err(AccountUpdateError::TransactionNotDisputable(2)),
         );
+        assert!(account.withdrawals.is_empty());
     }
 
     #[test]
-    fn test_dispute() {
-        let mut account = Account::new(42);
+    fn test_withdrawal_dispute_chargeback() {
+        let mut account = Account::new(42).with_dispute_policy(Arc::new(WithdrawalsDisputable));
         account
-            .deposit(1, Funds::new(dec!(1.5)))
+            .deposit(1, &usd(), Funds::new(dec!(1.5)))
             .expect("Deposit to succeed");
+        account
+            .withdraw(2, &usd(), Funds::new(dec!(1.0)))
+            .expect("Withdrawal to succeed");
+
+        account.dispute(2, None).expect("Dispute to succeed");
+        assert_eq!(account.balance(&usd()).available(), Funds::new(dec!(0.5)));
+        assert_eq!(account.balance(&usd()).held(), Funds::new(dec!(1.0)));
+        // Funds claimed back can't be spent while the dispute is open
         assert_eq!(
-            account.deposits.get(&1),
-            Some(&DepositState::Undisputed(Funds::new(dec!(1.5))))
+            account.withdraw(3, &usd(), Funds::new(dec!(1.0))),
+            Err(AccountUpdateError::ConstraintViolation(
+                ConstraintViolation::OverdraftLimitExceeded(Funds::ZERO)
+            )),
         );
 
-        account.dispute(1).expect("Dispute to succeed");
+        account.chargeback(2, None).expect("Chargeback to succeed");
+        assert_eq!(
+            account.withdrawals.get(&2).map(|d| &d.state),
+            Some(&DepositState::Chargedback)
+        );
+        assert_eq!(account.balance(&usd()).available(), Funds::new(dec!(1.5)));
+        assert_eq!(account.balance(&usd()).held(), Funds::new(dec!(0.0)));
+        assert!(!account.is_frozen());
         assert_eq!(
-            account.deposits.get(&1),
-            Some(&DepositState::InDispute(Funds::new(dec!(1.5))))
+            account.dispute(2, None),
+            Err(AccountUpdateError::TransactionNotDisputable(2)),
         );
-        assert_eq!(account.balance.available(), Funds::new(dec!(0.0)));
-        assert_eq!(account.balance.held(), Funds::new(dec!(1.5)));
     }
 
     #[test]
-    fn test_invalid_dispute_non_existent_transaction() {
-        let mut account = Account::new(42);
+    fn test_withdrawal_dispute_resolve() {
+        let mut account = Account::new(42).with_dispute_policy(Arc::new(WithdrawalsDisputable));
+        account
+            .deposit(1, &usd(), Funds::new(dec!(1.5)))
+            .expect("Deposit to succeed");
+        account
+            .withdraw(2, &usd(), Funds::new(dec!(1.0)))
+            .expect("Withdrawal to succeed");
+
+        account.dispute(2, None).expect("Dispute to succeed");
+        account.resolve(2, None).expect("Resolve to succeed");
         assert_eq!(
-            account.dispute(1),
-            Err(AccountUpdateError::TransactionNotDisputable(1))
+            account.withdrawals.get(&2).map(|d| &d.state),
+            Some(&DepositState::Resolved)
         );
+        assert_eq!(account.balance(&usd()).available(), Funds::new(dec!(0.5)));
+        assert_eq!(account.balance(&usd()).held(), Funds::new(dec!(0.0)));
     }
 
     #[test]
-    fn test_resolve() {
+    fn test_unlock_after_chargeback() {
         let mut account = Account::new(42);
         account
-            .deposit(1, Funds::new(dec!(1.5)))
+            .deposit(1, &usd(), Funds::new(dec!(1.5)))
             .expect("Deposit to succeed");
-        assert_eq!(
-            account.deposits.get(&1),
-            Some(&DepositState::Undisputed(Funds::new(dec!(1.5))))
-        );
+        account
+            .deposit(2, &usd(), Funds::new(dec!(3.0)))
+            .expect("Deposit to succeed");
+        account.dispute(1, None).expect("Dispute to succeed");
+        account.chargeback(1, None).expect("Chargeback to succeed");
 
-        account.dispute(1).expect("Dispute to succeed");
-        account.resolve(1).expect("Resolve to succeed");
-        assert_eq!(account.deposits.get(&1), Some(&DepositState::Resolved));
-        assert_eq!(account.balance.available(), Funds::new(dec!(1.5)));
-        assert_eq!(account.balance.held(), Funds::new(dec!(0.0)));
+        account
+            .unlock("Settled with the card issuer".to_string())
+            .expect("Unlock to succeed");
+        assert!(!account.is_frozen());
+        assert_eq!(account.admin_reason(), Some("Settled with the card issuer"));
+        account
+            .withdraw(3, &usd(), Funds::new(dec!(1.0)))
+            .expect("Withdrawal to succeed");
     }
 
     #[test]
-    fn test_resolve_not_in_dispute() {
+    fn test_lock() {
         let mut account = Account::new(42);
         account
-            .deposit(1, Funds::new(dec!(1.5)))
+            .deposit(1, &usd(), Funds::new(dec!(1.5)))
             .expect("Deposit to succeed");
         assert_eq!(
-            account.deposits.get(&1),
-            Some(&DepositState::Undisputed(Funds::new(dec!(1.5))))
+            account.unlock("Not frozen".to_string()),
+            Err(AccountUpdateError::AccountIsNotFrozen),
         );
 
+        account
+            .lock(
+                "Suspicious activity".to_string(),
+                FreezeLevel::WithdrawalsBlocked,
+            )
+            .expect("Lock to succeed");
         assert_eq!(
-            account.resolve(1),
-            Err(AccountUpdateError::TransactionNotInDispute(1)),
+            account.lock(
+                "Suspicious activity".to_string(),
+                FreezeLevel::WithdrawalsBlocked
+            ),
+            Err(AccountUpdateError::AccountIsFrozen),
         );
         assert_eq!(
-            account.deposits.get(&1),
-            Some(&DepositState::Undisputed(Funds::new(dec!(1.5))))
+            account.withdraw(2, &usd(), Funds::new(dec!(1.0))),
+            Err(AccountUpdateError::AccountIsFrozen),
         );
     }
 
     #[test]
-    fn test_chargeback() {
+    fn test_fully_locked_allows_settling_disputes() {
         let mut account = Account::new(42);
         account
-            .deposit(1, Funds::new(dec!(1.5)))
+            .deposit(1, &usd(), Funds::new(dec!(1.5)))
+            .expect("Deposit to succeed");
+        account
+            .deposit(2, &usd(), Funds::new(dec!(1.5)))
             .expect("Deposit to succeed");
+        account.dispute(1, None).expect("Dispute to succeed");
+        account
+            .lock("Fraud investigation".to_string(), FreezeLevel::FullyLocked)
+            .expect("Lock to succeed");
+
         assert_eq!(
-            account.deposits.get(&1),
-            Some(&DepositState::Undisputed(Funds::new(dec!(1.5))))
+            account.deposit(3, &usd(), Funds::new(dec!(1.0))),
+            Err(AccountUpdateError::AccountIsFrozen),
+        );
+        assert_eq!(
+            account.dispute(2, None),
+            Err(AccountUpdateError::AccountIsFrozen),
         );
+        account.chargeback(1, None).expect("Chargeback to succeed");
+        assert_eq!(account.freeze_level(), FreezeLevel::FullyLocked);
+    }
 
-        account.dispute(1).expect("Dispute to succeed");
-        account.chargeback(1).expect("Chargeback to succeed");
-        assert_eq!(account.deposits.get(&1), Some(&DepositState::Chargedback));
-        assert_eq!(account.balance.available(), Funds::new(dec!(0.0)));
-        assert_eq!(account.balance.held(), Funds::new(dec!(0.0)));
-        assert!(account.frozen);
+    #[test]
+    fn test_chargeback_freeze_level() {
+        let mut account =
+            Account::new(42).with_chargeback_freeze_level(FreezeLevel::DepositsBlocked);
+        account
+            .deposit(1, &usd(), Funds::new(dec!(1.5)))
+            .expect("Deposit to succeed");
+        account.dispute(1, None).expect("Dispute to succeed");
+        account.chargeback(1, None).expect("Chargeback to succeed");
+        assert_eq!(account.freeze_level(), FreezeLevel::DepositsBlocked);
+        assert_eq!(
+            account.deposit(2, &usd(), Funds::new(dec!(1.0))),
+            Err(AccountUpdateError::AccountIsFrozen),
+        );
     }
 
     #[test]
-    fn test_withdraw_from_frozen_account_fails() {
+    fn test_closed_account() {
         let mut account = Account::new(42);
         account
-            .deposit(1, Funds::new(dec!(1.5)))
+            .deposit(1, &usd(), Funds::new(dec!(1.5)))
+            .expect("Deposit to succeed");
+        account
+            .lock("Client request".to_string(), FreezeLevel::Closed)
+            .expect("Lock to succeed");
+        assert_eq!(
+            account.deposit(2, &usd(), Funds::new(dec!(1.0))),
+            Err(AccountUpdateError::AccountIsClosed),
+        );
+        assert_eq!(
+            account.unlock("Reopen".to_string()),
+            Err(AccountUpdateError::AccountIsClosed),
+        );
+        assert!(account.is_frozen());
+    }
+
+    #[test]
+    fn test_multiple_assets() {
+        let btc = Currency::new("BTC");
+        let mut account = Account::new(42);
+        account
+            .deposit(1, &usd(), Funds::new(dec!(1.5)))
             .expect("Deposit to succeed");
-        // Make sure we have sufficient funds for potential withdrawal
         account
-            .deposit(2, Funds::new(dec!(3.0)))
+            .deposit(2, &btc, Funds::new(dec!(0.25)))
             .expect("Deposit to succeed");
+        // Funds in one asset can't be used to withdraw another
+        assert_eq!(
+            account.withdraw(3, &btc, Funds::new(dec!(1.0))),
+            Err(AccountUpdateError::ConstraintViolation(
+                ConstraintViolation::OverdraftLimitExceeded(Funds::ZERO)
+            )),
+        );
 
-        account.dispute(1).expect("Dispute to succeed");
-        account.chargeback(1).expect("Chargeback to succeed");
+        // Disputes apply to the asset of the original deposit
+        account.dispute(2, None).expect("Dispute to succeed");
+        assert_eq!(account.balance(&btc).held(), Funds::new(dec!(0.25)));
+        assert_eq!(account.balance(&usd()).held(), Funds::new(0));
         assert_eq!(
-            account.withdraw(Funds::new(dec!(1.0))),
-            Err(AccountUpdateError::AccountIsFrozen),
+            account
+                .balances()
+                .map(|(currency, _)| currency.code())
+                .collect::<Vec<_>>(),
+            vec!["BTC", "USD"],
         );
     }
 
     #[test]
-    fn test_deposit_into_frozen_account_succeeds() {
+    fn test_transfer() {
         let mut account = Account::new(42);
         account
-            .deposit(1, Funds::new(dec!(1.5)))
+            .deposit(1, &usd(), Funds::new(dec!(1.5)))
             .expect("Deposit to succeed");
+        assert_eq!(
+            account.prepare_transfer(2, TransferLeg::Debit, 7, &usd(), Funds::new(dec!(2.0))),
+            Err(AccountUpdateError::ConstraintViolation(
+                ConstraintViolation::OverdraftLimitExceeded(Funds::ZERO)
+            )),
+        );
+        assert_eq!(
+            account.prepare_transfer(2, TransferLeg::Credit, 7, &usd(), Funds::new(dec!(-1.0))),
+            Err(AccountUpdateError::NegativeTransfer),
+        );
 
-        account.dispute(1).expect("Dispute to succeed");
-        account.chargeback(1).expect("Chargeback to succeed");
+        let transfer = account
+            .prepare_transfer(2, TransferLeg::Debit, 7, &usd(), Funds::new(dec!(1.0)))
+            .expect("Transfer to be prepared");
+        // Preparing doesn't change the account
+        assert_eq!(account.balance(&usd()).available(), Funds::new(dec!(1.5)));
+        assert_eq!(
+            account.commit_transfer(transfer),
+            vec![AccountEvent::TransferredOut {
+                transaction: 2,
+                to: 7,
+                currency: usd(),
+                diff: BalanceDiff::new().with_available(Funds::new(dec!(-1.0))),
+            }]
+        );
+        assert_eq!(account.balance(&usd()).available(), Funds::new(dec!(0.5)));
+    }
+
+    #[test]
+    fn test_authorize_capture_void() {
+        let mut account = Account::new(42);
         account
-            .deposit(2, Funds::new(dec!(1.0)))
+            .deposit(1, &usd(), Funds::new(dec!(5.0)))
             .expect("Deposit to succeed");
+        assert_eq!(
+            account.authorize(2, &usd(), Funds::new(dec!(6.0)), None),
+            Err(AccountUpdateError::ConstraintViolation(
+                ConstraintViolation::OverdraftLimitExceeded(Funds::ZERO)
+            )),
+        );
+        assert_eq!(
+            account.authorize(2, &usd(), Funds::ZERO, None),
+            Err(AccountUpdateError::NonPositiveAuthorization),
+        );
+        account
+            .authorize(2, &usd(), Funds::new(dec!(3.0)), None)
+            .expect("Authorization to succeed");
+        assert_eq!(account.balance(&usd()).available(), Funds::new(dec!(2.0)));
+        assert_eq!(account.balance(&usd()).held(), Funds::new(dec!(3.0)));
+
+        // Partial captures leave the rest on hold
+        account
+            .capture(2, Some(Funds::new(dec!(1.0))))
+            .expect("Capture to succeed");
+        assert_eq!(account.balance(&usd()).held(), Funds::new(dec!(2.0)));
+        assert_eq!(
+            account.capture(2, Some(Funds::new(dec!(2.5)))),
+            Err(AccountUpdateError::CaptureExceedsHold(2)),
+        );
+        assert_eq!(
+            account.capture(2, Some(Funds::ZERO)),
+            Err(AccountUpdateError::NonPositiveCapture),
+        );
+        account.void(2).expect("Void to succeed");
+        assert_eq!(account.balance(&usd()).available(), Funds::new(dec!(4.0)));
+        assert_eq!(account.balance(&usd()).held(), Funds::new(0));
+        assert_eq!(account.void(2), Err(AccountUpdateError::HoldNotOpen(2)));
+
+        // Capturing without an amount settles the whole hold
+        account
+            .authorize(3, &usd(), Funds::new(dec!(1.5)), None)
+            .expect("Authorization to succeed");
+        account.capture(3, None).expect("Capture to succeed");
+        assert_eq!(account.balance(&usd()).available(), Funds::new(dec!(2.5)));
+        assert_eq!(account.balance(&usd()).held(), Funds::new(0));
+        assert_eq!(
+            account.capture(3, None),
+            Err(AccountUpdateError::HoldNotOpen(3))
+        );
     }
 
     #[test]
-    fn test_chargeback_not_in_dispute() {
+    fn test_hold_expiry() {
         let mut account = Account::new(42);
         account
-            .deposit(1, Funds::new(dec!(1.5)))
+            .deposit(1, &usd(), Funds::new(dec!(5.0)))
             .expect("Deposit to succeed");
+        account
+            .authorize(2, &usd(), Funds::new(dec!(3.0)), Some(2))
+            .expect("Authorization to succeed");
+        account.count_transaction();
+
+        assert!(account.advance(1).is_empty());
+        // A transaction that fails doesn't count
+        assert!(account.advance(2).is_empty());
+        account.count_transaction();
         assert_eq!(
-            account.deposits.get(&1),
-            Some(&DepositState::Undisputed(Funds::new(dec!(1.5))))
+            account.advance(3),
+            vec![AccountEvent::HoldExpired {
+                transaction: 2,
+                currency: usd(),
+                diff: BalanceDiff::new()
+                    .with_available(Funds::new(dec!(3.0)))
+                    .with_held(Funds::new(dec!(-3.0))),
+            }]
+        );
+        assert_eq!(account.balance(&usd()).available(), Funds::new(dec!(5.0)));
+        assert_eq!(
+            account.capture(2, None),
+            Err(AccountUpdateError::HoldNotOpen(2))
         );
+    }
 
+    #[test]
+    fn test_events() {
+        let mut account = Account::new(42);
         assert_eq!(
-            account.chargeback(1),
-            Err(AccountUpdateError::TransactionNotInDispute(1)),
+            account.deposit(1, &usd(), Funds::new(dec!(1.5))),
+            Ok(vec![AccountEvent::Deposited {
+                transaction: 1,
+                currency: usd(),
+                diff: BalanceDiff::new().with_available(Funds::new(dec!(1.5))),
+            }]),
         );
+        account.dispute(1, None).expect("Dispute to succeed");
         assert_eq!(
-            account.deposits.get(&1),
-            Some(&DepositState::Undisputed(Funds::new(dec!(1.5))))
+            account.chargeback(1, None),
+            Ok(vec![
+                AccountEvent::ChargedBack {
+                    transaction: 1,
+                    currency: usd(),
+                    diff: BalanceDiff::new().with_held(Funds::new(dec!(-1.5))),
+                },
+                AccountEvent::Frozen {
+                    level: FreezeLevel::WithdrawalsBlocked,
+                    reason: None,
+                },
+            ]),
         );
     }
 }
@@ -0,0 +1,114 @@
+use crate::account::Account;
+use crate::transaction::ClientID;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::io;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum AccountStoreError {
+    #[error("Failed to access account store: {0}")]
+    Io(#[from] io::Error),
+    #[error("Malformed account record: {0}")]
+    Format(#[from] serde_json::Error),
+}
+
+pub type AccountIter<'a> =
+    Box<dyn Iterator<Item = Result<Cow<'a, Account>, AccountStoreError>> + 'a>;
+
+/// Where the engine keeps its accounts
+///
+/// Stores only need to keep the state of accounts (what's serialized in a snapshot). The engine
+/// applies its configuration (e.g. the dispute policy) to every account it gets from the store.
+pub trait AccountStore: Debug + Send {
+    /// Looks up the account for `client` without changing it
+    fn get(&mut self, client: ClientID) -> Result<Option<&Account>, AccountStoreError>;
+
+    /// Looks up the account for `client` to change it
+    ///
+    /// Changes made through the returned reference are only sure to be kept once they're reported
+    /// with `mark_changed`
+    fn get_mut(&mut self, client: ClientID) -> Result<Option<&mut Account>, AccountStoreError>;
+
+    /// Reports that the account for `client` was changed through `get_mut`
+    fn mark_changed(&mut self, _client: ClientID) {}
+
+    /// Inserts `account`, replacing the existing account for the same client if there's one
+    fn upsert(&mut self, account: Account) -> Result<(), AccountStoreError>;
+
+    /// Iterates over every account in the store, in no particular order
+    fn iter(&self) -> AccountIter<'_>;
+
+    /// Persists any changes that are only held in memory
+    fn flush(&mut self) -> Result<(), AccountStoreError> {
+        Ok(())
+    }
+}
+
+impl<S: AccountStore + ?Sized> AccountStore for Box<S> {
+    fn get(&mut self, client: ClientID) -> Result<Option<&Account>, AccountStoreError> {
+        (**self).get(client)
+    }
+
+    fn get_mut(&mut self, client: ClientID) -> Result<Option<&mut Account>, AccountStoreError> {
+        (**self).get_mut(client)
+    }
+
+    fn mark_changed(&mut self, client: ClientID) {
+        (**self).mark_changed(client)
+    }
+
+    fn upsert(&mut self, account: Account) -> Result<(), AccountStoreError> {
+        (**self).upsert(account)
+    }
+
+    fn iter(&self) -> AccountIter<'_> {
+        (**self).iter()
+    }
+
+    fn flush(&mut self) -> Result<(), AccountStoreError> {
+        (**self).flush()
+    }
+}
+
+/// Keeps every account in memory
+#[derive(Debug, Default)]
+pub struct MemoryAccountStore {
+    accounts: HashMap<ClientID, Account>,
+}
+
+impl MemoryAccountStore {
+    pub fn new() -> Self {
+        Self {
+            accounts: HashMap::new(),
+        }
+    }
+
+    pub fn accounts(&self) -> &HashMap<ClientID, Account> {
+        &self.accounts
+    }
+}
+
+impl AccountStore for MemoryAccountStore {
+    fn get(&mut self, client: ClientID) -> Result<Option<&Account>, AccountStoreError> {
+        Ok(self.accounts.get(&client))
+    }
+
+    fn get_mut(&mut self, client: ClientID) -> Result<Option<&mut Account>, AccountStoreError> {
+        Ok(self.accounts.get_mut(&client))
+    }
+
+    fn upsert(&mut self, account: Account) -> Result<(), AccountStoreError> {
+        self.accounts.insert(account.client_id(), account);
+        Ok(())
+    }
+
+    fn iter(&self) -> AccountIter<'_> {
+        Box::new(
+            self.accounts
+                .values()
+                .map(|account| Ok(Cow::Borrowed(account))),
+        )
+    }
+}
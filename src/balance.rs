@@ -1,5 +1,131 @@
 use crate::funds::{Funds, FundsOpError};
-use rust_decimal::Decimal;
+use serde::Deserialize;
+use serde::Serialize;
+use thiserror::Error;
+
+/// A `BalanceConstraints` limit that applying a `BalanceDiff` would break
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Error)]
+pub enum ConstraintViolation {
+    #[error("Available funds can't go below the minimum balance of {0}")]
+    BelowMinimumBalance(Funds),
+    #[error("Insufficient funds (overdraft limit of {0})")]
+    OverdraftLimitExceeded(Funds),
+    #[error("Held funds can't be negative")]
+    NegativeHeld,
+}
+
+#[derive(Debug, PartialEq, Eq, Error)]
+pub enum BalanceError {
+    #[error(transparent)]
+    Funds(#[from] FundsOpError),
+    #[error(transparent)]
+    Violation(#[from] ConstraintViolation),
+}
+
+/// Limits `Balance::apply` keeps a balance within
+///
+/// By default available funds can't go below zero (an overdraft limit of zero) and held funds
+/// can't be negative. A limit is only enforced on diffs that move the balance towards it, so a
+/// balance that's already past a limit can still be brought back (e.g. by a deposit)
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BalanceConstraints {
+    /// Lowest the available funds can go, if anything stricter than the overdraft limit
+    minimum_available: Option<Funds>,
+    /// How far below zero the available funds can go, `None` for no limit
+    overdraft_limit: Option<Funds>,
+    held_non_negative: bool,
+}
+
+impl BalanceConstraints {
+    pub fn new() -> Self {
+        Self {
+            minimum_available: None,
+            overdraft_limit: Some(Funds::ZERO),
+            held_non_negative: true,
+        }
+    }
+
+    /// Constraints that never reject a diff
+    pub fn unconstrained() -> Self {
+        Self {
+            minimum_available: None,
+            overdraft_limit: None,
+            held_non_negative: false,
+        }
+    }
+
+    /// Requires at least `minimum` to stay available
+    pub fn with_minimum_available(self, minimum: Funds) -> Self {
+        Self {
+            minimum_available: Some(minimum),
+            ..self
+        }
+    }
+
+    /// Lets available funds go down to `-limit`, or without a limit if `None`
+    pub fn with_overdraft_limit(self, limit: Option<Funds>) -> Self {
+        Self {
+            overdraft_limit: limit,
+            ..self
+        }
+    }
+
+    pub fn with_held_non_negative(self, held_non_negative: bool) -> Self {
+        Self {
+            held_non_negative,
+            ..self
+        }
+    }
+
+    /// The same constraints without the limits on available funds, only keeping held funds
+    /// from going negative
+    pub fn without_available_limits(self) -> Self {
+        Self {
+            minimum_available: None,
+            overdraft_limit: None,
+            ..self
+        }
+    }
+
+    pub fn minimum_available(&self) -> Option<Funds> {
+        self.minimum_available
+    }
+
+    pub fn overdraft_limit(&self) -> Option<Funds> {
+        self.overdraft_limit
+    }
+
+    /// Checks the balance resulting from applying `diff`
+    fn check(&self, balance: &Balance, diff: &BalanceDiff) -> Result<(), ConstraintViolation> {
+        if diff.available.is_some_and(|da| da.is_negative()) {
+            if let Some(minimum) = self.minimum_available {
+                if balance.available < minimum {
+                    return Err(ConstraintViolation::BelowMinimumBalance(minimum));
+                }
+            }
+            if let Some(limit) = self.overdraft_limit {
+                if balance.available < -limit {
+                    return Err(ConstraintViolation::OverdraftLimitExceeded(limit));
+                }
+            }
+        }
+        if self.held_non_negative
+            && diff.held.is_some_and(|dh| dh.is_negative())
+            && balance.held.is_negative()
+        {
+            return Err(ConstraintViolation::NegativeHeld);
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for BalanceConstraints {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 /// Type to represent the internal funds balance of an account
 ///
@@ -7,20 +133,25 @@ use rust_decimal::Decimal;
 /// By making this an immutable `Copy` type we make it safe for changes to either balance which can fail
 /// independently without needing to implement rollback logic.
 ///
-/// Note that although the only possible failure in the current implementation is either balance overflowing
-/// the same API can be extended to guard against errors such as maintaining a minimum balance or held funds
-/// not being negative
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+/// Besides either balance overflowing, applying a change fails if it breaks the given
+/// `BalanceConstraints`, such as maintaining a minimum balance or held funds not being negative
+///
+/// `pending` holds deposits that haven't cleared yet. Like held funds they count towards the
+/// total but can't be used
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
 pub struct Balance {
     available: Funds,
     held: Funds,
+    #[serde(default)]
+    pending: Funds,
 }
 
 impl Balance {
     pub fn new() -> Self {
         Self {
-            available: Funds::new(0),
-            held: Funds::new(0),
+            available: Funds::ZERO,
+            held: Funds::ZERO,
+            pending: Funds::ZERO,
         }
     }
 
@@ -32,8 +163,21 @@ impl Balance {
         self.held
     }
 
-    pub fn apply(self, diff: BalanceDiff) -> Result<Self, FundsOpError> {
-        Ok(Self {
+    pub fn pending(&self) -> Funds {
+        self.pending
+    }
+
+    /// Available, held and pending funds together
+    pub fn total(&self) -> Result<Funds, FundsOpError> {
+        self.available.add(self.held)?.add(self.pending)
+    }
+
+    pub fn apply(
+        self,
+        diff: BalanceDiff,
+        constraints: &BalanceConstraints,
+    ) -> Result<Self, BalanceError> {
+        let balance = Self {
             available: match diff.available {
                 Some(da) => self.available.add(da)?,
                 None => self.available,
@@ -42,7 +186,14 @@ impl Balance {
                 Some(dh) => self.held.add(dh)?,
                 None => self.held,
             },
-        })
+            pending: match diff.pending {
+                Some(dp) => self.pending.add(dp)?,
+                None => self.pending,
+            },
+        };
+        constraints.check(&balance, &diff)?;
+
+        Ok(balance)
     }
 }
 
@@ -53,10 +204,11 @@ impl Default for Balance {
 }
 
 /// Represents a change to an account's `Balance`
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub struct BalanceDiff {
     available: Option<Funds>,
     held: Option<Funds>,
+    pending: Option<Funds>,
 }
 
 impl BalanceDiff {
@@ -64,22 +216,42 @@ impl BalanceDiff {
         Self {
             available: None,
             held: None,
+            pending: None,
+        }
+    }
+
+    pub fn with_available(self, da: Funds) -> Self {
+        Self {
+            available: Some(da),
+            ..self
         }
     }
 
-    pub fn with_available<T: Into<Decimal>>(self, da: T) -> Self {
+    pub fn with_held(self, dh: Funds) -> Self {
         Self {
-            available: Some(Funds::new(da)),
-            held: self.held,
+            held: Some(dh),
+            ..self
         }
     }
 
-    pub fn with_held<T: Into<Decimal>>(self, dh: T) -> Self {
+    pub fn with_pending(self, dp: Funds) -> Self {
         Self {
-            available: self.available,
-            held: Some(Funds::new(dh)),
+            pending: Some(dp),
+            ..self
         }
     }
+
+    pub fn available(&self) -> Option<Funds> {
+        self.available
+    }
+
+    pub fn held(&self) -> Option<Funds> {
+        self.held
+    }
+
+    pub fn pending(&self) -> Option<Funds> {
+        self.pending
+    }
 }
 
 impl Default for BalanceDiff {
@@ -97,26 +269,97 @@ mod test {
     #[test]
     fn test_balance_apply() {
         assert_eq!(
-            Balance::new().apply(BalanceDiff::new().with_available(100).with_held(-100)),
+            Balance::new().apply(
+                BalanceDiff::new()
+                    .with_available(Funds::new(100))
+                    .with_held(Funds::new(-100)),
+                &BalanceConstraints::unconstrained(),
+            ),
             Ok(Balance {
                 available: Funds::new(100),
                 held: Funds::new(-100),
+                pending: Funds::ZERO,
             }),
         );
     }
 
+    #[test]
+    fn test_balance_pending() {
+        let balance = Balance::new()
+            .apply(
+                BalanceDiff::new().with_pending(Funds::new(5)),
+                &BalanceConstraints::new(),
+            )
+            .expect("To succeed");
+        assert_eq!(balance.available(), Funds::ZERO);
+        assert_eq!(balance.pending(), Funds::new(5));
+        assert_eq!(balance.total(), Ok(Funds::new(5)));
+    }
+
     #[test]
     fn test_balance_apply_overflow() {
         assert_eq!(
             Balance::new()
                 .apply(
                     BalanceDiff::new()
-                        .with_available(Decimal::MAX)
-                        .with_held(Decimal::MAX)
+                        .with_available(Funds::MAX)
+                        .with_held(Funds::MAX),
+                    &BalanceConstraints::new(),
                 )
                 .expect("To succeed")
-                .apply(BalanceDiff::new().with_available(1)),
-            Err(FundsOpError::Overflow),
+                .apply(
+                    BalanceDiff::new().with_available(Funds::new(1)),
+                    &BalanceConstraints::new()
+                ),
+            Err(BalanceError::Funds(FundsOpError::Overflow)),
         );
     }
+
+    #[test]
+    fn test_balance_constraints() {
+        let balance = Balance::new()
+            .apply(
+                BalanceDiff::new().with_available(Funds::new(10)),
+                &BalanceConstraints::new(),
+            )
+            .expect("To succeed");
+        let withdraw = |amount: i32| BalanceDiff::new().with_available(Funds::new(-amount));
+
+        assert_eq!(
+            balance.apply(withdraw(11), &BalanceConstraints::new()),
+            Err(BalanceError::Violation(
+                ConstraintViolation::OverdraftLimitExceeded(Funds::ZERO)
+            )),
+        );
+        let overdraft = BalanceConstraints::new().with_overdraft_limit(Some(Funds::new(5)));
+        assert!(balance.apply(withdraw(15), &overdraft).is_ok());
+        assert_eq!(
+            balance.apply(withdraw(16), &overdraft),
+            Err(BalanceError::Violation(
+                ConstraintViolation::OverdraftLimitExceeded(Funds::new(5))
+            )),
+        );
+        let minimum = BalanceConstraints::new().with_minimum_available(Funds::new(3));
+        assert_eq!(
+            balance.apply(withdraw(8), &minimum),
+            Err(BalanceError::Violation(
+                ConstraintViolation::BelowMinimumBalance(Funds::new(3))
+            )),
+        );
+        assert_eq!(
+            balance.apply(
+                BalanceDiff::new().with_held(Funds::new(-1)),
+                &BalanceConstraints::new()
+            ),
+            Err(BalanceError::Violation(ConstraintViolation::NegativeHeld)),
+        );
+
+        // Limits only apply to diffs moving the balance towards them
+        let overdrawn = balance
+            .apply(withdraw(20), &BalanceConstraints::unconstrained())
+            .expect("To succeed");
+        assert!(overdrawn
+            .apply(withdraw(-1), &BalanceConstraints::new())
+            .is_ok());
+    }
 }
@@ -1,103 +1,635 @@
+use clap::ArgEnum;
 use clap::Parser;
 use csv::ReaderBuilder;
 use csv::Trim;
 use csv::Writer;
-use rust_decimal::Decimal;
 use serde::Serialize;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::num::NonZeroUsize;
 use std::path::Path;
 use std::sync::mpsc::channel;
 use std::sync::mpsc::Receiver;
 use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::sync::Mutex;
 use txk::account::Account;
+use txk::account_store::AccountStore;
+use txk::account_store::MemoryAccountStore;
+use txk::balance::Balance;
+use txk::balance::BalanceConstraints;
+use txk::currency::Currency;
+use txk::currency::DEFAULT_CURRENCY;
+use txk::deduplicator::Deduplicator;
+use txk::deficit_policy::DeficitPolicy;
+use txk::disk_account_store::DiskAccountStore;
+use txk::dispute_expiry::DisputeDuration;
+use txk::dispute_expiry::DisputeExpiry;
+use txk::dispute_expiry::ExpiryAction;
+use txk::dispute_policy::DepositsOnly;
+use txk::dispute_policy::DisputePolicy;
+use txk::dispute_policy::WithdrawalsDisputable;
+use txk::freeze_level::FreezeLevel;
+use txk::funds::Funds;
+use txk::funds::FundsOpError;
+use txk::journal::CsvJournalSink;
+use txk::journal::Journal;
+use txk::precision::PrecisionTable;
+use txk::precision::DEFAULT_SCALE;
+use txk::rounding::RoundingMode;
+use txk::snapshot::Snapshot;
 use txk::transaction::ClientID;
 use txk::transaction::Transaction;
+use txk::transaction::TransactionType;
+use txk::transaction::TransferLeg;
+use txk::transaction_engine::AccountCreation;
+use txk::transaction_engine::ExpiredDispute;
 use txk::transaction_engine::TransactionEngine;
-
-// TODO: Move this to balance or serialisation
-const MAX_DEC_DIGITS: u32 = 4;
+use txk::transaction_index::TransactionIndex;
+use txk::wal::WalRecord;
+use txk::wal::WriteAheadLog;
 
 const NUM_THREADS: usize = 8;
 
+const CACHE_SIZE: usize = 10_000;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+type SharedJournalSink = Arc<Mutex<CsvJournalSink<File>>>;
+
+type Engine = TransactionEngine<Box<dyn AccountStore>>;
+
+/// Work sent to a processing thread
+enum Message {
+    Transaction(Transaction),
+    /// Prepare this thread's leg of a transfer with a client on another thread, reply whether it
+    /// succeeded and wait for a `FinishTransfer`
+    PrepareTransfer(Transaction, TransferLeg, Sender<bool>),
+    /// Commit the prepared leg if both legs succeeded, otherwise abort it
+    FinishTransfer(bool),
+}
+
+/// What processing threads report back to the main thread, which prints it
+enum Output {
+    Record(OutRecord),
+    ExpiredDispute(ExpiredDispute),
+    Error(anyhow::Error),
+}
+
+/// Balance of one asset held by a client
 #[derive(Serialize)]
 struct OutRecord {
     client: ClientID,
-    available: Decimal,
-    held: Decimal,
-    total: Decimal,
+    available: Funds,
+    held: Funds,
+    total: Funds,
     locked: bool,
+    freeze_level: FreezeLevel,
+    currency: Currency,
+    /// Whether a dispute left the account in deficit, see `DeficitPolicy::Flag`
+    deficit: bool,
+    /// Number of disputes settled automatically because they expired
+    expired_disputes: u64,
+    pending: Funds,
 }
 
 impl OutRecord {
-    fn new(account: &Account) -> Self {
-        let available: Decimal = account.balance().available().into();
-        let held: Decimal = account.balance().held().into();
-        let total = available + held;
-        Self {
+    /// Balances are rounded to the precision of their asset, although amounts are checked
+    /// against it when they're received so there shouldn't be anything to round
+    fn new(
+        account: &Account,
+        currency: &Currency,
+        balance: Balance,
+        precision: &PrecisionTable,
+    ) -> Result<Self, FundsOpError> {
+        let total = balance.total()?;
+        Ok(Self {
             client: account.client_id(),
-            available: available.round_dp(MAX_DEC_DIGITS),
-            held: held.round_dp(MAX_DEC_DIGITS),
-            total: total.round_dp(MAX_DEC_DIGITS),
+            available: precision.round(currency, balance.available())?,
+            held: precision.round(currency, balance.held())?,
+            total: precision.round(currency, total)?,
             locked: account.is_frozen(),
-        }
+            freeze_level: account.freeze_level(),
+            currency: currency.clone(),
+            deficit: account.is_in_deficit(),
+            expired_disputes: account.expired_disputes(),
+            pending: precision.round(currency, balance.pending())?,
+        })
     }
 }
 
+#[derive(ArgEnum, Clone, Copy, Debug)]
+enum OutputFormat {
+    /// One row per client and asset
+    Long,
+    /// One row per client, with balance columns for every asset
+    Wide,
+}
+
 #[derive(Parser, Debug)]
 struct Args {
     #[clap(short, long, default_value_t = NUM_THREADS)]
     num_threads: usize,
+    /// Only open accounts for clients on their first successful deposit
+    #[clap(long)]
+    deposits_open_accounts: bool,
+    /// Allow withdrawals to be disputed as well as deposits
+    #[clap(long)]
+    dispute_withdrawals: bool,
+    /// How far below zero withdrawals and other debits can take an account's available funds
+    #[clap(long, default_value = "0")]
+    overdraft_limit: Funds,
+    /// Available funds withdrawals and other debits have to leave in an account
+    #[clap(long)]
+    minimum_balance: Option<Funds>,
+    /// Unfreeze accounts when the chargeback that froze them is reversed
+    #[clap(long)]
+    unfreeze_on_reversal: bool,
+    /// What to do with disputes that would leave the available funds negative: allow, reject,
+    /// cap (at the available funds) or flag (allow and report the account in deficit)
+    #[clap(long, default_value_t = DeficitPolicy::default())]
+    deficit_policy: DeficitPolicy,
+    /// Write the events applied to each account to this file
+    #[clap(long)]
+    journal: Option<String>,
+    /// Start from the state saved in this snapshot
+    #[clap(long)]
+    load_snapshot: Option<String>,
+    /// Save the state after processing the input to this snapshot
+    #[clap(long)]
+    save_snapshot: Option<String>,
+    /// Record incoming transactions in this write-ahead log, replaying the ones that aren't in
+    /// the loaded snapshot on startup. The log is cleared once the new snapshot has been saved
+    #[clap(long, requires = "save-snapshot")]
+    wal: Option<String>,
+    /// Keep accounts on disk in this directory instead of in memory (one file per thread)
+    #[clap(long)]
+    account_store: Option<String>,
+    /// Number of accounts each thread caches in memory when using --account-store
+    #[clap(long, default_value_t = CACHE_SIZE)]
+    cache_size: usize,
+    /// Reject replays of any of the last N transactions. Remembered transactions are saved in
+    /// snapshots so replays are still detected in later runs
+    #[clap(long)]
+    dedup_window: Option<usize>,
+    /// Release authorization holds that haven't been captured or voided after N more
+    /// transactions on the account
+    #[clap(long)]
+    hold_expiry: Option<u64>,
+    /// Reject disputes of transactions that happened more than N days ago
+    #[clap(long)]
+    dispute_window_days: Option<u64>,
+    /// Settle disputes that are still open after N more transactions on the account
+    #[clap(long, conflicts_with = "dispute-expiry-days")]
+    dispute_expiry_transactions: Option<u64>,
+    /// Settle disputes that are still open N days after they were opened. Checked before every
+    /// transaction on the account, and for every account before the output is written
+    #[clap(long)]
+    dispute_expiry_days: Option<u64>,
+    /// How expired disputes are settled: resolve or chargeback
+    #[clap(long, default_value_t = ExpiryAction::default())]
+    dispute_expiry_action: ExpiryAction,
+    /// Asset of deposits and withdrawals that don't have a currency
+    #[clap(long, default_value = DEFAULT_CURRENCY)]
+    default_currency: String,
+    /// Decimal places allowed for an asset, as CURRENCY=PLACES (e.g. BTC=8). Can be repeated
+    #[clap(long, parse(try_from_str = parse_precision), multiple_occurrences(true))]
+    precision: Vec<(Currency, u32)>,
+    /// Days deposits in an asset are pending before they clear, as CURRENCY=DAYS (e.g. USD=3).
+    /// Can be repeated
+    #[clap(long, parse(try_from_str = parse_clearing_days), multiple_occurrences(true))]
+    clearing_days: Vec<(Currency, u64)>,
+    /// Decimal places allowed for assets without a --precision
+    #[clap(long, default_value_t = DEFAULT_SCALE)]
+    default_precision: u32,
+    /// How balances are rounded to the precision of their asset: half-even, half-up, down, up
+    /// or truncate
+    #[clap(long, default_value_t = RoundingMode::default())]
+    rounding: RoundingMode,
+    #[clap(long, arg_enum, default_value = "long")]
+    output_format: OutputFormat,
     input_file: String,
 }
 
-fn receiver_thread(out: Sender<anyhow::Result<OutRecord>>, input: Receiver<Transaction>) {
-    let mut engine = TransactionEngine::new();
+fn parse_precision(s: &str) -> anyhow::Result<(Currency, u32)> {
+    let (currency, places) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow::anyhow!("Expected CURRENCY=PLACES"))?;
+    Ok((Currency::new(currency), places.parse()?))
+}
+
+fn parse_clearing_days(s: &str) -> anyhow::Result<(Currency, u64)> {
+    let (currency, days) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow::anyhow!("Expected CURRENCY=DAYS"))?;
+    Ok((Currency::new(currency), days.parse()?))
+}
 
-    for transaction in input {
+/// Converts a number of days given on the command line to seconds
+fn days_to_seconds(days: u64) -> anyhow::Result<u64> {
+    days.checked_mul(SECONDS_PER_DAY)
+        .ok_or_else(|| anyhow::anyhow!("{} days is too long a duration", days))
+}
+
+/// Writes the events the engine journaled since the last call
+fn write_journal(out: &Sender<Output>, engine: &mut Engine, journal: &Option<SharedJournalSink>) {
+    if let Some(journal) = journal {
+        let mut sink = journal
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Err(e) = engine.journal_mut().write_to(&mut *sink) {
+            let _ = out.send(Output::Error(anyhow::anyhow!(
+                "Failed to write journal: {}",
+                e
+            )));
+        }
+    }
+}
+
+fn receiver_thread(
+    out: Sender<Output>,
+    input: Receiver<Message>,
+    mut engine: Engine,
+    journal: Option<SharedJournalSink>,
+) -> Engine {
+    while let Ok(message) = input.recv() {
         // Forward errors to be logged
-        if let Err(e) = engine.process(transaction) {
-            let _ = out.send(Err(anyhow::anyhow!(e)));
+        let result = match message {
+            Message::Transaction(transaction) => {
+                let result = engine.process(transaction);
+                for &expired in engine.expired_disputes() {
+                    let _ = out.send(Output::ExpiredDispute(expired));
+                }
+                result
+            }
+            Message::PrepareTransfer(transaction, leg, vote) => {
+                match engine.prepare_transfer(transaction, leg) {
+                    Ok(transfer) => {
+                        let _ = vote.send(true);
+                        // Nothing else is sent to this thread until the transfer is decided
+                        match input.recv() {
+                            Ok(Message::FinishTransfer(true)) => engine.commit_transfer(transfer),
+                            _ => {
+                                engine.abort_transfer(transfer);
+                                Ok(())
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        let _ = vote.send(false);
+                        // Wait for the decision so the protocol stays in step
+                        let _ = input.recv();
+                        Err(e)
+                    }
+                }
+            }
+            Message::FinishTransfer(_) => Ok(()),
+        };
+        if let Err(e) = result {
+            let _ = out.send(Output::Error(anyhow::anyhow!(e)));
         }
+
+        write_journal(&out, &mut engine, &journal);
+    }
+
+    // Accounts only check their deadlines when they see a transaction, so catch up with the ones
+    // idle accounts missed before reporting their balances
+    if let Err(e) = engine.settle_due() {
+        let _ = out.send(Output::Error(anyhow::anyhow!(e)));
+    }
+    for &expired in engine.expired_disputes() {
+        let _ = out.send(Output::ExpiredDispute(expired));
     }
+    write_journal(&out, &mut engine, &journal);
 
-    for account in engine.accounts().values() {
-        let _ = out.send(Ok(OutRecord::new(account)));
+    for account in engine.store().iter() {
+        match account {
+            Ok(account) => {
+                for (currency, balance) in account.balances() {
+                    let record = OutRecord::new(&account, currency, balance, engine.precision())
+                        .map_err(|e| {
+                            anyhow::anyhow!(
+                                "Failed to round {} balance of client {}: {}",
+                                currency,
+                                account.client_id(),
+                                e
+                            )
+                        });
+                    let _ = out.send(match record {
+                        Ok(record) => Output::Record(record),
+                        Err(e) => Output::Error(e),
+                    });
+                }
+            }
+            Err(e) => {
+                let _ = out.send(Output::Error(anyhow::anyhow!(e)));
+            }
+        }
     }
+
+    engine
+}
+
+/// Writes one row per client with the balances of every asset any client holds
+fn write_wide<W: std::io::Write>(out: &mut Writer<W>, records: Vec<OutRecord>) -> csv::Result<()> {
+    let currencies: BTreeSet<Currency> = records.iter().map(|r| r.currency.clone()).collect();
+    let mut clients: BTreeMap<ClientID, Vec<OutRecord>> = BTreeMap::new();
+    for record in records {
+        clients.entry(record.client).or_default().push(record);
+    }
+
+    let mut header = vec![
+        "client".to_string(),
+        "locked".to_string(),
+        "freeze_level".to_string(),
+        "deficit".to_string(),
+        "expired_disputes".to_string(),
+    ];
+    for currency in &currencies {
+        for column in ["available", "held", "total", "pending"] {
+            header.push(format!("{}_{}", currency, column));
+        }
+    }
+    out.write_record(&header)?;
+
+    for (client, records) in clients {
+        // The freeze level, deficit flag and expired disputes are the same for every asset of the
+        // account
+        let (locked, freeze_level, deficit, expired_disputes) = (
+            records[0].locked,
+            records[0].freeze_level,
+            records[0].deficit,
+            records[0].expired_disputes,
+        );
+        let mut balances = vec![];
+        for currency in &currencies {
+            match records.iter().find(|r| &r.currency == currency) {
+                Some(r) => balances.extend([r.available, r.held, r.total, r.pending]),
+                None => balances.extend([Funds::ZERO; 4]),
+            }
+        }
+        out.serialize((
+            client,
+            locked,
+            freeze_level,
+            deficit,
+            expired_disputes,
+            balances,
+        ))?;
+    }
+
+    Ok(())
+}
+
+/// Threads of the sending and receiving clients of a transfer, if they're different
+fn transfer_threads(t: &Transaction, num_threads: usize) -> Option<(usize, usize)> {
+    let to = t.to.filter(|_| t.tx_type == TransactionType::Transfer)?;
+    let (debit, credit) = (t.client as usize % num_threads, to as usize % num_threads);
+    (debit != credit).then_some((debit, credit))
+}
+
+/// Applies a transfer between clients on different threads with a two-phase commit: each thread
+/// checks its leg, and the legs are only applied if both of them succeeded
+fn transfer(senders: &[Sender<Message>], t: Transaction, debit: usize, credit: usize) {
+    let (vote_sender, votes) = channel();
+    let _ = senders[debit].send(Message::PrepareTransfer(
+        t.clone(),
+        TransferLeg::Debit,
+        vote_sender.clone(),
+    ));
+    let _ = senders[credit].send(Message::PrepareTransfer(
+        t,
+        TransferLeg::Credit,
+        vote_sender,
+    ));
+    // A thread that went away without voting counts as a failure
+    let votes: Vec<bool> = votes.iter().take(2).collect();
+    let commit = votes.len() == 2 && votes.iter().all(|&vote| vote);
+    let _ = senders[debit].send(Message::FinishTransfer(commit));
+    let _ = senders[credit].send(Message::FinishTransfer(commit));
+}
+
+/// Replays a transfer between clients on different threads from the write-ahead log, the same way
+/// `transfer` applies it
+fn replay_transfer(engines: &mut [Engine], record: WalRecord, debit: usize, credit: usize) {
+    let debit_leg = engines[debit].prepare_replayed_transfer(&record, TransferLeg::Debit);
+    let credit_leg = engines[credit].prepare_replayed_transfer(&record, TransferLeg::Credit);
+    match (debit_leg, credit_leg) {
+        (Ok(Some(debit_leg)), Ok(Some(credit_leg))) => {
+            let _ = engines[debit].commit_transfer(debit_leg);
+            let _ = engines[credit].commit_transfer(credit_leg);
+        }
+        (debit_leg, credit_leg) => {
+            if let Ok(Some(debit_leg)) = debit_leg {
+                engines[debit].abort_transfer(debit_leg);
+            }
+            if let Ok(Some(credit_leg)) = credit_leg {
+                engines[credit].abort_transfer(credit_leg);
+            }
+        }
+    }
+}
+
+fn save_snapshot(engines: Vec<Engine>, path: &str) -> anyhow::Result<()> {
+    let mut snapshot = Snapshot::new(vec![], TransactionIndex::new());
+    for engine in &engines {
+        snapshot.merge(engine.snapshot_without_accounts());
+    }
+
+    // Write to a temporary file first so that a failure halfway through doesn't clobber
+    // the previous snapshot
+    let tmp_path = format!("{}.tmp", path);
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    // Accounts are streamed from the engines' stores, which may not fit in memory
+    snapshot.write_with(
+        &mut writer,
+        engines.iter().flat_map(|engine| engine.store().iter()),
+    )?;
+    // The write-ahead log is cleared once this returns, so the snapshot must be on disk by then
+    writer
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .sync_all()?;
+    std::fs::rename(&tmp_path, path)?;
+    let dir = match Path::new(path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()?;
+
+    Ok(())
 }
 
 fn main() -> anyhow::Result<()> {
     let args = Args::parse();
 
     // Set up output channel
-    let (out_sender, out_receiver) = channel::<anyhow::Result<OutRecord>>();
+    let (out_sender, out_receiver) = channel::<Output>();
+
+    // Transaction IDs are unique across all clients, so every thread shares the same index.
+    // Threads claim IDs as they process them, so when clients on different threads reuse an ID
+    // which of them gets it (and the error reported for the others) depends on scheduling
+    let index = TransactionIndex::shared();
+    let account_creation = if args.deposits_open_accounts {
+        AccountCreation::DepositsOnly
+    } else {
+        AccountCreation::OnSuccess
+    };
+    let balance_constraints = args.minimum_balance.into_iter().fold(
+        BalanceConstraints::new().with_overdraft_limit(Some(args.overdraft_limit)),
+        BalanceConstraints::with_minimum_available,
+    );
+    let dispute_expiry = match (args.dispute_expiry_transactions, args.dispute_expiry_days) {
+        (Some(transactions), _) => Some(DisputeDuration::Transactions(transactions)),
+        (None, Some(days)) => Some(DisputeDuration::Seconds(days_to_seconds(days)?)),
+        (None, None) => None,
+    }
+    .map(|duration| DisputeExpiry::new(duration, args.dispute_expiry_action));
+    let dispute_window = args.dispute_window_days.map(days_to_seconds).transpose()?;
+    let clearing_periods = args
+        .clearing_days
+        .iter()
+        .map(|(currency, days)| Ok((currency.clone(), days_to_seconds(*days)?)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let dispute_policy: Arc<dyn DisputePolicy> = if args.dispute_withdrawals {
+        Arc::new(WithdrawalsDisputable)
+    } else {
+        Arc::new(DepositsOnly)
+    };
+    // Threads write to the same journal so entries for different clients can be interleaved,
+    // and sequence numbers are only unique per thread
+    let journal = match &args.journal {
+        Some(path) => Some(Arc::new(Mutex::new(CsvJournalSink::new(File::create(
+            path,
+        )?)))),
+        None => None,
+    };
+
+    let precision = args.precision.iter().cloned().fold(
+        PrecisionTable::new()
+            .with_default_scale(args.default_precision)
+            .with_rounding(args.rounding),
+        |table, (currency, scale)| table.with_scale(currency, scale),
+    );
+
+    // Shared so that replays are detected regardless of which thread processed the original
+    let deduplicator = args
+        .dedup_window
+        .map(|capacity| Deduplicator::new(capacity).shared());
 
     // Set up processing threads
     let num_threads = std::cmp::max(args.num_threads, 1);
+    // Each thread starts from the part of the snapshot holding the clients routed to it
+    let mut snapshots = match &args.load_snapshot {
+        Some(path) => Snapshot::read_from(BufReader::new(File::open(path)?))?
+            .split(num_threads, |client| client as usize),
+        None => vec![],
+    }
+    .into_iter();
+    let mut engines = vec![];
+    for thread_num in 0..num_threads {
+        // Stores on disk only live for the duration of the run, state is carried over between
+        // runs with snapshots
+        let store: Box<dyn AccountStore> = match &args.account_store {
+            Some(dir) => Box::new(DiskAccountStore::create(
+                Path::new(dir).join(format!("accounts-{}.jsonl", thread_num)),
+                NonZeroUsize::new(args.cache_size)
+                    .ok_or_else(|| anyhow::anyhow!("Cache size must be at least 1"))?,
+            )?),
+            None => Box::new(MemoryAccountStore::new()),
+        };
+        let mut engine = TransactionEngine::with_index(index.clone())
+            .with_store(store)
+            .with_account_creation(account_creation)
+            .with_dispute_policy(dispute_policy.clone())
+            .with_unfreeze_on_reversal(args.unfreeze_on_reversal)
+            .with_deficit_policy(args.deficit_policy)
+            .with_balance_constraints(balance_constraints)
+            .with_default_currency(Currency::new(&args.default_currency))
+            .with_precision(precision.clone());
+        if journal.is_some() {
+            engine = engine.with_journal(Journal::new());
+        }
+        if let Some(deduplicator) = &deduplicator {
+            engine = engine.with_deduplicator(deduplicator.clone());
+        }
+        if let Some(transactions) = args.hold_expiry {
+            engine = engine.with_hold_expiry(transactions);
+        }
+        if let Some(seconds) = dispute_window {
+            engine = engine.with_dispute_window(seconds);
+        }
+        if let Some(dispute_expiry) = dispute_expiry {
+            engine = engine.with_dispute_expiry(dispute_expiry);
+        }
+        for (currency, seconds) in &clearing_periods {
+            engine = engine.with_clearing_period(currency.clone(), *seconds);
+        }
+        if let Some(snapshot) = snapshots.next() {
+            engine.restore(snapshot)?;
+        }
+        engines.push(engine);
+    }
+
+    // Recover the transactions that were logged after the snapshot was taken, in the order
+    // they were originally received
+    let wal = match &args.wal {
+        Some(path) => {
+            let (wal, tail) = WriteAheadLog::open(path)?;
+            for record in tail {
+                // Transactions that failed were already reported when they were first received
+                match transfer_threads(&record.transaction, num_threads) {
+                    Some((debit, credit)) => replay_transfer(&mut engines, record, debit, credit),
+                    None => {
+                        let thread_num = (record.transaction.client as usize) % num_threads;
+                        let _ = engines[thread_num].replay(record);
+                    }
+                }
+            }
+            let wal = wal.shared();
+            engines = engines
+                .into_iter()
+                .map(|engine| engine.with_write_ahead_log(wal.clone()))
+                .collect();
+            Some(wal)
+        }
+        None => None,
+    };
+
     let mut input_senders = vec![];
     let mut receiver_threads = vec![];
-    for (sender, receiver) in std::iter::repeat_with(channel::<Transaction>).take(num_threads)
-    {
+    for engine in engines {
+        let (sender, receiver) = channel::<Message>();
         input_senders.push(sender);
         let out = out_sender.clone();
+        let journal = journal.clone();
         receiver_threads.push(std::thread::spawn(move || {
-            receiver_thread(out, receiver);
+            receiver_thread(out, receiver, engine, journal)
         }));
     }
 
+    // Trailing optional columns (e.g. `reason`) can be left out of rows that don't need them
     let mut reader = ReaderBuilder::new()
         .trim(Trim::All)
+        .flexible(true)
         .from_path(Path::new(&args.input_file))?;
 
     // Route input from file into the right thread based on the client id
-    for transaction in reader.deserialize::<Transaction>()
-    {
+    for transaction in reader.deserialize::<Transaction>() {
         match transaction {
-            Ok(t) => {
-                let thread_num = (t.client as usize) % num_threads;
-                let _ = input_senders[thread_num].send(t);
-            }
+            Ok(t) => match transfer_threads(&t, num_threads) {
+                Some((debit, credit)) => transfer(&input_senders, t, debit, credit),
+                None => {
+                    let thread_num = (t.client as usize) % num_threads;
+                    let _ = input_senders[thread_num].send(Message::Transaction(t));
+                }
+            },
             // Forward error to be logged
             Err(e) => {
-                let _ = out_sender.send(Err(anyhow::anyhow!(e)));
+                let _ = out_sender.send(Output::Error(anyhow::anyhow!(e)));
             }
         }
     }
@@ -107,18 +639,53 @@ fn main() -> anyhow::Result<()> {
     drop(out_sender);
 
     let mut out = Writer::from_writer(std::io::stdout());
-    for record in out_receiver {
-        match record {
-            Ok(r) => {
-                if let Err(e) = out.serialize(&r) {
-                    eprintln!("Failed to seralize record for account {}: {}", r.client, e);
+    // The wide format needs every asset up front for its header
+    let mut wide_records = vec![];
+    for output in out_receiver {
+        match output {
+            Output::Record(r) => match args.output_format {
+                OutputFormat::Long => {
+                    if let Err(e) = out.serialize(&r) {
+                        eprintln!("Failed to seralize record for account {}: {}", r.client, e);
+                    }
                 }
+                OutputFormat::Wide => wide_records.push(r),
+            },
+            Output::ExpiredDispute(expired) => {
+                eprintln!(
+                    "Dispute of transaction {} for account {} expired, settled with a {}",
+                    expired.transaction, expired.client, expired.action
+                );
             }
-            Err(e) => {
+            Output::Error(e) => {
                 eprintln!("Failed to process transaction: {}", e);
             }
         }
     }
+    if let OutputFormat::Wide = args.output_format {
+        if let Err(e) = write_wide(&mut out, wide_records) {
+            eprintln!("Failed to seralize records: {}", e);
+        }
+    }
+
+    if let Some(path) = &args.save_snapshot {
+        let engines = receiver_threads
+            .into_iter()
+            .map(|thread| {
+                thread
+                    .join()
+                    .map_err(|_| anyhow::anyhow!("Processing thread panicked"))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        save_snapshot(engines, path)?;
+
+        // Everything in the log is now part of the snapshot
+        if let Some(wal) = wal {
+            wal.lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .reset()?;
+        }
+    }
 
     Ok(())
 }
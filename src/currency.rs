@@ -0,0 +1,56 @@
+use serde::Deserialize;
+use serde::Deserializer;
+use serde::Serialize;
+use std::fmt;
+
+/// Asset amounts are in when a transaction doesn't say
+pub const DEFAULT_CURRENCY: &str = "USD";
+
+/// Code of the asset an amount is denominated in (e.g. `USD`, `EUR` or `BTC`)
+///
+/// Codes are case insensitive, they're stored in upper case
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Serialize)]
+#[serde(transparent)]
+pub struct Currency(String);
+
+impl Currency {
+    pub fn new(code: &str) -> Self {
+        Self(code.trim().to_uppercase())
+    }
+
+    pub fn code(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Default for Currency {
+    fn default() -> Self {
+        Self::new(DEFAULT_CURRENCY)
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Currency {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Self::new(&String::deserialize(deserializer)?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_case_insensitive() {
+        assert_eq!(Currency::new(" btc"), Currency::new("BTC"));
+        assert_eq!(
+            serde_json::from_str::<Currency>(r#""eur""#).expect("Deserialization to succeed"),
+            Currency::new("EUR")
+        );
+    }
+}
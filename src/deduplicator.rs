@@ -0,0 +1,218 @@
+use crate::transaction::Transaction;
+use serde::de;
+use serde::Deserialize;
+use serde::Deserializer;
+use serde::Serialize;
+use serde::Serializer;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+const FNV_OFFSET_BASIS: u128 = 0x6c62272e07bb014262b821756295c58d;
+const FNV_PRIME: u128 = 0x0000000001000000000000000000013b;
+
+/// Identifies a transaction for the purpose of detecting replays
+///
+/// Transactions with an idempotency key are identified by the key alone, any other transaction
+/// by its full contents. Identical rows are therefore the same transaction, so rows that repeat
+/// an operation on purpose (e.g. two partial disputes of the same amount) need an idempotency key
+/// or a timestamp to tell them apart
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct Fingerprint(u128);
+
+impl Fingerprint {
+    pub fn of(transaction: &Transaction) -> Self {
+        match &transaction.idempotency_key {
+            Some(key) => Self::hash(&[b"key:", key.as_bytes()]),
+            None => {
+                // Serializing a transaction can't fail: it has no maps and no non-string keys
+                let contents =
+                    serde_json::to_vec(transaction).expect("Transaction to be serializable");
+                Self::hash(&[b"tx:", &contents])
+            }
+        }
+    }
+
+    /// 128 bit FNV-1a, which unlike `std`'s hasher is stable across builds
+    fn hash(parts: &[&[u8]]) -> Self {
+        let hash = parts
+            .iter()
+            .flat_map(|part| part.iter())
+            .fold(FNV_OFFSET_BASIS, |hash, &byte| {
+                (hash ^ byte as u128).wrapping_mul(FNV_PRIME)
+            });
+        Self(hash)
+    }
+}
+
+// Serialized as hex strings since json numbers can't hold 128 bit integers reliably
+impl Serialize for Fingerprint {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("{:032x}", self.0))
+    }
+}
+
+impl<'de> Deserialize<'de> for Fingerprint {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        u128::from_str_radix(&hex, 16)
+            .map(Fingerprint)
+            .map_err(de::Error::custom)
+    }
+}
+
+/// Remembers the fingerprints of the most recent transactions to detect replays
+///
+/// Memory use is bounded by `capacity`: once it's full the oldest fingerprint is forgotten, so
+/// only replays within the last `capacity` transactions are detected.
+#[derive(Debug, Clone)]
+pub struct Deduplicator {
+    capacity: usize,
+    seen: HashSet<Fingerprint>,
+    /// Fingerprints in the order they were seen, oldest first
+    order: VecDeque<Fingerprint>,
+}
+
+pub type SharedDeduplicator = Arc<Mutex<Deduplicator>>;
+
+impl Deduplicator {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    pub fn shared(self) -> SharedDeduplicator {
+        Arc::new(Mutex::new(self))
+    }
+
+    /// Records `fingerprint`, returning `false` if it had already been seen
+    pub fn insert(&mut self, fingerprint: Fingerprint) -> bool {
+        if self.capacity == 0 {
+            return true;
+        }
+        if !self.seen.insert(fingerprint) {
+            return false;
+        }
+
+        self.order.push_back(fingerprint);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    /// Forgets `fingerprint`, e.g. when the transaction couldn't be processed after all
+    pub fn remove(&mut self, fingerprint: Fingerprint) {
+        if self.seen.remove(&fingerprint) {
+            self.order.retain(|f| *f != fingerprint);
+        }
+    }
+
+    /// Fingerprints currently remembered, oldest first
+    pub fn fingerprints(&self) -> impl Iterator<Item = Fingerprint> + '_ {
+        self.order.iter().copied()
+    }
+
+    /// Records every fingerprint in `fingerprints`, in order
+    pub fn extend<I: IntoIterator<Item = Fingerprint>>(&mut self, fingerprints: I) {
+        for fingerprint in fingerprints {
+            self.insert(fingerprint);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::funds::Funds;
+    use crate::transaction::TransactionType;
+    use rust_decimal_macros::dec;
+
+    fn withdrawal(transaction: u32, idempotency_key: Option<&str>) -> Transaction {
+        Transaction {
+            tx_type: TransactionType::Withdrawal,
+            client: 1,
+            transaction,
+            amount: Some(Funds::new(dec!(1.5))),
+            idempotency_key: idempotency_key.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        assert_eq!(
+            Fingerprint::of(&withdrawal(1, None)),
+            Fingerprint::of(&withdrawal(1, None))
+        );
+        assert_ne!(
+            Fingerprint::of(&withdrawal(1, None)),
+            Fingerprint::of(&withdrawal(2, None))
+        );
+        // Rows that only differ by their timestamp are different transactions
+        assert_ne!(
+            Fingerprint::of(&withdrawal(1, None)),
+            Fingerprint::of(&Transaction {
+                timestamp: Some(100),
+                ..withdrawal(1, None)
+            })
+        );
+        // Only the key matters when there's one
+        assert_eq!(
+            Fingerprint::of(&withdrawal(1, Some("a"))),
+            Fingerprint::of(&withdrawal(2, Some("a")))
+        );
+    }
+
+    #[test]
+    fn test_bounded() {
+        let mut deduplicator = Deduplicator::new(2);
+        let fingerprints: Vec<Fingerprint> = (1..=3)
+            .map(|tx| Fingerprint::of(&withdrawal(tx, None)))
+            .collect();
+
+        assert!(deduplicator.insert(fingerprints[0]));
+        assert!(!deduplicator.insert(fingerprints[0]));
+        assert!(deduplicator.insert(fingerprints[1]));
+        assert!(deduplicator.insert(fingerprints[2]));
+        assert_eq!(deduplicator.len(), 2);
+        // The oldest fingerprint has been forgotten
+        assert!(deduplicator.insert(fingerprints[0]));
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut deduplicator = Deduplicator::new(2);
+        let fingerprint = Fingerprint::of(&withdrawal(1, None));
+
+        deduplicator.insert(fingerprint);
+        deduplicator.remove(fingerprint);
+        assert!(deduplicator.is_empty());
+        assert!(deduplicator.insert(fingerprint));
+    }
+
+    #[test]
+    fn test_serialize_fingerprint() {
+        let fingerprint = Fingerprint::of(&withdrawal(1, None));
+        let json = serde_json::to_value(fingerprint).expect("Serialization to succeed");
+        assert!(json.is_string());
+        assert_eq!(
+            serde_json::from_value::<Fingerprint>(json).expect("Deserialization to succeed"),
+            fingerprint
+        );
+    }
+}
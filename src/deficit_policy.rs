@@ -0,0 +1,71 @@
+use serde::Deserialize;
+use serde::Serialize;
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Debug, PartialEq, Eq, Error)]
+#[error("Unknown deficit policy {0:?}, expected one of allow, reject, cap or flag")]
+pub struct UnknownDeficitPolicy(String);
+
+/// What to do with a dispute that would leave an account's available funds negative
+///
+/// This happens when disputed funds have already been spent, e.g. a deposit that was withdrawn
+/// before being disputed
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DeficitPolicy {
+    /// Hold the whole disputed amount anyway
+    #[default]
+    Allow,
+    /// Fail the dispute
+    Reject,
+    /// Only dispute as much as is available, the rest can still be disputed later on. Fails if
+    /// nothing is available
+    Cap,
+    /// Hold the whole disputed amount and flag the account as being in deficit
+    Flag,
+}
+
+impl FromStr for DeficitPolicy {
+    type Err = UnknownDeficitPolicy;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(DeficitPolicy::Allow),
+            "reject" => Ok(DeficitPolicy::Reject),
+            "cap" => Ok(DeficitPolicy::Cap),
+            "flag" => Ok(DeficitPolicy::Flag),
+            _ => Err(UnknownDeficitPolicy(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for DeficitPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DeficitPolicy::Allow => "allow",
+            DeficitPolicy::Reject => "reject",
+            DeficitPolicy::Cap => "cap",
+            DeficitPolicy::Flag => "flag",
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        for policy in [
+            DeficitPolicy::Allow,
+            DeficitPolicy::Reject,
+            DeficitPolicy::Cap,
+            DeficitPolicy::Flag,
+        ] {
+            assert_eq!(policy.to_string().parse(), Ok(policy));
+        }
+        assert!("warn".parse::<DeficitPolicy>().is_err());
+    }
+}
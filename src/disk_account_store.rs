@@ -0,0 +1,413 @@
+use crate::account::Account;
+use crate::account_store::AccountIter;
+use crate::account_store::AccountStore;
+use crate::account_store::AccountStoreError;
+use crate::transaction::ClientID;
+use lru::LruCache;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Don't bother compacting files with less stale data than this
+const MIN_COMPACTION_BYTES: u64 = 1 << 20;
+
+/// Where the latest record of an account is in the file
+#[derive(Debug, Clone, Copy)]
+struct RecordLocation {
+    offset: u64,
+    len: u64,
+}
+
+#[derive(Debug)]
+struct CachedAccount {
+    account: Account,
+    /// Whether the account changed since it was last written to the file
+    dirty: bool,
+}
+
+/// Keeps accounts in a file, with the most recently used ones cached in memory
+///
+/// Accounts are stored as json lines in an append-only file, and only the location of each
+/// client's latest record is kept in memory. Accounts are loaded into a bounded LRU cache when
+/// they're used, and accounts that are upserted or marked as changed are written back when
+/// they're evicted from it or when the store is flushed. Writing back appends a new record rather
+/// than overwriting the old one, so once more than half the file is stale records it's rewritten
+/// with only the latest ones.
+///
+/// The store isn't crash safe by itself: accounts that are only cached are lost on a crash
+/// (see `WriteAheadLog` for recovering them)
+#[derive(Debug)]
+pub struct DiskAccountStore {
+    path: PathBuf,
+    file: File,
+    /// Length of the valid records in the file
+    len: u64,
+    /// Bytes taken by records that have been superseded by newer ones
+    stale: u64,
+    locations: HashMap<ClientID, RecordLocation>,
+    cache: LruCache<ClientID, CachedAccount>,
+}
+
+impl DiskAccountStore {
+    /// Opens (or creates) the store at `path`, caching up to `capacity` accounts in memory
+    ///
+    /// A partially written record at the end of the file is truncated
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        capacity: NonZeroUsize,
+    ) -> Result<Self, AccountStoreError> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = Self::open_file(&path, false)?;
+        let mut contents = vec![];
+        file.read_to_end(&mut contents)?;
+
+        let mut store = Self {
+            path,
+            file,
+            len: 0,
+            stale: 0,
+            locations: HashMap::new(),
+            cache: LruCache::new(capacity),
+        };
+        for line in contents.split_inclusive(|&b| b == b'\n') {
+            let account: Account = match line.strip_suffix(b"\n").map(serde_json::from_slice) {
+                Some(Ok(account)) => account,
+                _ => break,
+            };
+            store.record_written(account.client_id(), line.len() as u64);
+        }
+        if store.len < contents.len() as u64 {
+            store.file.set_len(store.len)?;
+        }
+
+        Ok(store)
+    }
+
+    /// Creates an empty store at `path`, dropping any accounts already stored there
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        capacity: NonZeroUsize,
+    ) -> Result<Self, AccountStoreError> {
+        File::create(&path)?;
+        Self::open(path, capacity)
+    }
+
+    /// Rewrites the file with only the latest record of each account
+    pub fn compact(&mut self) -> Result<(), AccountStoreError> {
+        let tmp_path = self.path.with_extension("compact");
+        let mut tmp = Self::open_file(&tmp_path, true)?;
+        let mut locations = HashMap::with_capacity(self.locations.len());
+        let mut len = 0;
+        for (&client, &location) in &self.locations {
+            let record = self.read_record(location)?;
+            tmp.write_all(&record)?;
+            locations.insert(
+                client,
+                RecordLocation {
+                    offset: len,
+                    len: location.len,
+                },
+            );
+            len += location.len;
+        }
+        tmp.sync_data()?;
+        std::fs::rename(&tmp_path, &self.path)?;
+
+        self.file = tmp;
+        self.len = len;
+        self.stale = 0;
+        self.locations = locations;
+        Ok(())
+    }
+
+    fn open_file(path: &Path, truncate: bool) -> Result<File, AccountStoreError> {
+        if truncate {
+            File::create(path)?;
+        }
+        // Records are always appended, the file is only ever rewritten as a whole
+        Ok(OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?)
+    }
+
+    fn read_record(&self, location: RecordLocation) -> Result<Vec<u8>, AccountStoreError> {
+        let mut file = &self.file;
+        let mut record = vec![0; location.len as usize];
+        file.seek(SeekFrom::Start(location.offset))?;
+        file.read_exact(&mut record)?;
+        Ok(record)
+    }
+
+    fn read(&self, location: RecordLocation) -> Result<Account, AccountStoreError> {
+        Ok(serde_json::from_slice(&self.read_record(location)?)?)
+    }
+
+    fn write(&mut self, account: &Account) -> Result<(), AccountStoreError> {
+        let mut record = serde_json::to_vec(account)?;
+        record.push(b'\n');
+        if let Err(e) = self.file.write_all(&record) {
+            // Don't leave a partial record behind, it would hide every record after it
+            let _ = self.file.set_len(self.len);
+            return Err(e.into());
+        }
+        self.record_written(account.client_id(), record.len() as u64);
+
+        if self.stale >= MIN_COMPACTION_BYTES && self.stale * 2 > self.len {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    fn record_written(&mut self, client: ClientID, len: u64) {
+        let location = RecordLocation {
+            offset: self.len,
+            len,
+        };
+        if let Some(previous) = self.locations.insert(client, location) {
+            self.stale += previous.len;
+        }
+        self.len += len;
+    }
+
+    /// Makes sure the account for `client` is cached, if there's one
+    fn load(&mut self, client: ClientID) -> Result<bool, AccountStoreError> {
+        if self.cache.contains(&client) {
+            return Ok(true);
+        }
+        let location = match self.locations.get(&client) {
+            Some(&location) => location,
+            None => return Ok(false),
+        };
+        let account = self.read(location)?;
+        self.cache(account, false)?;
+
+        Ok(true)
+    }
+
+    /// Caches `account`, writing back the least recently used account if the cache is full
+    fn cache(&mut self, account: Account, dirty: bool) -> Result<(), AccountStoreError> {
+        let client = account.client_id();
+        if !self.cache.contains(&client) && self.cache.len() == self.cache.cap().get() {
+            // Write the evicted account back before dropping it, so it isn't lost if that fails
+            if let Some((_, evicted)) = self.cache.peek_lru() {
+                if evicted.dirty {
+                    let account = evicted.account.clone();
+                    self.write(&account)?;
+                }
+            }
+            self.cache.pop_lru();
+        }
+        self.cache.put(client, CachedAccount { account, dirty });
+
+        Ok(())
+    }
+}
+
+impl AccountStore for DiskAccountStore {
+    fn get(&mut self, client: ClientID) -> Result<Option<&Account>, AccountStoreError> {
+        if !self.load(client)? {
+            return Ok(None);
+        }
+
+        Ok(self.cache.get(&client).map(|cached| &cached.account))
+    }
+
+    fn get_mut(&mut self, client: ClientID) -> Result<Option<&mut Account>, AccountStoreError> {
+        if !self.load(client)? {
+            return Ok(None);
+        }
+
+        Ok(self
+            .cache
+            .get_mut(&client)
+            .map(|cached| &mut cached.account))
+    }
+
+    fn mark_changed(&mut self, client: ClientID) {
+        if let Some(cached) = self.cache.peek_mut(&client) {
+            cached.dirty = true;
+        }
+    }
+
+    fn upsert(&mut self, account: Account) -> Result<(), AccountStoreError> {
+        self.cache(account, true)
+    }
+
+    fn iter(&self) -> AccountIter<'_> {
+        let cached = self
+            .cache
+            .iter()
+            .map(|(_, cached)| Ok(Cow::Borrowed(&cached.account)));
+        let stored = self
+            .locations
+            .iter()
+            .filter(|(client, _)| !self.cache.contains(*client))
+            .map(|(_, &location)| self.read(location).map(Cow::Owned));
+
+        Box::new(cached.chain(stored))
+    }
+
+    fn flush(&mut self) -> Result<(), AccountStoreError> {
+        let dirty: Vec<ClientID> = self
+            .cache
+            .iter()
+            .filter(|(_, cached)| cached.dirty)
+            .map(|(&client, _)| client)
+            .collect();
+        for client in dirty {
+            if let Some(cached) = self.cache.peek(&client) {
+                let account = cached.account.clone();
+                self.write(&account)?;
+            }
+            if let Some(cached) = self.cache.peek_mut(&client) {
+                cached.dirty = false;
+            }
+        }
+        self.file.sync_data()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::currency::Currency;
+    use crate::funds::Funds;
+    use rust_decimal_macros::dec;
+
+    fn store_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("txk-{}-{}.accounts", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn capacity(capacity: usize) -> NonZeroUsize {
+        NonZeroUsize::new(capacity).expect("Capacity to be non zero")
+    }
+
+    #[test]
+    fn test_eviction_writes_back() {
+        let path = store_path("eviction");
+        let mut store = DiskAccountStore::create(&path, capacity(1)).expect("Create to succeed");
+        store.upsert(Account::new(1)).expect("Upsert to succeed");
+        store
+            .get_mut(1)
+            .expect("Get to succeed")
+            .expect("Account to exist")
+            .deposit(1, &Currency::default(), Funds::new(dec!(1.5)))
+            .expect("Deposit to succeed");
+        store.mark_changed(1);
+        // Evicts account 1
+        store.upsert(Account::new(2)).expect("Upsert to succeed");
+
+        let account = store
+            .get(1)
+            .expect("Get to succeed")
+            .expect("Account to exist");
+        assert_eq!(
+            account.balance(&Currency::default()).available(),
+            Funds::new(dec!(1.5))
+        );
+        assert!(store.get(3).expect("Get to succeed").is_none());
+
+        let mut clients: Vec<ClientID> = store
+            .iter()
+            .map(|account| account.expect("Read to succeed").client_id())
+            .collect();
+        clients.sort_unstable();
+        assert_eq!(clients, vec![1, 2]);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_reopen() {
+        let path = store_path("reopen");
+        let mut store = DiskAccountStore::create(&path, capacity(2)).expect("Create to succeed");
+        for client in 1..=3 {
+            store
+                .upsert(Account::new(client))
+                .expect("Upsert to succeed");
+        }
+        store
+            .get_mut(3)
+            .expect("Get to succeed")
+            .expect("Account to exist")
+            .deposit(1, &Currency::default(), Funds::new(dec!(2.0)))
+            .expect("Deposit to succeed");
+        store.mark_changed(3);
+        store.flush().expect("Flush to succeed");
+        drop(store);
+
+        // Simulate a crash halfway through writing a record
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .expect("Store to exist");
+        file.write_all(br#"{"client":4,"#)
+            .expect("Write to succeed");
+        drop(file);
+
+        let mut store = DiskAccountStore::open(&path, capacity(2)).expect("Open to succeed");
+        assert_eq!(store.iter().count(), 3);
+        let account = store
+            .get(3)
+            .expect("Get to succeed")
+            .expect("Account to exist");
+        assert_eq!(
+            account.balance(&Currency::default()).available(),
+            Funds::new(dec!(2.0))
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_reads_not_written_back() {
+        let path = store_path("reads");
+        let mut store = DiskAccountStore::create(&path, capacity(1)).expect("Create to succeed");
+        store.upsert(Account::new(1)).expect("Upsert to succeed");
+        store.upsert(Account::new(2)).expect("Upsert to succeed");
+        store.flush().expect("Flush to succeed");
+        let before = std::fs::metadata(&path).expect("Store to exist").len();
+
+        // Alternating between the accounts evicts them on every lookup
+        for client in [1, 2, 1, 2] {
+            assert!(store.get(client).expect("Get to succeed").is_some());
+        }
+        store.flush().expect("Flush to succeed");
+        assert_eq!(
+            std::fs::metadata(&path).expect("Store to exist").len(),
+            before
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_compact() {
+        let path = store_path("compact");
+        let mut store = DiskAccountStore::create(&path, capacity(1)).expect("Create to succeed");
+        for _ in 0..3 {
+            store.upsert(Account::new(1)).expect("Upsert to succeed");
+            store.upsert(Account::new(2)).expect("Upsert to succeed");
+        }
+        store.flush().expect("Flush to succeed");
+        let before = std::fs::metadata(&path).expect("Store to exist").len();
+
+        store.compact().expect("Compact to succeed");
+        assert!(std::fs::metadata(&path).expect("Store to exist").len() < before);
+        assert_eq!(store.iter().count(), 2);
+        assert!(store.get(1).expect("Get to succeed").is_some());
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,86 @@
+use serde::Deserialize;
+use serde::Serialize;
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Debug, PartialEq, Eq, Error)]
+#[error("Unknown dispute expiry action {0:?}, expected one of resolve or chargeback")]
+pub struct UnknownExpiryAction(String);
+
+/// How long a dispute can stay open, counted from when the transaction went into dispute
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum DisputeDuration {
+    /// Number of transactions processed for the account
+    Transactions(u64),
+    /// Time between transaction timestamps. Disputes opened without a known time never expire
+    Seconds(u64),
+}
+
+/// How disputes that have been open for too long are settled
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExpiryAction {
+    /// In the client's favour, making the held funds available again
+    #[default]
+    Resolve,
+    Chargeback,
+}
+
+/// Settles disputes automatically once they've been open for `max_duration`
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct DisputeExpiry {
+    max_duration: DisputeDuration,
+    action: ExpiryAction,
+}
+
+impl DisputeExpiry {
+    pub fn new(max_duration: DisputeDuration, action: ExpiryAction) -> Self {
+        Self {
+            max_duration,
+            action,
+        }
+    }
+
+    pub fn max_duration(&self) -> DisputeDuration {
+        self.max_duration
+    }
+
+    pub fn action(&self) -> ExpiryAction {
+        self.action
+    }
+}
+
+impl FromStr for ExpiryAction {
+    type Err = UnknownExpiryAction;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "resolve" => Ok(ExpiryAction::Resolve),
+            "chargeback" => Ok(ExpiryAction::Chargeback),
+            _ => Err(UnknownExpiryAction(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for ExpiryAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ExpiryAction::Resolve => "resolve",
+            ExpiryAction::Chargeback => "chargeback",
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        for action in [ExpiryAction::Resolve, ExpiryAction::Chargeback] {
+            assert_eq!(action.to_string().parse(), Ok(action));
+        }
+        assert!("void".parse::<ExpiryAction>().is_err());
+    }
+}
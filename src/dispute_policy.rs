@@ -0,0 +1,161 @@
+use crate::balance::BalanceDiff;
+use crate::funds::Funds;
+use std::fmt::Debug;
+
+/// Kinds of transactions that a `DisputePolicy` may allow to be disputed
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum DisputableKind {
+    Deposit,
+    Withdrawal,
+}
+
+/// Steps in the lifecycle of a dispute
+///
+/// After a chargeback, the other party can re-present the transaction. The charged back funds are
+/// held again until the chargeback is either reversed or upheld, possibly after pre-arbitration
+/// (which doesn't change the balance)
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum DisputeStep {
+    Dispute,
+    Resolve,
+    Chargeback,
+    Represent,
+    ReverseChargeback,
+    UpholdChargeback,
+}
+
+/// Decides which transactions can be disputed and how each step of a dispute affects the balance
+///
+/// The state machine itself (undisputed -> in dispute -> resolved / charged back) is enforced by
+/// `Account`, so a policy only has to describe the balance effects of each step. Whatever the
+/// policy, a transaction can only go through the dispute lifecycle once.
+pub trait DisputePolicy: Debug + Send + Sync {
+    fn is_disputable(&self, kind: DisputableKind) -> bool;
+
+    /// Balance change for moving a disputed transaction of `amount` through `step`
+    fn balance_diff(&self, kind: DisputableKind, step: DisputeStep, amount: Funds) -> BalanceDiff;
+
+    /// Whether charging back a transaction of this kind should freeze the account
+    fn freezes_on_chargeback(&self, _kind: DisputableKind) -> bool {
+        true
+    }
+}
+
+/// Balance effects of disputing a deposit
+///
+/// The deposited funds are held while the dispute is open, then either made available again
+/// (resolve) or removed from the account altogether (chargeback). Re-presenting a chargeback
+/// credits them back to `held`, and they become available if the chargeback is reversed
+fn deposit_diff(step: DisputeStep, amount: Funds) -> BalanceDiff {
+    match step {
+        DisputeStep::Dispute => BalanceDiff::new().with_available(-amount).with_held(amount),
+        DisputeStep::Resolve | DisputeStep::ReverseChargeback => {
+            BalanceDiff::new().with_available(amount).with_held(-amount)
+        }
+        DisputeStep::Chargeback | DisputeStep::UpholdChargeback => {
+            BalanceDiff::new().with_held(-amount)
+        }
+        DisputeStep::Represent => BalanceDiff::new().with_held(amount),
+    }
+}
+
+/// Only deposits can be disputed
+///
+/// Disputing withdrawals naively (i.e. mirroring the deposit effects) would increase an account's
+/// available funds after they might have already been withdrawn and allow for double spend
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DepositsOnly;
+
+impl DisputePolicy for DepositsOnly {
+    fn is_disputable(&self, kind: DisputableKind) -> bool {
+        kind == DisputableKind::Deposit
+    }
+
+    fn balance_diff(&self, kind: DisputableKind, step: DisputeStep, amount: Funds) -> BalanceDiff {
+        debug_assert_eq!(kind, DisputableKind::Deposit);
+        deposit_diff(step, amount)
+    }
+}
+
+/// Allows disputing both deposits and withdrawals
+///
+/// A withdrawal dispute has the reverse effect of a deposit dispute: the client is claiming
+/// the funds back rather than giving them up. To protect against double spend the claimed funds
+/// are only credited to `held` while the dispute is open, so they can't be withdrawn again.
+/// They only become available if the withdrawal is charged back, and are dropped if the
+/// dispute is resolved in favour of the original withdrawal. Re-presenting a withdrawal's
+/// chargeback holds the funds the client got back until the chargeback is settled again.
+///
+/// Charging back a withdrawal doesn't freeze the account since it's the client who was wronged
+#[derive(Debug, Default, Clone, Copy)]
+pub struct WithdrawalsDisputable;
+
+impl DisputePolicy for WithdrawalsDisputable {
+    fn is_disputable(&self, _kind: DisputableKind) -> bool {
+        true
+    }
+
+    fn balance_diff(&self, kind: DisputableKind, step: DisputeStep, amount: Funds) -> BalanceDiff {
+        match (kind, step) {
+            (DisputableKind::Deposit, _) => deposit_diff(step, amount),
+            (DisputableKind::Withdrawal, DisputeStep::Dispute) => {
+                BalanceDiff::new().with_held(amount)
+            }
+            (DisputableKind::Withdrawal, DisputeStep::Resolve | DisputeStep::ReverseChargeback) => {
+                BalanceDiff::new().with_held(-amount)
+            }
+            (
+                DisputableKind::Withdrawal,
+                DisputeStep::Chargeback | DisputeStep::UpholdChargeback,
+            ) => BalanceDiff::new().with_available(amount).with_held(-amount),
+            (DisputableKind::Withdrawal, DisputeStep::Represent) => {
+                BalanceDiff::new().with_available(-amount).with_held(amount)
+            }
+        }
+    }
+
+    fn freezes_on_chargeback(&self, kind: DisputableKind) -> bool {
+        kind == DisputableKind::Deposit
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_deposits_only() {
+        assert!(DepositsOnly.is_disputable(DisputableKind::Deposit));
+        assert!(!DepositsOnly.is_disputable(DisputableKind::Withdrawal));
+        assert_eq!(
+            DepositsOnly.balance_diff(
+                DisputableKind::Deposit,
+                DisputeStep::Dispute,
+                Funds::new(dec!(1.5))
+            ),
+            BalanceDiff::new()
+                .with_available(Funds::new(dec!(-1.5)))
+                .with_held(Funds::new(dec!(1.5))),
+        );
+    }
+
+    #[test]
+    fn test_withdrawal_dispute_never_increases_available() {
+        let amount = Funds::new(dec!(1.5));
+        for step in [
+            DisputeStep::Dispute,
+            DisputeStep::Resolve,
+            DisputeStep::ReverseChargeback,
+        ] {
+            assert_eq!(
+                WithdrawalsDisputable
+                    .balance_diff(DisputableKind::Withdrawal, step, amount)
+                    .available(),
+                None,
+            );
+        }
+        assert!(!WithdrawalsDisputable.freezes_on_chargeback(DisputableKind::Withdrawal));
+        assert!(WithdrawalsDisputable.freezes_on_chargeback(DisputableKind::Deposit));
+    }
+}
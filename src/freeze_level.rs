@@ -0,0 +1,100 @@
+use serde::Deserialize;
+use serde::Serialize;
+
+/// Operations on an account that can be blocked by its `FreezeLevel`
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum AccountOperation {
+    Deposit,
+    Withdraw,
+    Dispute,
+    Resolve,
+    Chargeback,
+    Authorize,
+    Capture,
+    Void,
+    Refund,
+}
+
+/// How restricted an account is
+///
+/// Accounts start out `Active`. Chargebacks and administrative `lock`s move them to a more
+/// restrictive level, and `unlock` brings them back to `Active`, except for `Closed` accounts
+/// which can't be reopened.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FreezeLevel {
+    #[default]
+    Active,
+    WithdrawalsBlocked,
+    DepositsBlocked,
+    /// Only disputes and holds that are already open can be settled
+    FullyLocked,
+    Closed,
+}
+
+impl FreezeLevel {
+    pub fn allows(&self, operation: AccountOperation) -> bool {
+        use AccountOperation::*;
+        match self {
+            FreezeLevel::Active => true,
+            // Authorizations reserve funds to be withdrawn and refunds pay them out
+            FreezeLevel::WithdrawalsBlocked => !matches!(operation, Withdraw | Authorize | Refund),
+            FreezeLevel::DepositsBlocked => operation != Deposit,
+            FreezeLevel::FullyLocked => matches!(operation, Resolve | Chargeback | Capture | Void),
+            FreezeLevel::Closed => false,
+        }
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        *self != FreezeLevel::Active
+    }
+
+    /// Combines two levels into one that keeps the restrictions of both
+    pub fn escalate(self, other: Self) -> Self {
+        match (self, other) {
+            (FreezeLevel::Active, level) | (level, FreezeLevel::Active) => level,
+            (FreezeLevel::Closed, _) | (_, FreezeLevel::Closed) => FreezeLevel::Closed,
+            (a, b) if a == b => a,
+            _ => FreezeLevel::FullyLocked,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_allows() {
+        assert!(FreezeLevel::WithdrawalsBlocked.allows(AccountOperation::Deposit));
+        assert!(!FreezeLevel::WithdrawalsBlocked.allows(AccountOperation::Withdraw));
+        assert!(!FreezeLevel::DepositsBlocked.allows(AccountOperation::Deposit));
+        assert!(!FreezeLevel::FullyLocked.allows(AccountOperation::Dispute));
+        assert!(FreezeLevel::FullyLocked.allows(AccountOperation::Chargeback));
+        assert!(!FreezeLevel::WithdrawalsBlocked.allows(AccountOperation::Authorize));
+        assert!(FreezeLevel::FullyLocked.allows(AccountOperation::Capture));
+        assert!(!FreezeLevel::WithdrawalsBlocked.allows(AccountOperation::Refund));
+        assert!(FreezeLevel::DepositsBlocked.allows(AccountOperation::Refund));
+        assert!(!FreezeLevel::Closed.allows(AccountOperation::Resolve));
+    }
+
+    #[test]
+    fn test_escalate() {
+        assert_eq!(
+            FreezeLevel::Active.escalate(FreezeLevel::WithdrawalsBlocked),
+            FreezeLevel::WithdrawalsBlocked,
+        );
+        assert_eq!(
+            FreezeLevel::WithdrawalsBlocked.escalate(FreezeLevel::DepositsBlocked),
+            FreezeLevel::FullyLocked,
+        );
+        assert_eq!(
+            FreezeLevel::FullyLocked.escalate(FreezeLevel::WithdrawalsBlocked),
+            FreezeLevel::FullyLocked,
+        );
+        assert_eq!(
+            FreezeLevel::Closed.escalate(FreezeLevel::WithdrawalsBlocked),
+            FreezeLevel::Closed,
+        );
+    }
+}
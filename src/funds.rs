@@ -1,88 +1,90 @@
-use std::ops::Neg;
-
-use rust_decimal::Decimal;
-use serde::Deserialize;
 use thiserror::Error;
 
 #[derive(Debug, PartialEq, Eq, Error)]
 pub enum FundsOpError {
     #[error("Overflow")]
     Overflow,
+    #[error("Amount has {scale} decimal places but at most {max_scale} are allowed")]
+    ExcessPrecision { scale: u32, max_scale: u32 },
+    #[error("Invalid amount {0:?}")]
+    Invalid(String),
 }
 
-/// Wrapper type for overflow safe operations to represent funds
-///
-/// This type represents a trade-off between API ergonomics and safety.
-/// by making the innter type private and not implementing traits like
-/// `Deref`, `Add`, `Sub`, etc. we make dealing with funds safer (against overflows)
-/// but harder to use. Normally overflows would either cause a `panic` or, arguably worse,
-/// happen silently. This type makes it so that arithmetic operations are fallible so we handle
-/// overflows explicitly.
-///
-/// Arguably overflows are rare enough that this it not worth it,
-/// but this at least serves as an illustration of how to use the type system
-/// to implement these tradeoffs.
-#[derive(Debug, PartialEq, Eq, PartialOrd, Clone, Copy, Deserialize)]
-pub struct Funds(Decimal);
-
-impl Neg for Funds {
-    type Output = Self;
-    fn neg(self) -> Self::Output {
-        Self(self.0.neg())
-    }
-}
+#[cfg(not(feature = "fixed-point"))]
+mod decimal;
+#[cfg(feature = "fixed-point")]
+mod fixed_point;
 
-impl From<Funds> for Decimal {
-    fn from(funds: Funds) -> Self {
-        funds.0
-    }
-}
+#[cfg(not(feature = "fixed-point"))]
+pub use decimal::Funds;
+#[cfg(feature = "fixed-point")]
+pub use fixed_point::Funds;
+#[cfg(feature = "fixed-point")]
+pub use fixed_point::SCALE;
 
 impl Funds {
-    pub fn new<T: Into<Decimal>>(n: T) -> Self {
-        Self(n.into())
-    }
-
-    pub fn add<T: Into<Decimal>>(&self, n: T) -> Result<Self, FundsOpError> {
-        Ok(Self(
-            self.0.checked_add(n.into()).ok_or(FundsOpError::Overflow)?,
-        ))
-    }
-
-    pub fn sub<T: Into<Decimal>>(&self, n: T) -> Result<Self, FundsOpError> {
-        Ok(Self(
-            self.0.checked_sub(n.into()).ok_or(FundsOpError::Overflow)?,
-        ))
-    }
-
-    pub fn is_negative(&self) -> bool {
-        self.0.is_sign_negative()
+    /// Fails if the amount has more than `max_scale` decimal places
+    ///
+    /// Amounts are checked when they're received so that balances never carry digits that would
+    /// be dropped when they're output
+    pub fn check_scale(self, max_scale: u32) -> Result<Self, FundsOpError> {
+        match self.scale() {
+            scale if scale > max_scale => Err(FundsOpError::ExcessPrecision { scale, max_scale }),
+            _ => Ok(self),
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::rounding::RoundingMode;
+    use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
 
     #[test]
     fn test_pos_overflow() {
-        assert_eq!(
-            Funds::new(Decimal::MAX).add(42),
-            Err(FundsOpError::Overflow),
-        );
+        assert_eq!(Funds::MAX.add(Funds::new(42)), Err(FundsOpError::Overflow),);
     }
 
     #[test]
     fn test_neg_overflow() {
+        assert_eq!(Funds::MIN.sub(Funds::new(42)), Err(FundsOpError::Overflow),);
+    }
+
+    #[test]
+    fn test_neg() {
+        assert_eq!(-Funds::MIN, Funds::MAX);
+        assert_eq!(-Funds::MAX, Funds::MIN);
+    }
+
+    #[test]
+    fn test_check_scale() {
         assert_eq!(
-            Funds::new(Decimal::MIN).sub(42),
-            Err(FundsOpError::Overflow),
+            Funds::new(dec!(1.00001)).check_scale(4),
+            Err(FundsOpError::ExcessPrecision {
+                scale: 5,
+                max_scale: 4
+            }),
+        );
+        // Trailing zeros don't count
+        assert_eq!(
+            Funds::new(dec!(1.50000)).check_scale(4),
+            Ok(Funds::new(dec!(1.5)))
         );
     }
 
     #[test]
-    fn test_neg() {
-        assert_eq!(-Funds::new(Decimal::MIN), Funds::new(-Decimal::MIN));
-        assert_eq!(-Funds::new(Decimal::MAX), Funds::new(-Decimal::MAX));
+    fn test_round() {
+        let round = |n: Decimal, mode| Funds::new(n).round(2, mode).map(Decimal::from);
+        assert_eq!(round(dec!(1.125), RoundingMode::HalfEven), Ok(dec!(1.12)));
+        assert_eq!(round(dec!(1.125), RoundingMode::HalfUp), Ok(dec!(1.13)));
+        assert_eq!(round(dec!(-1.125), RoundingMode::HalfUp), Ok(dec!(-1.13)));
+        assert_eq!(round(dec!(-1.121), RoundingMode::Down), Ok(dec!(-1.13)));
+        assert_eq!(round(dec!(-1.129), RoundingMode::Up), Ok(dec!(-1.12)));
+        assert_eq!(round(dec!(-1.129), RoundingMode::Truncate), Ok(dec!(-1.12)));
+        assert_eq!(round(dec!(1.129), RoundingMode::Truncate), Ok(dec!(1.12)));
+        // Nothing to round
+        assert_eq!(round(dec!(1.5), RoundingMode::Up), Ok(dec!(1.5)));
     }
 }
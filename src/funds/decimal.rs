@@ -0,0 +1,94 @@
+use super::FundsOpError;
+use crate::rounding::RoundingMode;
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use serde::Serialize;
+use std::fmt;
+use std::ops::Neg;
+use std::str::FromStr;
+
+/// Wrapper type for overflow safe operations to represent funds
+///
+/// This type represents a trade-off between API ergonomics and safety.
+/// by making the innter type private and not implementing traits like
+/// `Deref`, `Add`, `Sub`, etc. we make dealing with funds safer (against overflows)
+/// but harder to use. Normally overflows would either cause a `panic` or, arguably worse,
+/// happen silently. This type makes it so that arithmetic operations are fallible so we handle
+/// overflows explicitly.
+///
+/// Arguably overflows are rare enough that this it not worth it,
+/// but this at least serves as an illustration of how to use the type system
+/// to implement these tradeoffs.
+#[derive(Debug, Default, PartialEq, Eq, PartialOrd, Clone, Copy, Serialize, Deserialize)]
+pub struct Funds(Decimal);
+
+impl Neg for Funds {
+    type Output = Self;
+    fn neg(self) -> Self::Output {
+        Self(self.0.neg())
+    }
+}
+
+impl From<Funds> for Decimal {
+    fn from(funds: Funds) -> Self {
+        funds.0
+    }
+}
+
+impl TryFrom<Decimal> for Funds {
+    type Error = FundsOpError;
+
+    fn try_from(n: Decimal) -> Result<Self, Self::Error> {
+        Ok(Self(n))
+    }
+}
+
+impl FromStr for Funds {
+    type Err = FundsOpError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Decimal::from_str(s)
+            .map(Self)
+            .map_err(|_| FundsOpError::Invalid(s.to_string()))
+    }
+}
+
+impl fmt::Display for Funds {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Funds {
+    pub const ZERO: Self = Self(Decimal::ZERO);
+    pub const MAX: Self = Self(Decimal::MAX);
+    pub const MIN: Self = Self(Decimal::MIN);
+
+    pub fn new<T: Into<Decimal>>(n: T) -> Self {
+        Self(n.into())
+    }
+
+    pub fn add(&self, n: Funds) -> Result<Self, FundsOpError> {
+        Ok(Self(self.0.checked_add(n.0).ok_or(FundsOpError::Overflow)?))
+    }
+
+    pub fn sub(&self, n: Funds) -> Result<Self, FundsOpError> {
+        Ok(Self(self.0.checked_sub(n.0).ok_or(FundsOpError::Overflow)?))
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.0.is_sign_negative()
+    }
+
+    /// Number of decimal places, not counting trailing zeros
+    pub fn scale(&self) -> u32 {
+        self.0.normalize().scale()
+    }
+
+    /// Rounds to `scale` decimal places using `mode`
+    ///
+    /// Fails if the rounded amount can't be represented
+    pub fn round(&self, scale: u32, mode: RoundingMode) -> Result<Self, FundsOpError> {
+        Ok(Self(self.0.round_dp_with_strategy(scale, mode.into())))
+    }
+}
@@ -0,0 +1,305 @@
+use super::FundsOpError;
+use crate::rounding::RoundingMode;
+use rust_decimal::Decimal;
+use serde::de;
+use serde::Deserialize;
+use serde::Deserializer;
+use serde::Serialize;
+use serde::Serializer;
+use std::fmt;
+use std::ops::Neg;
+use std::str::FromStr;
+
+/// Decimal places every amount is stored with
+pub const SCALE: u32 = 18;
+
+const ONE: i128 = 10i128.pow(SCALE);
+
+/// Largest exponent accepted when parsing, either way. Only zero can be represented beyond it
+const MAX_EXPONENT: i64 = 1_000;
+
+/// Fixed-point version of `Funds`, storing amounts as an integer number of `10^-SCALE` units
+///
+/// Integer arithmetic is much cheaper than `Decimal`'s, at the cost of range: amounts are limited
+/// to about ±1.7 * 10^20 and can't have more than `SCALE` decimal places. The API is the same as
+/// the `Decimal` version so the two can be swapped with the `fixed-point` feature.
+///
+/// `i128::MIN` is never used so that negating an amount can't overflow
+#[derive(Debug, Default, PartialEq, Eq, PartialOrd, Clone, Copy)]
+pub struct Funds(i128);
+
+impl Neg for Funds {
+    type Output = Self;
+    fn neg(self) -> Self::Output {
+        Self(-self.0)
+    }
+}
+
+/// Exact unless the amount has more significant digits than `Decimal` can hold, in which case
+/// the least significant ones are truncated
+impl From<Funds> for Decimal {
+    fn from(funds: Funds) -> Self {
+        let (mut units, mut scale) = (funds.0, SCALE);
+        while scale > 0 && units % 10 == 0 {
+            units /= 10;
+            scale -= 1;
+        }
+        loop {
+            // Whole units always fit, so this ends by the time the scale reaches zero
+            match Decimal::try_from_i128_with_scale(units, scale) {
+                Ok(n) => return n,
+                Err(_) => {
+                    units /= 10;
+                    scale -= 1;
+                }
+            }
+        }
+    }
+}
+
+impl TryFrom<Decimal> for Funds {
+    type Error = FundsOpError;
+
+    fn try_from(n: Decimal) -> Result<Self, Self::Error> {
+        let n = n.normalize();
+        Self::from_parts(n.mantissa(), n.scale() as i64)
+    }
+}
+
+/// Parses amounts exactly, rather than going through a float or `Decimal`
+///
+/// Accepts the same formats as `Decimal`: an optional sign, digits with an optional decimal point
+/// and `_` separators, and an optional exponent of at most `MAX_EXPONENT`
+impl FromStr for Funds {
+    type Err = FundsOpError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || FundsOpError::Invalid(s.to_string());
+        let (number, exponent) = match s.find(['e', 'E']) {
+            Some(i) => (&s[..i], s[i + 1..].parse::<i64>().map_err(|_| invalid())?),
+            None => (s, 0),
+        };
+        if !(-MAX_EXPONENT..=MAX_EXPONENT).contains(&exponent) {
+            return Err(invalid());
+        }
+        let (negative, number) = match number.strip_prefix('-') {
+            Some(number) => (true, number),
+            None => (false, number.strip_prefix('+').unwrap_or(number)),
+        };
+
+        let (mut mantissa, mut scale, mut digits, mut point) = (0i128, 0i64, 0, false);
+        for c in number.chars() {
+            match c {
+                '0'..='9' => {
+                    mantissa = mantissa
+                        .checked_mul(10)
+                        .and_then(|m| m.checked_add(c as i128 - '0' as i128))
+                        .ok_or(FundsOpError::Overflow)?;
+                    digits += 1;
+                    if point {
+                        scale += 1;
+                    }
+                }
+                '.' if !point => point = true,
+                '_' => {}
+                _ => return Err(invalid()),
+            }
+        }
+        if digits == 0 {
+            return Err(invalid());
+        }
+
+        let mantissa = if negative { -mantissa } else { mantissa };
+        let scale = scale.checked_sub(exponent).ok_or(FundsOpError::Overflow)?;
+        Self::from_parts(mantissa, scale)
+    }
+}
+
+impl fmt::Display for Funds {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let (whole, fraction) = (
+            self.0.unsigned_abs() / ONE as u128,
+            self.0.unsigned_abs() % ONE as u128,
+        );
+        if fraction == 0 {
+            return write!(f, "{}{}", sign, whole);
+        }
+        let fraction = format!("{:0width$}", fraction, width = SCALE as usize);
+        write!(f, "{}{}.{}", sign, whole, fraction.trim_end_matches('0'))
+    }
+}
+
+// Serialized as strings like `Decimal` so snapshots and logs work with either representation
+impl Serialize for Funds {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Funds {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct FundsVisitor;
+
+        impl<'de> de::Visitor<'de> for FundsVisitor {
+            type Value = Funds;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a decimal amount")
+            }
+
+            fn visit_str<E: de::Error>(self, s: &str) -> Result<Self::Value, E> {
+                s.parse().map_err(E::custom)
+            }
+        }
+
+        // Going through a string keeps the csv crate from parsing amounts as floats
+        deserializer.deserialize_str(FundsVisitor)
+    }
+}
+
+impl Funds {
+    pub const ZERO: Self = Self(0);
+    pub const MAX: Self = Self(i128::MAX);
+    pub const MIN: Self = Self(-i128::MAX);
+
+    /// Panics if `n` can't be represented, use `Funds::try_from` for amounts that might not fit
+    pub fn new<T: Into<Decimal>>(n: T) -> Self {
+        Self::try_from(n.into()).expect("Amount to fit in fixed-point funds")
+    }
+
+    pub fn add(&self, n: Funds) -> Result<Self, FundsOpError> {
+        Self::checked(self.0.checked_add(n.0))
+    }
+
+    pub fn sub(&self, n: Funds) -> Result<Self, FundsOpError> {
+        Self::checked(self.0.checked_sub(n.0))
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.0 < 0
+    }
+
+    /// Number of decimal places, not counting trailing zeros
+    pub fn scale(&self) -> u32 {
+        let mut scale = SCALE;
+        let mut units = self.0;
+        while scale > 0 && units % 10 == 0 {
+            units /= 10;
+            scale -= 1;
+        }
+        scale
+    }
+
+    /// Rounds to `scale` decimal places using `mode`
+    ///
+    /// Fails if the rounded amount can't be represented
+    pub fn round(&self, scale: u32, mode: RoundingMode) -> Result<Self, FundsOpError> {
+        if scale >= SCALE {
+            return Ok(*self);
+        }
+        let factor = 10i128.pow(SCALE - scale);
+        // Both truncate towards zero
+        let (quotient, remainder) = (self.0 / factor, self.0 % factor);
+        let away = match mode {
+            RoundingMode::Truncate => false,
+            RoundingMode::Down => remainder < 0,
+            RoundingMode::Up => remainder > 0,
+            RoundingMode::HalfUp => remainder.abs() * 2 >= factor,
+            RoundingMode::HalfEven => match (remainder.abs() * 2).cmp(&factor) {
+                std::cmp::Ordering::Less => false,
+                std::cmp::Ordering::Equal => quotient % 2 != 0,
+                std::cmp::Ordering::Greater => true,
+            },
+        };
+        let quotient = if away {
+            quotient + remainder.signum()
+        } else {
+            quotient
+        };
+        Self::checked(quotient.checked_mul(factor))
+    }
+
+    /// Converts `mantissa * 10^-scale` to units, failing if it has more than `SCALE` places
+    fn from_parts(mut mantissa: i128, mut scale: i64) -> Result<Self, FundsOpError> {
+        while scale > SCALE as i64 && mantissa % 10 == 0 && mantissa != 0 {
+            mantissa /= 10;
+            scale -= 1;
+        }
+        if mantissa == 0 {
+            return Ok(Self::ZERO);
+        }
+        if scale > SCALE as i64 {
+            return Err(FundsOpError::ExcessPrecision {
+                scale: scale as u32,
+                max_scale: SCALE,
+            });
+        }
+        let shift = u32::try_from(SCALE as i64 - scale).map_err(|_| FundsOpError::Overflow)?;
+        Self::checked(
+            10i128
+                .checked_pow(shift)
+                .and_then(|factor| mantissa.checked_mul(factor)),
+        )
+    }
+
+    fn checked(units: Option<i128>) -> Result<Self, FundsOpError> {
+        match units {
+            Some(units) if units != i128::MIN => Ok(Self(units)),
+            _ => Err(FundsOpError::Overflow),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_parse() {
+        let parse = |s: &str| s.parse::<Funds>().map(Decimal::from);
+        assert_eq!(parse("1.5"), Ok(dec!(1.5)));
+        assert_eq!(parse("-0.0001"), Ok(dec!(-0.0001)));
+        assert_eq!(parse("+3"), Ok(dec!(3)));
+        assert_eq!(parse(".5"), Ok(dec!(0.5)));
+        assert_eq!(parse("1_000.50"), Ok(dec!(1000.5)));
+        assert_eq!(parse("1.5e3"), Ok(dec!(1500)));
+        // Trailing zeros past the scale aren't lost precision
+        assert_eq!(parse("1.0000000000000000000000"), Ok(dec!(1)));
+        assert_eq!(
+            parse("0.0000000000000000001"),
+            Err(FundsOpError::ExcessPrecision {
+                scale: 19,
+                max_scale: SCALE
+            })
+        );
+        assert_eq!(parse("1e30"), Err(FundsOpError::Overflow));
+        assert_eq!(
+            parse("1.2.3"),
+            Err(FundsOpError::Invalid("1.2.3".to_string()))
+        );
+        assert_eq!(parse("-"), Err(FundsOpError::Invalid("-".to_string())));
+        // Exponents that can't give a representable amount are rejected without overflowing
+        for s in ["1e-9223372036854775808", "1e9223372036854775807", "0e1001"] {
+            assert_eq!(parse(s), Err(FundsOpError::Invalid(s.to_string())));
+        }
+        assert_eq!(parse("0e1000"), Ok(dec!(0)));
+    }
+
+    #[test]
+    fn test_exact() {
+        // Too many digits for a float, which is how the csv crate would otherwise parse it
+        let amount: Funds = "12345678901234.123456789012345678"
+            .parse()
+            .expect("Parsing to succeed");
+        assert_eq!(amount.to_string(), "12345678901234.123456789012345678");
+        assert_eq!(
+            serde_json::from_str::<Funds>(
+                &serde_json::to_string(&amount).expect("Serialization to succeed")
+            )
+            .expect("Deserialization to succeed"),
+            amount
+        );
+    }
+}
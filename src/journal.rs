@@ -0,0 +1,479 @@
+use crate::balance::BalanceDiff;
+use crate::currency::Currency;
+use crate::dispute_expiry::ExpiryAction;
+use crate::freeze_level::FreezeLevel;
+use crate::funds::Funds;
+use crate::transaction::ClientID;
+use crate::transaction::TransactionID;
+use csv::Writer;
+use serde::Serialize;
+use std::io;
+use std::io::Write;
+
+/// Something that happened to an account as the result of a successful operation
+///
+/// Events that change the balance carry the exact `BalanceDiff` that was applied to the balance of
+/// their currency, so replaying them from an empty account reproduces its balances
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum AccountEvent {
+    Deposited {
+        transaction: TransactionID,
+        currency: Currency,
+        diff: BalanceDiff,
+    },
+    Withdrew {
+        transaction: TransactionID,
+        currency: Currency,
+        diff: BalanceDiff,
+    },
+    DisputeOpened {
+        transaction: TransactionID,
+        currency: Currency,
+        diff: BalanceDiff,
+    },
+    DisputeResolved {
+        transaction: TransactionID,
+        currency: Currency,
+        diff: BalanceDiff,
+    },
+    ChargedBack {
+        transaction: TransactionID,
+        currency: Currency,
+        diff: BalanceDiff,
+    },
+    /// A dispute was open for longer than allowed and is settled automatically with `action`,
+    /// the settlement itself is recorded by the event that follows
+    DisputeExpired {
+        transaction: TransactionID,
+        currency: Currency,
+        action: ExpiryAction,
+    },
+    /// The charged back funds are claimed back and held until the chargeback is settled again
+    ChargebackRepresented {
+        transaction: TransactionID,
+        currency: Currency,
+        diff: BalanceDiff,
+    },
+    /// The re-presentment was contested, doesn't change the balance
+    PreArbitrationOpened {
+        transaction: TransactionID,
+        currency: Currency,
+    },
+    ChargebackReversed {
+        transaction: TransactionID,
+        currency: Currency,
+        diff: BalanceDiff,
+    },
+    ChargebackUpheld {
+        transaction: TransactionID,
+        currency: Currency,
+        diff: BalanceDiff,
+    },
+    /// The pending funds of a deposit cleared and became available
+    Cleared {
+        transaction: TransactionID,
+        currency: Currency,
+        diff: BalanceDiff,
+    },
+    /// Part or all of a deposit was returned to the client
+    Refunded {
+        transaction: TransactionID,
+        currency: Currency,
+        diff: BalanceDiff,
+    },
+    /// A dispute left the available funds negative and the account was flagged for it (see
+    /// `DeficitPolicy::Flag`), doesn't change the balance beyond the dispute's own event
+    FlaggedInDeficit {
+        transaction: TransactionID,
+        currency: Currency,
+    },
+    /// Funds were put on hold by an authorization
+    Authorized {
+        transaction: TransactionID,
+        currency: Currency,
+        diff: BalanceDiff,
+    },
+    Captured {
+        transaction: TransactionID,
+        currency: Currency,
+        diff: BalanceDiff,
+    },
+    Voided {
+        transaction: TransactionID,
+        currency: Currency,
+        diff: BalanceDiff,
+    },
+    HoldExpired {
+        transaction: TransactionID,
+        currency: Currency,
+        diff: BalanceDiff,
+    },
+    TransferredOut {
+        transaction: TransactionID,
+        to: ClientID,
+        currency: Currency,
+        diff: BalanceDiff,
+    },
+    TransferredIn {
+        transaction: TransactionID,
+        from: ClientID,
+        currency: Currency,
+        diff: BalanceDiff,
+    },
+    /// The account moved to a more restrictive freeze level
+    ///
+    /// `reason` is only set when the freeze was requested by an operator
+    Frozen {
+        level: FreezeLevel,
+        reason: Option<String>,
+    },
+    /// The account became active again, `reason` is only set when requested by an operator
+    Unfrozen { reason: Option<String> },
+}
+
+impl AccountEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AccountEvent::Deposited { .. } => "deposited",
+            AccountEvent::Withdrew { .. } => "withdrew",
+            AccountEvent::DisputeOpened { .. } => "dispute-opened",
+            AccountEvent::DisputeResolved { .. } => "dispute-resolved",
+            AccountEvent::ChargedBack { .. } => "charged-back",
+            AccountEvent::DisputeExpired { .. } => "dispute-expired",
+            AccountEvent::ChargebackRepresented { .. } => "chargeback-represented",
+            AccountEvent::PreArbitrationOpened { .. } => "pre-arbitration-opened",
+            AccountEvent::ChargebackReversed { .. } => "chargeback-reversed",
+            AccountEvent::ChargebackUpheld { .. } => "chargeback-upheld",
+            AccountEvent::Refunded { .. } => "refunded",
+            AccountEvent::Cleared { .. } => "cleared",
+            AccountEvent::FlaggedInDeficit { .. } => "flagged-in-deficit",
+            AccountEvent::Authorized { .. } => "authorized",
+            AccountEvent::Captured { .. } => "captured",
+            AccountEvent::Voided { .. } => "voided",
+            AccountEvent::HoldExpired { .. } => "hold-expired",
+            AccountEvent::TransferredOut { .. } => "transferred-out",
+            AccountEvent::TransferredIn { .. } => "transferred-in",
+            AccountEvent::Frozen { .. } => "frozen",
+            AccountEvent::Unfrozen { .. } => "unfrozen",
+        }
+    }
+
+    pub fn transaction(&self) -> Option<TransactionID> {
+        match self {
+            AccountEvent::Deposited { transaction, .. }
+            | AccountEvent::Withdrew { transaction, .. }
+            | AccountEvent::DisputeOpened { transaction, .. }
+            | AccountEvent::DisputeResolved { transaction, .. }
+            | AccountEvent::ChargedBack { transaction, .. }
+            | AccountEvent::DisputeExpired { transaction, .. }
+            | AccountEvent::ChargebackRepresented { transaction, .. }
+            | AccountEvent::PreArbitrationOpened { transaction, .. }
+            | AccountEvent::ChargebackReversed { transaction, .. }
+            | AccountEvent::ChargebackUpheld { transaction, .. }
+            | AccountEvent::Refunded { transaction, .. }
+            | AccountEvent::Cleared { transaction, .. }
+            | AccountEvent::FlaggedInDeficit { transaction, .. }
+            | AccountEvent::Authorized { transaction, .. }
+            | AccountEvent::Captured { transaction, .. }
+            | AccountEvent::Voided { transaction, .. }
+            | AccountEvent::HoldExpired { transaction, .. }
+            | AccountEvent::TransferredOut { transaction, .. }
+            | AccountEvent::TransferredIn { transaction, .. } => Some(*transaction),
+            AccountEvent::Frozen { .. } | AccountEvent::Unfrozen { .. } => None,
+        }
+    }
+
+    pub fn currency(&self) -> Option<&Currency> {
+        match self {
+            AccountEvent::Deposited { currency, .. }
+            | AccountEvent::Withdrew { currency, .. }
+            | AccountEvent::DisputeOpened { currency, .. }
+            | AccountEvent::DisputeResolved { currency, .. }
+            | AccountEvent::ChargedBack { currency, .. }
+            | AccountEvent::DisputeExpired { currency, .. }
+            | AccountEvent::ChargebackRepresented { currency, .. }
+            | AccountEvent::PreArbitrationOpened { currency, .. }
+            | AccountEvent::ChargebackReversed { currency, .. }
+            | AccountEvent::ChargebackUpheld { currency, .. }
+            | AccountEvent::Refunded { currency, .. }
+            | AccountEvent::Cleared { currency, .. }
+            | AccountEvent::FlaggedInDeficit { currency, .. }
+            | AccountEvent::Authorized { currency, .. }
+            | AccountEvent::Captured { currency, .. }
+            | AccountEvent::Voided { currency, .. }
+            | AccountEvent::HoldExpired { currency, .. }
+            | AccountEvent::TransferredOut { currency, .. }
+            | AccountEvent::TransferredIn { currency, .. } => Some(currency),
+            AccountEvent::Frozen { .. } | AccountEvent::Unfrozen { .. } => None,
+        }
+    }
+
+    /// The other client involved in a transfer
+    pub fn counterparty(&self) -> Option<ClientID> {
+        match self {
+            AccountEvent::TransferredOut { to, .. } => Some(*to),
+            AccountEvent::TransferredIn { from, .. } => Some(*from),
+            _ => None,
+        }
+    }
+
+    pub fn diff(&self) -> Option<BalanceDiff> {
+        match self {
+            AccountEvent::Deposited { diff, .. }
+            | AccountEvent::Withdrew { diff, .. }
+            | AccountEvent::DisputeOpened { diff, .. }
+            | AccountEvent::DisputeResolved { diff, .. }
+            | AccountEvent::ChargedBack { diff, .. }
+            | AccountEvent::ChargebackRepresented { diff, .. }
+            | AccountEvent::ChargebackReversed { diff, .. }
+            | AccountEvent::ChargebackUpheld { diff, .. }
+            | AccountEvent::Refunded { diff, .. }
+            | AccountEvent::Cleared { diff, .. }
+            | AccountEvent::Authorized { diff, .. }
+            | AccountEvent::Captured { diff, .. }
+            | AccountEvent::Voided { diff, .. }
+            | AccountEvent::HoldExpired { diff, .. }
+            | AccountEvent::TransferredOut { diff, .. }
+            | AccountEvent::TransferredIn { diff, .. } => Some(*diff),
+            AccountEvent::DisputeExpired { .. }
+            | AccountEvent::PreArbitrationOpened { .. }
+            | AccountEvent::FlaggedInDeficit { .. }
+            | AccountEvent::Frozen { .. }
+            | AccountEvent::Unfrozen { .. } => None,
+        }
+    }
+}
+
+/// An `AccountEvent` as recorded in a `Journal`
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct JournalEntry {
+    /// Position of the entry in the journal. Keeps increasing after entries have been drained
+    pub sequence: u64,
+    pub client: ClientID,
+    pub event: AccountEvent,
+}
+
+/// Destination for journal entries, e.g. a file or a message queue
+pub trait JournalSink {
+    fn write(&mut self, entry: &JournalEntry) -> io::Result<()>;
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Append-only log of the events applied to accounts
+///
+/// Entries can't be modified or removed other than by consuming them (through `drain` or
+/// `write_to`). Sequence numbers are never reused, so consumers can tell whether they've seen
+/// every entry.
+#[derive(Debug)]
+pub struct Journal {
+    entries: Vec<JournalEntry>,
+    next_sequence: u64,
+    enabled: bool,
+}
+
+impl Journal {
+    pub fn new() -> Self {
+        Self {
+            entries: vec![],
+            next_sequence: 0,
+            enabled: true,
+        }
+    }
+
+    /// Creates a journal that drops every event, for when there's nothing consuming it
+    pub fn disabled() -> Self {
+        Self {
+            enabled: false,
+            ..Self::new()
+        }
+    }
+
+    pub fn append(&mut self, client: ClientID, events: Vec<AccountEvent>) {
+        if !self.enabled {
+            return;
+        }
+
+        for event in events {
+            self.entries.push(JournalEntry {
+                sequence: self.next_sequence,
+                client,
+                event,
+            });
+            self.next_sequence += 1;
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &JournalEntry> {
+        self.entries.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Consumes all the entries recorded so far
+    pub fn drain(&mut self) -> impl Iterator<Item = JournalEntry> + '_ {
+        self.entries.drain(..)
+    }
+
+    /// Consumes all the entries recorded so far by writing them to `sink`
+    ///
+    /// If writing an entry fails, it and all the entries after it are kept in the journal
+    pub fn write_to<S: JournalSink + ?Sized>(&mut self, sink: &mut S) -> io::Result<()> {
+        let mut written = 0;
+        let result = self.entries.iter().try_for_each(|entry| {
+            sink.write(entry)?;
+            written += 1;
+            Ok(())
+        });
+        self.entries.drain(..written);
+
+        result.and_then(|_| sink.flush())
+    }
+}
+
+impl Default for Journal {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Serialize)]
+struct JournalRecord<'a> {
+    sequence: u64,
+    client: ClientID,
+    event: &'static str,
+    tx: Option<TransactionID>,
+    currency: Option<&'a str>,
+    available: Option<Funds>,
+    held: Option<Funds>,
+    level: Option<FreezeLevel>,
+    reason: Option<&'a str>,
+    counterparty: Option<ClientID>,
+    pending: Option<Funds>,
+}
+
+impl<'a> JournalRecord<'a> {
+    fn new(entry: &'a JournalEntry) -> Self {
+        let diff = entry.event.diff();
+        let (level, reason) = match &entry.event {
+            AccountEvent::Frozen { level, reason } => (Some(*level), reason.as_deref()),
+            AccountEvent::Unfrozen { reason } => (Some(FreezeLevel::Active), reason.as_deref()),
+            _ => (None, None),
+        };
+        Self {
+            sequence: entry.sequence,
+            client: entry.client,
+            event: entry.event.name(),
+            tx: entry.event.transaction(),
+            currency: entry.event.currency().map(Currency::code),
+            available: diff.and_then(|d| d.available()),
+            held: diff.and_then(|d| d.held()),
+            level,
+            reason,
+            counterparty: entry.event.counterparty(),
+            pending: diff.and_then(|d| d.pending()),
+        }
+    }
+}
+
+/// Writes journal entries as csv records
+pub struct CsvJournalSink<W: Write> {
+    writer: Writer<W>,
+}
+
+impl<W: Write> CsvJournalSink<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: Writer::from_writer(writer),
+        }
+    }
+}
+
+impl<W: Write> JournalSink for CsvJournalSink<W> {
+    fn write(&mut self, entry: &JournalEntry) -> io::Result<()> {
+        Ok(self.writer.serialize(JournalRecord::new(entry))?)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    fn deposited(transaction: TransactionID) -> AccountEvent {
+        AccountEvent::Deposited {
+            transaction,
+            currency: Currency::default(),
+            diff: BalanceDiff::new().with_available(Funds::new(dec!(1.5))),
+        }
+    }
+
+    #[test]
+    fn test_sequence_survives_drain() {
+        let mut journal = Journal::new();
+        journal.append(1, vec![deposited(1), deposited(2)]);
+        assert_eq!(
+            journal.drain().map(|e| e.sequence).collect::<Vec<_>>(),
+            vec![0, 1]
+        );
+
+        journal.append(2, vec![deposited(3)]);
+        assert_eq!(
+            journal.iter().map(|e| e.sequence).collect::<Vec<_>>(),
+            vec![2]
+        );
+    }
+
+    #[test]
+    fn test_disabled() {
+        let mut journal = Journal::disabled();
+        journal.append(1, vec![deposited(1)]);
+        assert!(journal.is_empty());
+    }
+
+    #[test]
+    fn test_csv_sink() {
+        let mut journal = Journal::new();
+        journal.append(
+            1,
+            vec![
+                deposited(1),
+                AccountEvent::Frozen {
+                    level: FreezeLevel::FullyLocked,
+                    reason: Some("fraud".to_string()),
+                },
+                AccountEvent::TransferredIn {
+                    transaction: 2,
+                    from: 3,
+                    currency: Currency::default(),
+                    diff: BalanceDiff::new().with_available(Funds::new(dec!(0.5))),
+                },
+            ],
+        );
+
+        let mut buffer = vec![];
+        journal
+            .write_to(&mut CsvJournalSink::new(&mut buffer))
+            .expect("Write to succeed");
+        assert!(journal.is_empty());
+        assert_eq!(
+            String::from_utf8(buffer).expect("Valid utf8"),
+            "sequence,client,event,tx,currency,available,held,level,reason,counterparty,pending\n\
+             0,1,deposited,1,USD,1.5,,,,,\n\
+             1,1,frozen,,,,,fully-locked,fraud,,\n\
+             2,1,transferred-in,2,USD,0.5,,,,3,\n",
+        );
+    }
+}
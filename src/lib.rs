@@ -1,5 +1,20 @@
 pub mod account;
+pub mod account_store;
 pub mod balance;
+pub mod clock;
+pub mod currency;
+pub mod deduplicator;
+pub mod deficit_policy;
+pub mod disk_account_store;
+pub mod dispute_expiry;
+pub mod dispute_policy;
+pub mod freeze_level;
 pub mod funds;
+pub mod journal;
+pub mod precision;
+pub mod rounding;
+pub mod snapshot;
 pub mod transaction;
 pub mod transaction_engine;
+pub mod transaction_index;
+pub mod wal;
@@ -0,0 +1,114 @@
+use crate::currency::Currency;
+use crate::funds::Funds;
+use crate::funds::FundsOpError;
+use crate::rounding::RoundingMode;
+use std::collections::HashMap;
+
+/// Decimal places allowed for assets that aren't in the table, suitable for fiat currencies
+pub const DEFAULT_SCALE: u32 = 4;
+
+/// Number of decimal places amounts in each asset can have
+///
+/// Comes with entries for common crypto assets, every other asset uses the default scale.
+/// Amounts are quantized to the scale of their asset with the table's `RoundingMode`
+#[derive(Debug, Clone)]
+pub struct PrecisionTable {
+    default_scale: u32,
+    scales: HashMap<Currency, u32>,
+    rounding: RoundingMode,
+}
+
+impl PrecisionTable {
+    pub fn new() -> Self {
+        Self {
+            default_scale: DEFAULT_SCALE,
+            scales: HashMap::from([(Currency::new("BTC"), 8), (Currency::new("ETH"), 18)]),
+            rounding: RoundingMode::default(),
+        }
+    }
+
+    pub fn with_default_scale(self, default_scale: u32) -> Self {
+        Self {
+            default_scale,
+            ..self
+        }
+    }
+
+    pub fn with_rounding(self, rounding: RoundingMode) -> Self {
+        Self { rounding, ..self }
+    }
+
+    /// Sets the scale of `currency`, replacing its previous entry if there was one
+    pub fn with_scale(self, currency: Currency, scale: u32) -> Self {
+        let mut scales = self.scales;
+        scales.insert(currency, scale);
+        Self { scales, ..self }
+    }
+
+    pub fn scale(&self, currency: &Currency) -> u32 {
+        self.scales
+            .get(currency)
+            .copied()
+            .unwrap_or(self.default_scale)
+    }
+
+    /// Fails if `amount` has more decimal places than allowed for `currency`
+    pub fn check(&self, currency: &Currency, amount: Funds) -> Result<Funds, FundsOpError> {
+        amount.check_scale(self.scale(currency))
+    }
+
+    pub fn rounding(&self) -> RoundingMode {
+        self.rounding
+    }
+
+    /// Rounds `amount` to the scale of `currency`
+    pub fn round(&self, currency: &Currency, amount: Funds) -> Result<Funds, FundsOpError> {
+        amount.round(self.scale(currency), self.rounding)
+    }
+}
+
+impl Default for PrecisionTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_scales() {
+        let table = PrecisionTable::new().with_scale(Currency::new("EUR"), 2);
+        assert_eq!(table.scale(&Currency::new("USD")), DEFAULT_SCALE);
+        assert_eq!(table.scale(&Currency::new("EUR")), 2);
+        assert_eq!(table.scale(&Currency::new("BTC")), 8);
+
+        assert!(table
+            .check(&Currency::new("BTC"), Funds::new(dec!(0.00000001)))
+            .is_ok());
+        assert_eq!(
+            table.check(&Currency::new("EUR"), Funds::new(dec!(0.001))),
+            Err(FundsOpError::ExcessPrecision {
+                scale: 3,
+                max_scale: 2
+            }),
+        );
+    }
+
+    #[test]
+    fn test_round() {
+        let table = PrecisionTable::new()
+            .with_scale(Currency::new("EUR"), 2)
+            .with_rounding(RoundingMode::HalfUp);
+        assert_eq!(
+            table.round(&Currency::new("EUR"), Funds::new(dec!(0.125))),
+            Ok(Funds::new(dec!(0.13)))
+        );
+        assert_eq!(
+            table.round(&Currency::new("BTC"), Funds::new(dec!(0.125))),
+            Ok(Funds::new(dec!(0.125)))
+        );
+    }
+}
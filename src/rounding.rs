@@ -0,0 +1,85 @@
+use rust_decimal::RoundingStrategy;
+use serde::Deserialize;
+use serde::Serialize;
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Debug, PartialEq, Eq, Error)]
+#[error("Unknown rounding mode {0:?}, expected one of half-even, half-up, down, up or truncate")]
+pub struct UnknownRoundingMode(String);
+
+/// How amounts are rounded when they're quantized to fewer decimal places
+///
+/// `Down` and `Up` round towards negative and positive infinity respectively, so they only
+/// agree with `Truncate` (which rounds towards zero) for positive amounts
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RoundingMode {
+    /// Ties go to the nearest even digit (banker's rounding)
+    #[default]
+    HalfEven,
+    /// Ties go away from zero
+    HalfUp,
+    Down,
+    Up,
+    Truncate,
+}
+
+impl From<RoundingMode> for RoundingStrategy {
+    fn from(mode: RoundingMode) -> Self {
+        match mode {
+            RoundingMode::HalfEven => RoundingStrategy::MidpointNearestEven,
+            RoundingMode::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+            RoundingMode::Down => RoundingStrategy::ToNegativeInfinity,
+            RoundingMode::Up => RoundingStrategy::ToPositiveInfinity,
+            RoundingMode::Truncate => RoundingStrategy::ToZero,
+        }
+    }
+}
+
+impl FromStr for RoundingMode {
+    type Err = UnknownRoundingMode;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "half-even" => Ok(RoundingMode::HalfEven),
+            "half-up" => Ok(RoundingMode::HalfUp),
+            "down" => Ok(RoundingMode::Down),
+            "up" => Ok(RoundingMode::Up),
+            "truncate" => Ok(RoundingMode::Truncate),
+            _ => Err(UnknownRoundingMode(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for RoundingMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RoundingMode::HalfEven => "half-even",
+            RoundingMode::HalfUp => "half-up",
+            RoundingMode::Down => "down",
+            RoundingMode::Up => "up",
+            RoundingMode::Truncate => "truncate",
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        for mode in [
+            RoundingMode::HalfEven,
+            RoundingMode::HalfUp,
+            RoundingMode::Down,
+            RoundingMode::Up,
+            RoundingMode::Truncate,
+        ] {
+            assert_eq!(mode.to_string().parse(), Ok(mode));
+        }
+        assert!("nearest".parse::<RoundingMode>().is_err());
+    }
+}
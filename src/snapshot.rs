@@ -0,0 +1,416 @@
+use crate::account::Account;
+use crate::account_store::AccountStoreError;
+use crate::currency::DEFAULT_CURRENCY;
+use crate::deduplicator::Fingerprint;
+use crate::transaction::ClientID;
+use crate::transaction_index::TransactionIndex;
+use serde::ser;
+use serde::ser::SerializeSeq;
+use serde::Deserialize;
+use serde::Serialize;
+use serde::Serializer;
+use serde_json::Map;
+use serde_json::Value;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use thiserror::Error;
+
+/// Version of the snapshot format written by this build
+///
+/// Bump this whenever the serialized form of the engine state changes, and handle older
+/// versions in `Snapshot::read_from`
+pub const SNAPSHOT_VERSION: u64 = 3;
+
+#[derive(Debug, Error)]
+pub enum SnapshotError {
+    #[error("Failed to access snapshot: {0}")]
+    Io(#[from] io::Error),
+    #[error("Malformed snapshot: {0}")]
+    Format(#[from] serde_json::Error),
+    #[error(transparent)]
+    AccountStore(#[from] AccountStoreError),
+    #[error("Unsupported snapshot version {0} (expected {SNAPSHOT_VERSION})")]
+    UnsupportedVersion(u64),
+    #[error("Snapshot has no version")]
+    MissingVersion,
+}
+
+/// Point in time copy of an engine's state, used to carry it over between runs
+///
+/// Includes every account (with the state of their disputes and freeze levels) and the
+/// transaction index, but not engine configuration such as the dispute policy.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    version: u64,
+    accounts: Vec<Account>,
+    transactions: TransactionIndex,
+    /// Sequence number of the last write-ahead log record included in the snapshot
+    #[serde(default)]
+    wal_sequence: u64,
+    /// Fingerprints of recently processed transactions, oldest first
+    #[serde(default)]
+    fingerprints: Vec<Fingerprint>,
+}
+
+impl Snapshot {
+    pub fn new(accounts: Vec<Account>, transactions: TransactionIndex) -> Self {
+        Self {
+            version: SNAPSHOT_VERSION,
+            accounts,
+            transactions,
+            wal_sequence: 0,
+            fingerprints: vec![],
+        }
+    }
+
+    pub fn with_wal_sequence(self, wal_sequence: u64) -> Self {
+        Self {
+            wal_sequence,
+            ..self
+        }
+    }
+
+    pub fn wal_sequence(&self) -> u64 {
+        self.wal_sequence
+    }
+
+    pub fn with_fingerprints(self, fingerprints: Vec<Fingerprint>) -> Self {
+        Self {
+            fingerprints,
+            ..self
+        }
+    }
+
+    pub fn fingerprints(&self) -> &[Fingerprint] {
+        &self.fingerprints
+    }
+
+    pub fn with_accounts(self, accounts: Vec<Account>) -> Self {
+        Self { accounts, ..self }
+    }
+
+    pub fn accounts(&self) -> &[Account] {
+        &self.accounts
+    }
+
+    pub fn into_parts(self) -> (Vec<Account>, TransactionIndex) {
+        (self.accounts, self.transactions)
+    }
+
+    /// Combines the state of two engines, e.g. the ones used by different threads
+    ///
+    /// The engines are expected to hold different clients. Transaction index entries are merged.
+    /// If the engines share a write-ahead log they're expected to have applied all of its records.
+    /// Fingerprints that aren't already in this snapshot are added after its own
+    pub fn merge(&mut self, other: Snapshot) {
+        self.accounts.extend(other.accounts);
+        self.transactions.extend(other.transactions);
+        self.wal_sequence = self.wal_sequence.max(other.wal_sequence);
+        let seen: HashSet<Fingerprint> = self.fingerprints.iter().copied().collect();
+        self.fingerprints.extend(
+            other
+                .fingerprints
+                .into_iter()
+                .filter(|fingerprint| !seen.contains(fingerprint)),
+        );
+    }
+
+    /// Splits a snapshot into `parts` smaller ones, assigning accounts with `part_of`
+    ///
+    /// The transaction index and fingerprints aren't split since they're shared between all
+    /// clients, they're only included in the first part
+    pub fn split(self, parts: usize, part_of: impl Fn(ClientID) -> usize) -> Vec<Snapshot> {
+        let mut snapshots: Vec<Snapshot> = std::iter::repeat_with(|| {
+            Snapshot::new(vec![], TransactionIndex::new()).with_wal_sequence(self.wal_sequence)
+        })
+        .take(parts)
+        .collect();
+        if let Some(first) = snapshots.first_mut() {
+            first.transactions = self.transactions;
+            first.fingerprints = self.fingerprints;
+        }
+        for account in self.accounts {
+            snapshots[part_of(account.client_id()) % parts]
+                .accounts
+                .push(account);
+        }
+
+        snapshots
+    }
+
+    pub fn write_to<W: Write>(&self, writer: W) -> Result<(), SnapshotError> {
+        Ok(serde_json::to_writer(writer, self)?)
+    }
+
+    /// Writes the snapshot with `accounts` added to its own
+    ///
+    /// The accounts are read one at a time as they're written, so they don't all have to fit in
+    /// memory (e.g. when they come from a `DiskAccountStore`)
+    pub fn write_with<'a, W, I>(&self, writer: W, accounts: I) -> Result<(), SnapshotError>
+    where
+        W: Write,
+        I: IntoIterator<Item = Result<Cow<'a, Account>, AccountStoreError>>,
+    {
+        #[derive(Serialize)]
+        struct SnapshotRef<'a, A> {
+            version: u64,
+            accounts: A,
+            transactions: &'a TransactionIndex,
+            wal_sequence: u64,
+            fingerprints: &'a [Fingerprint],
+        }
+
+        let error = RefCell::new(None);
+        let result = serde_json::to_writer(
+            writer,
+            &SnapshotRef {
+                version: self.version,
+                accounts: AccountsRef {
+                    own: &self.accounts,
+                    streamed: RefCell::new(Some(accounts.into_iter())),
+                    error: &error,
+                },
+                transactions: &self.transactions,
+                wal_sequence: self.wal_sequence,
+                fingerprints: &self.fingerprints,
+            },
+        );
+        // Reading an account failed if that's what interrupted serialization
+        match error.into_inner() {
+            Some(e) => Err(e.into()),
+            None => Ok(result?),
+        }
+    }
+
+    pub fn read_from<R: Read>(reader: R) -> Result<Self, SnapshotError> {
+        // Check the version before deserializing the rest so that snapshots from other versions
+        // are reported as such rather than as malformed
+        let mut value: Value = serde_json::from_reader(reader)?;
+        match value.get("version").and_then(Value::as_u64) {
+            Some(version @ 1..=SNAPSHOT_VERSION) => {
+                if version < 2 {
+                    migrate_v1(&mut value);
+                }
+                if version < 3 {
+                    migrate_v2(&mut value);
+                }
+                Ok(serde_json::from_value(value)?)
+            }
+            Some(version) => Err(SnapshotError::UnsupportedVersion(version)),
+            None => Err(SnapshotError::MissingVersion),
+        }
+    }
+}
+
+/// Serializes a snapshot's own accounts followed by the ones read from a store
+struct AccountsRef<'a, I> {
+    own: &'a [Account],
+    streamed: RefCell<Option<I>>,
+    /// Where the error of an account that couldn't be read is kept
+    error: &'a RefCell<Option<AccountStoreError>>,
+}
+
+impl<'a, 'b, I> Serialize for AccountsRef<'a, I>
+where
+    I: Iterator<Item = Result<Cow<'b, Account>, AccountStoreError>>,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(None)?;
+        for account in self.own {
+            seq.serialize_element(account)?;
+        }
+        for account in self.streamed.take().into_iter().flatten() {
+            match account {
+                Ok(account) => seq.serialize_element(&*account)?,
+                Err(e) => {
+                    let message = e.to_string();
+                    *self.error.borrow_mut() = Some(e);
+                    return Err(ser::Error::custom(message));
+                }
+            }
+        }
+        seq.end()
+    }
+}
+
+/// Upgrades a version 1 snapshot, from before accounts held multiple assets
+///
+/// Balances and disputable transactions in version 1 snapshots are in `DEFAULT_CURRENCY`
+fn migrate_v1(value: &mut Value) {
+    let accounts = value
+        .get_mut("accounts")
+        .and_then(Value::as_array_mut)
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_object_mut);
+    for account in accounts {
+        if let Some(balance) = account.remove("balance") {
+            let mut balances = Map::new();
+            balances.insert(DEFAULT_CURRENCY.to_string(), balance);
+            account.insert("balances".to_string(), Value::Object(balances));
+        }
+        for transactions in ["deposits", "withdrawals"] {
+            let states = account
+                .get_mut(transactions)
+                .and_then(Value::as_object_mut)
+                .into_iter()
+                .flat_map(|states| states.values_mut());
+            for state in states {
+                let mut disputable = Map::new();
+                disputable.insert("currency".to_string(), DEFAULT_CURRENCY.into());
+                disputable.insert("state".to_string(), state.take());
+                *state = Value::Object(disputable);
+            }
+        }
+    }
+    value["version"] = 2.into();
+}
+
+/// Upgrades a version 2 snapshot, from before disputes could be for part of a transaction
+///
+/// Disputes in version 2 snapshots were always for the whole amount
+fn migrate_v2(value: &mut Value) {
+    let accounts = value
+        .get_mut("accounts")
+        .and_then(Value::as_array_mut)
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_object_mut);
+    for account in accounts {
+        for transactions in ["deposits", "withdrawals"] {
+            let states = account
+                .get_mut(transactions)
+                .and_then(Value::as_object_mut)
+                .into_iter()
+                .flat_map(|states| states.values_mut())
+                .filter_map(|disputable| disputable.get_mut("state"));
+            for state in states {
+                if let Some(disputed) = state.get_mut("InDispute") {
+                    let mut in_dispute = Map::new();
+                    in_dispute.insert("disputed".to_string(), disputed.take());
+                    in_dispute.insert("undisputed".to_string(), "0".into());
+                    *disputed = Value::Object(in_dispute);
+                }
+            }
+        }
+    }
+    value["version"] = 3.into();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::currency::Currency;
+    use crate::funds::Funds;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_roundtrip() {
+        let mut account = Account::new(1);
+        account
+            .deposit(1, &Currency::default(), Funds::new(dec!(1.5)))
+            .expect("Deposit to succeed");
+        account.dispute(1, None).expect("Dispute to succeed");
+
+        let mut buffer = vec![];
+        Snapshot::new(vec![account], TransactionIndex::new())
+            .write_to(&mut buffer)
+            .expect("Write to succeed");
+        let mut restored = Snapshot::read_from(buffer.as_slice())
+            .expect("Read to succeed")
+            .into_parts()
+            .0;
+
+        let mut account = restored.pop().expect("One account");
+        assert_eq!(
+            account.balance(&Currency::default()).held(),
+            Funds::new(dec!(1.5))
+        );
+        // The dispute is still open after restoring
+        account.resolve(1, None).expect("Resolve to succeed");
+        assert_eq!(
+            account.balance(&Currency::default()).available(),
+            Funds::new(dec!(1.5))
+        );
+    }
+
+    #[test]
+    fn test_write_with() {
+        let mut buffer = vec![];
+        let streamed = [Account::new(2), Account::new(3)];
+        Snapshot::new(vec![Account::new(1)], TransactionIndex::new())
+            .write_with(&mut buffer, streamed.iter().map(|a| Ok(Cow::Borrowed(a))))
+            .expect("Write to succeed");
+        let clients: Vec<ClientID> = Snapshot::read_from(buffer.as_slice())
+            .expect("Read to succeed")
+            .accounts()
+            .iter()
+            .map(Account::client_id)
+            .collect();
+        assert_eq!(clients, vec![1, 2, 3]);
+
+        let failing = [Err(AccountStoreError::Io(io::ErrorKind::Other.into()))];
+        assert!(matches!(
+            Snapshot::new(vec![], TransactionIndex::new()).write_with(vec![], failing),
+            Err(SnapshotError::AccountStore(AccountStoreError::Io(_)))
+        ));
+    }
+
+    #[test]
+    fn test_migrate_v1() {
+        let snapshot = Snapshot::read_from(
+            r#"{
+                "version": 1,
+                "accounts": [{
+                    "client": 1,
+                    "balance": {"available": "0.5", "held": "1.5"},
+                    "deposits": {"1": {"InDispute": "1.5"}},
+                    "withdrawals": {},
+                    "freeze_level": "active",
+                    "admin_reason": null
+                }],
+                "transactions": {"1": {"client": 1, "tx_type": "deposit"}}
+            }"#
+            .as_bytes(),
+        )
+        .expect("Read to succeed");
+
+        let mut account = snapshot.into_parts().0.pop().expect("One account");
+        account.resolve(1, None).expect("Resolve to succeed");
+        assert_eq!(
+            account
+                .balance(&Currency::new(DEFAULT_CURRENCY))
+                .available(),
+            Funds::new(dec!(2.0))
+        );
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        assert!(matches!(
+            Snapshot::read_from(r#"{"version":0,"accounts":[],"transactions":{}}"#.as_bytes()),
+            Err(SnapshotError::UnsupportedVersion(0)),
+        ));
+        assert!(matches!(
+            Snapshot::read_from(r#"{"accounts":[]}"#.as_bytes()),
+            Err(SnapshotError::MissingVersion),
+        ));
+    }
+
+    #[test]
+    fn test_split() {
+        let snapshot = Snapshot::new((0..4).map(Account::new).collect(), TransactionIndex::new());
+        let parts = snapshot.split(2, |client| client as usize);
+        assert_eq!(
+            parts
+                .iter()
+                .map(|p| p.accounts().iter().map(Account::client_id).collect())
+                .collect::<Vec<Vec<_>>>(),
+            vec![vec![0, 2], vec![1, 3]],
+        );
+    }
+}
@@ -1,17 +1,64 @@
+use crate::currency::Currency;
+use crate::freeze_level::FreezeLevel;
 use crate::funds::Funds;
 use serde::Deserialize;
+use serde::Serialize;
 
 pub type ClientID = u16;
 pub type TransactionID = u32;
+/// Seconds since the Unix epoch
+pub type Timestamp = u64;
 
-#[derive(Debug, PartialEq, Eq, Deserialize)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum TransactionType {
+    #[default]
     Deposit,
     Withdrawal,
     Dispute,
     Resolve,
     Chargeback,
+    /// Administrative freeze of an account
+    Lock,
+    /// Administrative reinstatement of a frozen account
+    Unlock,
+    /// Moves funds from `client` to `to`
+    Transfer,
+    /// Returns part or all of the deposit it references, all of what's left unless it has an amount
+    Refund,
+    /// Makes the pending funds of the deposit it references available without waiting for it
+    /// to clear
+    Clear,
+    /// Puts funds on hold, under the authorization's own transaction ID
+    Authorize,
+    /// Settles funds held by the authorization it references, all of them unless it has an amount
+    Capture,
+    /// Releases the funds held by the authorization it references
+    Void,
+    /// Contests the chargeback of the transaction it references, holding the funds again
+    Represent,
+    /// Contests a re-presentment, after which a `chargeback` upholds the original chargeback
+    #[serde(rename = "pre-arbitration")]
+    PreArbitration,
+    /// Settles a re-presentment in the client's favour, making the funds available again
+    #[serde(rename = "reverse-chargeback")]
+    ReverseChargeback,
+}
+
+/// Side of a transfer applied to one of its accounts
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TransferLeg {
+    /// Takes the funds out of the sending account
+    Debit,
+    /// Credits them to the receiving account
+    Credit,
+}
+
+impl TransactionType {
+    /// Administrative transactions act on existing accounts and require an audit reason
+    pub fn is_admin(&self) -> bool {
+        matches!(self, TransactionType::Lock | TransactionType::Unlock)
+    }
 }
 
 /// Type for representing transactions from the csv input files
@@ -23,10 +70,28 @@ pub enum TransactionType {
 ///
 /// Instead we opt to make amount an `Option`
 ///
+/// Similarly `reason` and `level` are optional trailing columns only used by administrative
+/// transactions: `reason` records why they were issued and `level` is the `FreezeLevel` a `lock`
+/// moves the account to. Input without these columns is still valid
+///
+/// `currency` is the asset a deposit or withdrawal is in (`DEFAULT_CURRENCY` when left out).
+/// Disputes, resolves and chargebacks always apply to the asset of the transaction they reference
+///
+/// `to` is the client a `transfer` sends funds to, `client` being the one sending them
+///
+/// `idempotency_key` optionally identifies the transaction when deduplicating replays (see
+/// `Deduplicator`), it can be left out like the other optional columns
+///
+/// `timestamp` is when the transaction happened. Transactions without one are stamped by the
+/// engine's `Clock` when they're received
+///
+/// `clearing_period` is how many seconds a deposit's funds stay pending before they become
+/// available, overriding the clearing period of its asset
+///
 /// This has some implications for serialisation:
 /// because all records need to have the same amount of columns we need a trailing comma for
 /// records that do not have an amount
-#[derive(Debug, PartialEq, Eq, Deserialize)]
+#[derive(Debug, PartialEq, Eq, Clone, Default, Serialize, Deserialize)]
 pub struct Transaction {
     #[serde(rename = "type")]
     pub tx_type: TransactionType,
@@ -34,6 +99,21 @@ pub struct Transaction {
     #[serde(rename = "tx")]
     pub transaction: TransactionID,
     pub amount: Option<Funds>,
+    #[serde(default)]
+    pub currency: Option<Currency>,
+    #[serde(default)]
+    pub reason: Option<String>,
+    #[serde(default)]
+    pub level: Option<FreezeLevel>,
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+    // Left out when empty so fingerprints of transactions logged before transfers existed still match
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<ClientID>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<Timestamp>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clearing_period: Option<u64>,
 }
 
 #[cfg(test)]
@@ -59,6 +139,7 @@ mod test {
                 client: 1,
                 transaction: 1,
                 amount: Some(Funds::new(1)),
+                ..Default::default()
             },
         );
     }
@@ -73,6 +154,102 @@ mod test {
                 client: 1,
                 transaction: 1,
                 amount: None,
+                ..Default::default()
+            },
+        );
+    }
+
+    #[test]
+    fn test_deserialize_timestamp() {
+        let read = |input: &str| {
+            Reader::from_reader(input.as_bytes())
+                .deserialize::<Transaction>()
+                .next()
+                .expect("One element")
+                .expect("Serialization to succeed")
+                .timestamp
+        };
+        assert_eq!(
+            read("type,client,tx,amount,timestamp\ndeposit,1,1,1.0,1700000000"),
+            Some(1_700_000_000)
+        );
+        assert_eq!(read("type,client,tx,amount,timestamp\ndispute,1,1,,"), None);
+    }
+
+    #[test]
+    fn test_deserialize_pre_arbitration() {
+        assert_eq!(
+            deserialize_transaction_from_str("pre-arbitration,1,1,").tx_type,
+            TransactionType::PreArbitration,
+        );
+        assert_eq!(
+            deserialize_transaction_from_str("reverse-chargeback,1,1,").tx_type,
+            TransactionType::ReverseChargeback,
+        );
+    }
+
+    #[test]
+    fn test_deserialize_unlock() {
+        assert_eq!(
+            Reader::from_reader(
+                "type,client,tx,amount,reason\nunlock,1,1,,chargeback reversed".as_bytes()
+            )
+            .deserialize::<Transaction>()
+            .next()
+            .expect("One element")
+            .expect("Serialization to succeed"),
+            Transaction {
+                tx_type: TransactionType::Unlock,
+                client: 1,
+                transaction: 1,
+                amount: None,
+                reason: Some("chargeback reversed".to_string()),
+                ..Default::default()
+            },
+        );
+    }
+
+    #[test]
+    fn test_deserialize_lock() {
+        assert_eq!(
+            Reader::from_reader(
+                "type,client,tx,amount,reason,level\nlock,1,1,,fraud,fully-locked".as_bytes()
+            )
+            .deserialize::<Transaction>()
+            .next()
+            .expect("One element")
+            .expect("Serialization to succeed"),
+            Transaction {
+                tx_type: TransactionType::Lock,
+                client: 1,
+                transaction: 1,
+                amount: None,
+                reason: Some("fraud".to_string()),
+                level: Some(FreezeLevel::FullyLocked),
+                ..Default::default()
+            },
+        );
+    }
+
+    #[test]
+    fn test_deserialize_optional_columns() {
+        assert_eq!(
+            Reader::from_reader(
+                "type,client,tx,amount,currency,idempotency_key\nwithdrawal,1,2,1.0,eur,abc"
+                    .as_bytes()
+            )
+            .deserialize::<Transaction>()
+            .next()
+            .expect("One element")
+            .expect("Serialization to succeed"),
+            Transaction {
+                tx_type: TransactionType::Withdrawal,
+                client: 1,
+                transaction: 2,
+                amount: Some(Funds::new(1)),
+                currency: Some(Currency::new("EUR")),
+                idempotency_key: Some("abc".to_string()),
+                ..Default::default()
             },
         );
     }
@@ -1,9 +1,42 @@
 use crate::account::Account;
+use crate::account::AccountConfig;
 use crate::account::AccountUpdateError;
+use crate::account::PendingTransfer;
+use crate::account_store::AccountStore;
+use crate::account_store::AccountStoreError;
+use crate::account_store::MemoryAccountStore;
+use crate::balance::BalanceConstraints;
+use crate::clock::Clock;
+use crate::clock::SystemClock;
+use crate::currency::Currency;
+use crate::deduplicator::Fingerprint;
+use crate::deduplicator::SharedDeduplicator;
+use crate::deficit_policy::DeficitPolicy;
+use crate::dispute_expiry::DisputeExpiry;
+use crate::dispute_expiry::ExpiryAction;
+use crate::dispute_policy::DisputePolicy;
+use crate::freeze_level::FreezeLevel;
+use crate::funds::FundsOpError;
+use crate::journal::AccountEvent;
+use crate::journal::Journal;
+use crate::precision::PrecisionTable;
+use crate::snapshot::Snapshot;
 use crate::transaction::ClientID;
+use crate::transaction::Timestamp;
 use crate::transaction::Transaction;
+use crate::transaction::TransactionID;
 use crate::transaction::TransactionType;
+use crate::transaction::TransferLeg;
+use crate::transaction_index::IndexedTransaction;
+use crate::transaction_index::SharedTransactionIndex;
+use crate::transaction_index::TransactionIndex;
+use crate::wal::SharedWriteAheadLog;
+use crate::wal::WalError;
+use crate::wal::WalRecord;
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -12,49 +45,2030 @@ pub enum TransactionEngineError {
     AccountUpdate(ClientID, AccountUpdateError),
     #[error("Missing amount")]
     MissingAmount,
+    #[error("Missing reason")]
+    MissingReason,
+    #[error("Missing transfer recipient")]
+    MissingRecipient,
+    #[error("Account {0} can't transfer to itself")]
+    SelfTransfer(ClientID),
+    #[error("Invalid {0} amount: {1}")]
+    InvalidAmount(Currency, FundsOpError),
+    #[error("Transaction {0} already processed as a {1:?} for account {2}")]
+    DuplicateTransaction(TransactionID, TransactionType, ClientID),
+    #[error("Transaction {tx} belongs to account {owner}, not account {claimed}")]
+    ClientMismatch {
+        claimed: ClientID,
+        owner: ClientID,
+        tx: TransactionID,
+    },
+    #[error("Account {0} does not exist")]
+    UnknownAccount(ClientID),
+    #[error("{0}")]
+    WriteAheadLog(#[from] WalError),
+    #[error("{0}")]
+    AccountStore(#[from] AccountStoreError),
+    #[error("Transaction {0} is a replay of a transaction that was already processed")]
+    Replay(TransactionID),
 }
 
+/// Controls which transactions can open an account for a client the engine hasn't seen yet
+///
+/// In both cases an account is only created if the transaction succeeds, so failed or
+/// reference-only transactions (e.g. disputes) for unknown clients don't leave empty accounts behind
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum AccountCreation {
+    /// Any successful transaction opens the account
+    #[default]
+    OnSuccess,
+    /// Only successful deposits open the account, anything else is rejected with `UnknownAccount`
+    DepositsOnly,
+}
+
+/// A dispute the engine settled automatically because it was open for too long
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct ExpiredDispute {
+    pub client: ClientID,
+    pub transaction: TransactionID,
+    pub action: ExpiryAction,
+}
+
+/// Processes transactions, keeping the accounts in an `AccountStore` (in memory by default)
 #[derive(Debug)]
-pub struct TransactionEngine {
-    accounts: HashMap<ClientID, Account>,
+pub struct TransactionEngine<S: AccountStore = MemoryAccountStore> {
+    store: S,
+    index: SharedTransactionIndex,
+    account_creation: AccountCreation,
+    /// Applied to every account the engine gets from its store
+    account_config: AccountConfig,
+    balance_constraints: BalanceConstraints,
+    journal: Journal,
+    wal: Option<SharedWriteAheadLog>,
+    /// Sequence number of the last write-ahead log record processed by this engine
+    wal_sequence: u64,
+    deduplicator: Option<SharedDeduplicator>,
+    default_currency: Currency,
+    precision: PrecisionTable,
+    /// Number of transactions for an account after which its holds expire
+    hold_expiry: Option<u64>,
+    /// Seconds deposits in each asset are pending for before they clear
+    clearing_periods: HashMap<Currency, u64>,
+    /// Disputes settled automatically while processing the last transaction
+    expired_disputes: Vec<ExpiredDispute>,
+    clock: Arc<dyn Clock>,
 }
 
 impl TransactionEngine {
     pub fn new() -> Self {
+        Self::with_index(TransactionIndex::shared())
+    }
+
+    /// Creates an engine that records transaction IDs in `index`
+    ///
+    /// Engines sharing the same index reject IDs already used by any of them
+    pub fn with_index(index: SharedTransactionIndex) -> Self {
         Self {
-            accounts: HashMap::new(),
+            store: MemoryAccountStore::new(),
+            index,
+            account_creation: AccountCreation::default(),
+            account_config: AccountConfig::new(),
+            balance_constraints: BalanceConstraints::new(),
+            journal: Journal::disabled(),
+            wal: None,
+            wal_sequence: 0,
+            deduplicator: None,
+            default_currency: Currency::default(),
+            precision: PrecisionTable::new(),
+            hold_expiry: None,
+            clearing_periods: HashMap::new(),
+            expired_disputes: vec![],
+            clock: Arc::new(SystemClock),
         }
     }
 
     pub fn accounts(&self) -> &HashMap<ClientID, Account> {
-        &self.accounts
+        self.store.accounts()
+    }
+}
+
+impl<S: AccountStore> TransactionEngine<S> {
+    /// Keeps accounts in `store` instead
+    ///
+    /// Accounts already in the engine are dropped, so this should be set before processing
+    /// or restoring anything
+    pub fn with_store<T: AccountStore>(self, store: T) -> TransactionEngine<T> {
+        TransactionEngine {
+            store,
+            index: self.index,
+            account_creation: self.account_creation,
+            account_config: self.account_config,
+            balance_constraints: self.balance_constraints,
+            journal: self.journal,
+            wal: self.wal,
+            wal_sequence: self.wal_sequence,
+            deduplicator: self.deduplicator,
+            default_currency: self.default_currency,
+            precision: self.precision,
+            hold_expiry: self.hold_expiry,
+            clearing_periods: self.clearing_periods,
+            expired_disputes: self.expired_disputes,
+            clock: self.clock,
+        }
+    }
+
+    pub fn with_account_creation(self, account_creation: AccountCreation) -> Self {
+        Self {
+            account_creation,
+            ..self
+        }
+    }
+
+    /// Sets the dispute policy for accounts opened from now on
+    pub fn with_dispute_policy(self, dispute_policy: Arc<dyn DisputePolicy>) -> Self {
+        Self {
+            account_config: self.account_config.with_dispute_policy(dispute_policy),
+            ..self
+        }
+    }
+
+    /// Sets the level chargebacks freeze accounts opened from now on to
+    pub fn with_chargeback_freeze_level(self, chargeback_freeze_level: FreezeLevel) -> Self {
+        Self {
+            account_config: self
+                .account_config
+                .with_chargeback_freeze_level(chargeback_freeze_level),
+            ..self
+        }
+    }
+
+    /// Lets reversed chargebacks unfreeze accounts (see `Account::reverse_chargeback`)
+    pub fn with_unfreeze_on_reversal(self, unfreeze_on_reversal: bool) -> Self {
+        Self {
+            account_config: self
+                .account_config
+                .with_unfreeze_on_reversal(unfreeze_on_reversal),
+            ..self
+        }
+    }
+
+    /// Sets what happens to disputes that would leave an account's available funds negative
+    pub fn with_deficit_policy(self, deficit_policy: DeficitPolicy) -> Self {
+        Self {
+            account_config: self.account_config.with_deficit_policy(deficit_policy),
+            ..self
+        }
+    }
+
+    /// Sets the balance constraints of accounts opened from now on
+    ///
+    /// Constraints are part of an account's state, so accounts restored from a snapshot keep
+    /// the ones they were opened with
+    pub fn with_balance_constraints(self, balance_constraints: BalanceConstraints) -> Self {
+        Self {
+            balance_constraints,
+            ..self
+        }
+    }
+
+    /// Records events in `journal`, e.g. `Journal::new()`
+    ///
+    /// No events are recorded by default, since they'd pile up unless they're consumed
+    pub fn with_journal(self, journal: Journal) -> Self {
+        Self { journal, ..self }
+    }
+
+    /// Durably records every transaction in `wal` before processing it
+    ///
+    /// Engines can share the same log, in which case snapshots should only be taken once all
+    /// of them have processed every transaction they've logged
+    pub fn with_write_ahead_log(self, wal: SharedWriteAheadLog) -> Self {
+        lock(&wal).continue_after(self.wal_sequence);
+        Self {
+            wal: Some(wal),
+            ..self
+        }
+    }
+
+    /// Rejects transactions that are replays of ones `deduplicator` has already seen
+    ///
+    /// Engines can share the same deduplicator, in which case a replay is detected even if it's
+    /// processed by another engine
+    pub fn with_deduplicator(self, deduplicator: SharedDeduplicator) -> Self {
+        Self {
+            deduplicator: Some(deduplicator),
+            ..self
+        }
+    }
+
+    /// Sets the asset deposits and withdrawals without a currency are in
+    pub fn with_default_currency(self, default_currency: Currency) -> Self {
+        Self {
+            default_currency,
+            ..self
+        }
+    }
+
+    /// Sets how many decimal places amounts in each asset can have
+    pub fn with_precision(self, precision: PrecisionTable) -> Self {
+        Self { precision, ..self }
+    }
+
+    /// Makes holds expire once `transactions` more transactions have been processed for their
+    /// account without them being captured or voided
+    pub fn with_hold_expiry(self, transactions: u64) -> Self {
+        Self {
+            hold_expiry: Some(transactions),
+            ..self
+        }
+    }
+
+    /// Rejects disputes of transactions that happened more than `seconds` ago
+    pub fn with_dispute_window(self, seconds: u64) -> Self {
+        Self {
+            account_config: self.account_config.with_dispute_window(Some(seconds)),
+            ..self
+        }
+    }
+
+    /// Settles disputes automatically once they've been open for longer than `dispute_expiry`
+    /// allows (see `expired_disputes`)
+    pub fn with_dispute_expiry(self, dispute_expiry: DisputeExpiry) -> Self {
+        Self {
+            account_config: self
+                .account_config
+                .with_dispute_expiry(Some(dispute_expiry)),
+            ..self
+        }
+    }
+
+    /// Keeps deposits in `currency` pending for `seconds` before their funds become available,
+    /// unless a deposit has its own clearing period
+    pub fn with_clearing_period(self, currency: Currency, seconds: u64) -> Self {
+        let mut clearing_periods = self.clearing_periods;
+        clearing_periods.insert(currency, seconds);
+        Self {
+            clearing_periods,
+            ..self
+        }
+    }
+
+    /// Replaces the clock transactions without a timestamp are stamped with (the system's by
+    /// default)
+    pub fn with_clock(self, clock: Arc<dyn Clock>) -> Self {
+        Self { clock, ..self }
+    }
+
+    pub fn precision(&self) -> &PrecisionTable {
+        &self.precision
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    pub fn store_mut(&mut self) -> &mut S {
+        &mut self.store
+    }
+
+    /// Captures the state of every account and the transaction index
+    ///
+    /// Every account is copied into the snapshot, for stores larger than memory use
+    /// `snapshot_without_accounts` and pass the accounts to `Snapshot::write_with` instead
+    pub fn snapshot(&self) -> Result<Snapshot, TransactionEngineError> {
+        let accounts = self
+            .store
+            .iter()
+            .map(|account| account.map(|a| a.into_owned()))
+            .collect::<Result<_, _>>()?;
+        Ok(self.snapshot_without_accounts().with_accounts(accounts))
+    }
+
+    /// Captures the transaction index and everything else but the accounts
+    pub fn snapshot_without_accounts(&self) -> Snapshot {
+        let fingerprints = match &self.deduplicator {
+            Some(deduplicator) => lock(deduplicator).fingerprints().collect(),
+            None => vec![],
+        };
+        Snapshot::new(vec![], self.index().clone())
+            .with_wal_sequence(self.wal_sequence)
+            .with_fingerprints(fingerprints)
+    }
+
+    /// Loads the accounts and transaction IDs from `snapshot`
+    ///
+    /// Accounts already in the engine are replaced by the ones in the snapshot. Restored accounts
+    /// use the engine's configuration (e.g. dispute policy) rather than the one they were created with
+    pub fn restore(&mut self, snapshot: Snapshot) -> Result<(), TransactionEngineError> {
+        self.wal_sequence = self.wal_sequence.max(snapshot.wal_sequence());
+        if let Some(deduplicator) = &self.deduplicator {
+            lock(deduplicator).extend(snapshot.fingerprints().iter().copied());
+        }
+        let (accounts, transactions) = snapshot.into_parts();
+        self.index().extend(transactions);
+        for account in accounts {
+            let account = self.configure(account);
+            self.store.upsert(account)?;
+        }
+
+        Ok(())
+    }
+
+    /// Events applied to accounts that haven't been consumed yet
+    pub fn journal(&self) -> &Journal {
+        &self.journal
+    }
+
+    pub fn journal_mut(&mut self) -> &mut Journal {
+        &mut self.journal
+    }
+
+    /// Disputes that expired and were settled automatically while processing the last
+    /// transaction
+    ///
+    /// Expired disputes are settled before the transaction is applied, so they're reported
+    /// whether or not it succeeded
+    pub fn expired_disputes(&self) -> &[ExpiredDispute] {
+        &self.expired_disputes
+    }
+
+    /// Looks up which client and transaction type `transaction_id` belongs to
+    pub fn transaction(&self, transaction_id: TransactionID) -> Option<IndexedTransaction> {
+        self.index().get(transaction_id)
     }
 
     pub fn process(&mut self, t: Transaction) -> Result<(), TransactionEngineError> {
-        let account = self
-            .accounts
-            .entry(t.client)
-            .or_insert_with(|| Account::new(t.client));
+        // Taken before the transaction is stamped, and logged with it, so that the same row read
+        // from the input again still matches once the log has been replayed
+        let fingerprint = self.fingerprint(&t);
+        // Replays are rejected before being logged, so they're never replayed from the log either
+        self.deduplicated(t, fingerprint, |engine, mut t| {
+            // Stamped before being logged so a replay happens at the same time as the original
+            t.timestamp.get_or_insert_with(|| engine.clock.now());
+            if let Some(wal) = &engine.wal {
+                engine.wal_sequence = lock(wal).append(&t, fingerprint)?;
+            }
+
+            engine.process_transaction(t)
+        })
+    }
+
+    /// Processes a transaction recovered from the write-ahead log, without logging it again
+    ///
+    /// Records already included in the state restored from a snapshot are skipped
+    pub fn replay(&mut self, record: WalRecord) -> Result<(), TransactionEngineError> {
+        if record.sequence <= self.wal_sequence {
+            return Ok(());
+        }
+
+        self.wal_sequence = record.sequence;
+        self.deduplicated(
+            record.transaction,
+            record.fingerprint,
+            Self::process_transaction,
+        )
+    }
+
+    /// Fingerprint `t` is deduplicated and logged with, if replays are being rejected
+    fn fingerprint(&self, t: &Transaction) -> Option<Fingerprint> {
+        self.deduplicator.as_ref().map(|_| Fingerprint::of(t))
+    }
+
+    /// Processes `t` with `f` unless it's a replay of a transaction that was already processed
+    ///
+    /// Transactions that fail are still remembered (replaying them could have a different outcome
+    /// than the first time), unless they failed before being applied because of an I/O error
+    ///
+    /// `fingerprint` is the one `t` was logged with, if it's known
+    fn deduplicated<F, R>(
+        &mut self,
+        t: Transaction,
+        fingerprint: Option<Fingerprint>,
+        f: F,
+    ) -> Result<R, TransactionEngineError>
+    where
+        F: FnOnce(&mut Self, Transaction) -> Result<R, TransactionEngineError>,
+    {
+        let deduplicator = match &self.deduplicator {
+            Some(deduplicator) => deduplicator.clone(),
+            None => return f(self, t),
+        };
+
+        let fingerprint = fingerprint.unwrap_or_else(|| Fingerprint::of(&t));
+        if !lock(&deduplicator).insert(fingerprint) {
+            return Err(TransactionEngineError::Replay(t.transaction));
+        }
+        let result = f(self, t);
+        if matches!(
+            result,
+            Err(TransactionEngineError::WriteAheadLog(_) | TransactionEngineError::AccountStore(_))
+        ) {
+            lock(&deduplicator).remove(fingerprint);
+        }
+
+        result
+    }
+
+    /// Checks this engine's leg of a transfer between accounts kept by different engines
+    ///
+    /// Nothing is applied until the returned transfer is passed to `commit_transfer`, which should
+    /// only happen once the other engine has prepared its leg too (otherwise both should be passed
+    /// to `abort_transfer`). The engine mustn't process anything else in between.
+    ///
+    /// The debit leg is deduplicated, logged and claims the transaction ID the way `process` does,
+    /// so only the engine of the sending client logs the transfer
+    pub fn prepare_transfer(
+        &mut self,
+        t: Transaction,
+        leg: TransferLeg,
+    ) -> Result<PendingTransfer, TransactionEngineError> {
+        match leg {
+            TransferLeg::Debit => {
+                let fingerprint = self.fingerprint(&t);
+                self.deduplicated(t, fingerprint, |engine, t| {
+                    if let Some(wal) = &engine.wal {
+                        engine.wal_sequence = lock(wal).append(&t, fingerprint)?;
+                    }
+
+                    engine.prepare_debit(&t)
+                })
+            }
+            TransferLeg::Credit => self.prepare_leg(&t, leg),
+        }
+    }
+
+    /// Same as `prepare_transfer` for a transfer recovered from the write-ahead log
+    ///
+    /// Returns `None` if the record is already included in the state restored from a snapshot
+    pub fn prepare_replayed_transfer(
+        &mut self,
+        record: &WalRecord,
+        leg: TransferLeg,
+    ) -> Result<Option<PendingTransfer>, TransactionEngineError> {
+        if record.sequence <= self.wal_sequence {
+            return Ok(None);
+        }
+
+        self.wal_sequence = record.sequence;
+        match leg {
+            TransferLeg::Debit => self
+                .deduplicated(
+                    record.transaction.clone(),
+                    record.fingerprint,
+                    |engine, t| engine.prepare_debit(&t),
+                )
+                .map(Some),
+            TransferLeg::Credit => self.prepare_leg(&record.transaction, leg).map(Some),
+        }
+    }
+
+    /// Applies a leg of a transfer checked by `prepare_transfer`
+    pub fn commit_transfer(
+        &mut self,
+        transfer: PendingTransfer,
+    ) -> Result<(), TransactionEngineError> {
+        let client = transfer.client();
+        let events = match self.store.get_mut(client)? {
+            Some(account) => {
+                let events = account.commit_transfer(transfer);
+                self.store.mark_changed(client);
+
+                events
+            }
+            None => {
+                let mut account = self.open(client);
+                let events = account.commit_transfer(transfer);
+                self.store.upsert(account)?;
+
+                events
+            }
+        };
+        self.journal.append(client, events);
+
+        Ok(())
+    }
+
+    /// Clears the deposits and settles the disputes of every account that are due by the engine's
+    /// clock, as if each account had just seen a transaction
+    ///
+    /// Accounts only check their deadlines when a transaction is processed for them, so this
+    /// should be called before reporting balances of accounts that may have gone idle. Disputes
+    /// settled along the way are reported by `expired_disputes`
+    pub fn settle_due(&mut self) -> Result<(), TransactionEngineError> {
+        self.expired_disputes.clear();
+        let time = self.clock.now();
+        let clients = self
+            .store
+            .iter()
+            .map(|account| account.map(|account| account.client_id()))
+            .collect::<Result<Vec<ClientID>, _>>()?;
+        for client in clients {
+            let account = match self.store.get_mut(client)? {
+                Some(account) => account,
+                None => continue,
+            };
+            account.configure(&self.account_config);
+            let events = account.catch_up(time);
+            if events.is_empty() {
+                continue;
+            }
+            self.store.mark_changed(client);
+            self.expired_disputes
+                .extend(expired_disputes(client, &events));
+            self.journal.append(client, events);
+        }
+
+        Ok(())
+    }
+
+    /// Drops a leg of a transfer checked by `prepare_transfer`, e.g. because the other leg failed
+    pub fn abort_transfer(&mut self, transfer: PendingTransfer) {
+        if transfer.leg() == TransferLeg::Debit {
+            self.index().release(transfer.transaction());
+        }
+    }
+
+    fn process_transaction(&mut self, t: Transaction) -> Result<(), TransactionEngineError> {
+        self.expired_disputes.clear();
+        // Administrative transactions claim their own ID so they can be traced back later on
+        let claims_id = matches!(
+            t.tx_type,
+            TransactionType::Deposit
+                | TransactionType::Withdrawal
+                | TransactionType::Transfer
+                | TransactionType::Authorize
+        ) || t.tx_type.is_admin();
+        if claims_id {
+            self.reserve(&t)?;
+        }
+
+        let transaction_id = t.transaction;
+        let result = self.apply(t);
+        if claims_id && result.is_err() {
+            self.index().release(transaction_id);
+        }
+
+        result
+    }
+
+    fn reserve(&self, t: &Transaction) -> Result<(), TransactionEngineError> {
+        self.index()
+            .reserve(
+                t.transaction,
+                IndexedTransaction {
+                    client: t.client,
+                    tx_type: t.tx_type,
+                },
+            )
+            .map_err(|existing| {
+                TransactionEngineError::DuplicateTransaction(
+                    t.transaction,
+                    existing.tx_type,
+                    existing.client,
+                )
+            })
+    }
+
+    fn apply(&mut self, t: Transaction) -> Result<(), TransactionEngineError> {
+        // Check ownership before touching the accounts so that a dispute naming someone else's
+        // transaction doesn't create an account for the claimed client
+        if let Some(owner) = self.transaction(t.transaction).map(|entry| entry.client) {
+            if owner != t.client {
+                return Err(TransactionEngineError::ClientMismatch {
+                    claimed: t.client,
+                    owner,
+                    tx: t.transaction,
+                });
+            }
+        }
+
+        if t.tx_type == TransactionType::Transfer {
+            // Both legs are checked before either is applied, so a failed transfer leaves both
+            // accounts untouched
+            let debit = self.prepare_leg(&t, TransferLeg::Debit)?;
+            let credit = self.prepare_leg(&t, TransferLeg::Credit)?;
+            self.commit_transfer(debit)?;
+            return self.commit_transfer(credit);
+        }
+
+        let currency = self.currency(&t);
+        if let (
+            TransactionType::Deposit | TransactionType::Withdrawal | TransactionType::Authorize,
+            Some(amount),
+        ) = (t.tx_type, t.amount)
+        {
+            self.precision
+                .check(&currency, amount)
+                .map_err(|e| TransactionEngineError::InvalidAmount(currency.clone(), e))?;
+        }
+        // Records logged before transactions were stamped are replayed at the current time
+        let time = t.timestamp.unwrap_or_else(|| self.clock.now());
+        let clears_at = match t
+            .clearing_period
+            .or_else(|| self.clearing_periods.get(&currency).copied())
+        {
+            Some(period) if t.tx_type == TransactionType::Deposit && period > 0 => {
+                Some(time.saturating_add(period))
+            }
+            _ => None,
+        };
+        let (mut events, result) = match self.store.get_mut(t.client)? {
+            Some(account) => {
+                account.configure(&self.account_config);
+                // Holds expire even if the transaction itself fails
+                let events = account.advance(time);
+                let result = Self::update_account(
+                    account,
+                    &t,
+                    &currency,
+                    &self.precision,
+                    self.hold_expiry,
+                    clears_at,
+                );
+                if result.is_ok() {
+                    account.count_transaction();
+                }
+                // A failed transaction leaves the account as it was, unless something expired
+                if result.is_ok() || !events.is_empty() {
+                    self.store.mark_changed(t.client);
+                }
+                (events, result)
+            }
+            None => {
+                if t.tx_type.is_admin()
+                    || (self.account_creation == AccountCreation::DepositsOnly
+                        && t.tx_type != TransactionType::Deposit)
+                {
+                    return Err(TransactionEngineError::UnknownAccount(t.client));
+                }
+
+                let mut account = self.open(t.client);
+                let events = account.advance(time);
+                let result = Self::update_account(
+                    &mut account,
+                    &t,
+                    &currency,
+                    &self.precision,
+                    self.hold_expiry,
+                    clears_at,
+                );
+                if result.is_ok() {
+                    account.count_transaction();
+                    self.store.upsert(account)?;
+                }
+                (events, result)
+            }
+        };
+        self.expired_disputes = expired_disputes(t.client, &events);
+        let result = result.map(|applied| events.extend(applied));
+        self.journal.append(t.client, events);
+
+        result
+    }
+
+    /// Claims the ID of a transfer and checks its debit leg
+    fn prepare_debit(
+        &mut self,
+        t: &Transaction,
+    ) -> Result<PendingTransfer, TransactionEngineError> {
+        self.reserve(t)?;
+        let result = self.prepare_leg(t, TransferLeg::Debit);
+        if result.is_err() {
+            self.index().release(t.transaction);
+        }
+
+        result
+    }
+
+    /// Checks one leg of a transfer against its account, without applying it
+    ///
+    /// The sending account has to exist, the receiving one is opened by the transfer if the
+    /// account creation rules allow it
+    fn prepare_leg(
+        &mut self,
+        t: &Transaction,
+        leg: TransferLeg,
+    ) -> Result<PendingTransfer, TransactionEngineError> {
+        let to = t.to.ok_or(TransactionEngineError::MissingRecipient)?;
+        if to == t.client {
+            return Err(TransactionEngineError::SelfTransfer(to));
+        }
+        let amount = t.amount.ok_or(TransactionEngineError::MissingAmount)?;
+        let currency = self.currency(t);
+        self.precision
+            .check(&currency, amount)
+            .map_err(|e| TransactionEngineError::InvalidAmount(currency.clone(), e))?;
+
+        let (client, counterparty) = match leg {
+            TransferLeg::Debit => (t.client, to),
+            TransferLeg::Credit => (to, t.client),
+        };
+        // Preparing a leg doesn't change the account, nor depend on the engine's configuration
+        let transfer = match self.store.get(client)? {
+            Some(account) => {
+                account.prepare_transfer(t.transaction, leg, counterparty, &currency, amount)
+            }
+            None if leg == TransferLeg::Credit
+                && self.account_creation == AccountCreation::OnSuccess =>
+            {
+                self.open(client).prepare_transfer(
+                    t.transaction,
+                    leg,
+                    counterparty,
+                    &currency,
+                    amount,
+                )
+            }
+            None => return Err(TransactionEngineError::UnknownAccount(client)),
+        };
+
+        transfer.map_err(|e| TransactionEngineError::AccountUpdate(client, e))
+    }
+
+    /// Asset `t` is in
+    fn currency(&self, t: &Transaction) -> Currency {
+        t.currency
+            .clone()
+            .unwrap_or_else(|| self.default_currency.clone())
+    }
+
+    /// Account for a client seen for the first time
+    fn open(&self, client: ClientID) -> Account {
+        self.configure(Account::new(client))
+            .with_constraints(self.balance_constraints)
+    }
+
+    fn configure(&self, account: Account) -> Account {
+        account.with_config(self.account_config.clone())
+    }
+
+    fn update_account(
+        account: &mut Account,
+        t: &Transaction,
+        currency: &Currency,
+        precision: &PrecisionTable,
+        hold_expiry: Option<u64>,
+        clears_at: Option<Timestamp>,
+    ) -> Result<Vec<AccountEvent>, TransactionEngineError> {
+        // Amounts of transactions referencing another one are in its asset, so they're checked
+        // against it
+        let referenced = match t.tx_type {
+            TransactionType::Dispute
+            | TransactionType::Resolve
+            | TransactionType::Chargeback
+            | TransactionType::Refund => account.disputable_currency(t.transaction),
+            TransactionType::Capture => account.hold_currency(t.transaction),
+            _ => None,
+        };
+        if let (Some(amount), Some(currency)) = (t.amount, referenced) {
+            precision
+                .check(currency, amount)
+                .map_err(|e| TransactionEngineError::InvalidAmount(currency.clone(), e))?;
+        }
+
         match t.tx_type {
+            TransactionType::Deposit if clears_at.is_some() => account.deposit_uncleared(
+                t.transaction,
+                currency,
+                t.amount.ok_or(TransactionEngineError::MissingAmount)?,
+                clears_at,
+            ),
             TransactionType::Deposit => account.deposit(
                 t.transaction,
+                currency,
                 t.amount.ok_or(TransactionEngineError::MissingAmount)?,
             ),
-            TransactionType::Withdrawal => {
-                account.withdraw(t.amount.ok_or(TransactionEngineError::MissingAmount)?)
-            }
-            TransactionType::Dispute => account.dispute(t.transaction),
-            TransactionType::Resolve => account.resolve(t.transaction),
-            TransactionType::Chargeback => account.chargeback(t.transaction),
+            TransactionType::Withdrawal => account.withdraw(
+                t.transaction,
+                currency,
+                t.amount.ok_or(TransactionEngineError::MissingAmount)?,
+            ),
+            TransactionType::Dispute => account.dispute(t.transaction, t.amount),
+            TransactionType::Resolve => account.resolve(t.transaction, t.amount),
+            TransactionType::Chargeback => account.chargeback(t.transaction, t.amount),
+            TransactionType::Refund => account.refund(t.transaction, t.amount),
+            TransactionType::Clear => account.clear(t.transaction),
+            // Without an explicit level a lock freezes the account the same way a chargeback does
+            TransactionType::Lock => account.lock(
+                t.reason
+                    .clone()
+                    .ok_or(TransactionEngineError::MissingReason)?,
+                t.level.unwrap_or(FreezeLevel::WithdrawalsBlocked),
+            ),
+            TransactionType::Unlock => account.unlock(
+                t.reason
+                    .clone()
+                    .ok_or(TransactionEngineError::MissingReason)?,
+            ),
+            TransactionType::Authorize => account.authorize(
+                t.transaction,
+                currency,
+                t.amount.ok_or(TransactionEngineError::MissingAmount)?,
+                hold_expiry,
+            ),
+            TransactionType::Capture => account.capture(t.transaction, t.amount),
+            TransactionType::Void => account.void(t.transaction),
+            TransactionType::Represent => account.represent(t.transaction),
+            TransactionType::PreArbitration => account.pre_arbitration(t.transaction),
+            TransactionType::ReverseChargeback => account.reverse_chargeback(t.transaction),
+            TransactionType::Transfer => unreachable!("Transfers involve two accounts"),
         }
-        .map_err(|e| TransactionEngineError::AccountUpdate(t.client, e))?;
+        .map_err(|e| TransactionEngineError::AccountUpdate(t.client, e))
+    }
 
-        Ok(())
+    fn index(&self) -> MutexGuard<'_, TransactionIndex> {
+        // The index is only mutated through `reserve` and `release` which can't leave it
+        // in an inconsistent state, so it's safe to keep using it after a panic elsewhere
+        lock(&self.index)
     }
 }
 
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Picks the disputes that expired for `client` out of the events of one of its accounts
+fn expired_disputes(client: ClientID, events: &[AccountEvent]) -> Vec<ExpiredDispute> {
+    events
+        .iter()
+        .filter_map(|event| match event {
+            AccountEvent::DisputeExpired {
+                transaction,
+                action,
+                ..
+            } => Some(ExpiredDispute {
+                client,
+                transaction: *transaction,
+                action: *action,
+            }),
+            _ => None,
+        })
+        .collect()
+}
+
 impl Default for TransactionEngine {
     fn default() -> Self {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::balance::ConstraintViolation;
+    use crate::clock::ManualClock;
+    use crate::deduplicator::Deduplicator;
+    use crate::disk_account_store::DiskAccountStore;
+    use crate::dispute_expiry::DisputeDuration;
+    use crate::dispute_policy::WithdrawalsDisputable;
+    use crate::funds::Funds;
+    use crate::wal::WriteAheadLog;
+    use rust_decimal_macros::dec;
+    use std::num::NonZeroUsize;
+
+    fn transaction(
+        tx_type: TransactionType,
+        client: ClientID,
+        transaction: TransactionID,
+        amount: Option<Funds>,
+    ) -> Transaction {
+        Transaction {
+            tx_type,
+            client,
+            transaction,
+            amount,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_duplicate_id_across_clients_and_types() {
+        let mut engine = TransactionEngine::new();
+        engine
+            .process(transaction(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Funds::new(dec!(2.0))),
+            ))
+            .expect("Deposit to succeed");
+
+        assert!(matches!(
+            engine.process(transaction(
+                TransactionType::Deposit,
+                2,
+                1,
+                Some(Funds::new(dec!(1.0))),
+            )),
+            Err(TransactionEngineError::DuplicateTransaction(
+                1,
+                TransactionType::Deposit,
+                1
+            )),
+        ));
+        assert!(matches!(
+            engine.process(transaction(
+                TransactionType::Withdrawal,
+                1,
+                1,
+                Some(Funds::new(dec!(1.0))),
+            )),
+            Err(TransactionEngineError::DuplicateTransaction(
+                1,
+                TransactionType::Deposit,
+                1
+            )),
+        ));
+        assert_eq!(
+            engine.accounts()[&1]
+                .balance(&Currency::default())
+                .available(),
+            Funds::new(dec!(2.0))
+        );
+    }
+
+    #[test]
+    fn test_failed_transaction_releases_id() {
+        let mut engine = TransactionEngine::new();
+        assert!(engine
+            .process(transaction(
+                TransactionType::Withdrawal,
+                1,
+                1,
+                Some(Funds::new(dec!(1.0))),
+            ))
+            .is_err());
+        assert_eq!(engine.transaction(1), None);
+
+        engine
+            .process(transaction(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Funds::new(dec!(1.0))),
+            ))
+            .expect("Deposit to succeed");
+        assert_eq!(
+            engine.transaction(1),
+            Some(IndexedTransaction {
+                client: 1,
+                tx_type: TransactionType::Deposit,
+            })
+        );
+    }
+
+    #[test]
+    fn test_dispute_other_clients_transaction() {
+        let mut engine = TransactionEngine::new();
+        engine
+            .process(transaction(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Funds::new(dec!(1.0))),
+            ))
+            .expect("Deposit to succeed");
+
+        for tx_type in [
+            TransactionType::Dispute,
+            TransactionType::Resolve,
+            TransactionType::Chargeback,
+        ] {
+            assert!(matches!(
+                engine.process(transaction(tx_type, 2, 1, None)),
+                Err(TransactionEngineError::ClientMismatch {
+                    claimed: 2,
+                    owner: 1,
+                    tx: 1,
+                }),
+            ));
+        }
+        assert!(!engine.accounts().contains_key(&2));
+        assert_eq!(
+            engine.accounts()[&1].balance(&Currency::default()).held(),
+            Funds::new(dec!(0.0))
+        );
+    }
+
+    #[test]
+    fn test_failed_transactions_do_not_create_accounts() {
+        let mut engine = TransactionEngine::new();
+        assert!(engine
+            .process(transaction(
+                TransactionType::Withdrawal,
+                1,
+                1,
+                Some(Funds::new(dec!(1.0))),
+            ))
+            .is_err());
+        assert!(engine
+            .process(transaction(TransactionType::Dispute, 2, 2, None))
+            .is_err());
+        assert!(engine
+            .process(transaction(TransactionType::Deposit, 3, 3, None))
+            .is_err());
+        assert!(engine.accounts().is_empty());
+    }
+
+    #[test]
+    fn test_deposits_only_account_creation() {
+        let mut engine =
+            TransactionEngine::new().with_account_creation(AccountCreation::DepositsOnly);
+        assert!(matches!(
+            engine.process(transaction(
+                TransactionType::Withdrawal,
+                1,
+                1,
+                Some(Funds::new(dec!(0.0))),
+            )),
+            Err(TransactionEngineError::UnknownAccount(1)),
+        ));
+        assert!(engine.accounts().is_empty());
+
+        engine
+            .process(transaction(
+                TransactionType::Deposit,
+                1,
+                2,
+                Some(Funds::new(dec!(1.0))),
+            ))
+            .expect("Deposit to succeed");
+        engine
+            .process(transaction(
+                TransactionType::Withdrawal,
+                1,
+                3,
+                Some(Funds::new(dec!(0.5))),
+            ))
+            .expect("Withdrawal to succeed");
+        assert_eq!(
+            engine.accounts()[&1]
+                .balance(&Currency::default())
+                .available(),
+            Funds::new(dec!(0.5))
+        );
+    }
+
+    #[test]
+    fn test_shared_index() {
+        let index = TransactionIndex::shared();
+        let mut first = TransactionEngine::with_index(index.clone());
+        let mut second = TransactionEngine::with_index(index);
+
+        first
+            .process(transaction(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Funds::new(dec!(1.0))),
+            ))
+            .expect("Deposit to succeed");
+        assert!(matches!(
+            second.process(transaction(
+                TransactionType::Deposit,
+                2,
+                1,
+                Some(Funds::new(dec!(1.0))),
+            )),
+            Err(TransactionEngineError::DuplicateTransaction(..)),
+        ));
+    }
+
+    #[test]
+    fn test_unlock() {
+        let mut engine = TransactionEngine::new();
+        for t in [
+            transaction(TransactionType::Deposit, 1, 1, Some(Funds::new(dec!(2.0)))),
+            transaction(TransactionType::Deposit, 1, 2, Some(Funds::new(dec!(1.0)))),
+            transaction(TransactionType::Dispute, 1, 1, None),
+            transaction(TransactionType::Chargeback, 1, 1, None),
+        ] {
+            engine.process(t).expect("Transaction to succeed");
+        }
+        assert!(engine.accounts()[&1].is_frozen());
+
+        assert!(matches!(
+            engine.process(transaction(TransactionType::Unlock, 1, 3, None)),
+            Err(TransactionEngineError::MissingReason),
+        ));
+        engine
+            .process(Transaction {
+                reason: Some("Chargeback reversed".to_string()),
+                ..transaction(TransactionType::Unlock, 1, 3, None)
+            })
+            .expect("Unlock to succeed");
+        assert!(!engine.accounts()[&1].is_frozen());
+        assert_eq!(
+            engine.transaction(3),
+            Some(IndexedTransaction {
+                client: 1,
+                tx_type: TransactionType::Unlock,
+            })
+        );
+    }
+
+    #[test]
+    fn test_lock_unknown_account() {
+        let mut engine = TransactionEngine::new();
+        assert!(matches!(
+            engine.process(Transaction {
+                reason: Some("Suspicious activity".to_string()),
+                ..transaction(TransactionType::Lock, 1, 1, None)
+            }),
+            Err(TransactionEngineError::UnknownAccount(1)),
+        ));
+        assert!(engine.accounts().is_empty());
+    }
+
+    #[test]
+    fn test_journal() {
+        let mut engine = TransactionEngine::new().with_journal(Journal::new());
+        for t in [
+            transaction(TransactionType::Deposit, 1, 1, Some(Funds::new(dec!(2.0)))),
+            transaction(
+                TransactionType::Withdrawal,
+                1,
+                2,
+                Some(Funds::new(dec!(3.0))),
+            ),
+            transaction(TransactionType::Deposit, 2, 3, Some(Funds::new(dec!(1.0)))),
+            transaction(TransactionType::Dispute, 1, 1, None),
+        ] {
+            let _ = engine.process(t);
+        }
+
+        assert_eq!(
+            engine
+                .journal_mut()
+                .drain()
+                .map(|entry| (entry.sequence, entry.client, entry.event.name()))
+                .collect::<Vec<_>>(),
+            vec![
+                (0, 1, "deposited"),
+                (1, 2, "deposited"),
+                (2, 1, "dispute-opened"),
+            ],
+        );
+        assert!(engine.journal().is_empty());
+
+        // Nothing is recorded unless a journal is given
+        let mut engine = TransactionEngine::new();
+        engine
+            .process(transaction(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Funds::new(dec!(2.0))),
+            ))
+            .expect("Deposit to succeed");
+        assert!(engine.journal().is_empty());
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        let mut engine =
+            TransactionEngine::new().with_dispute_policy(Arc::new(WithdrawalsDisputable));
+        for t in [
+            transaction(TransactionType::Deposit, 1, 1, Some(Funds::new(dec!(2.0)))),
+            transaction(
+                TransactionType::Withdrawal,
+                1,
+                2,
+                Some(Funds::new(dec!(1.0))),
+            ),
+            transaction(TransactionType::Dispute, 1, 1, None),
+        ] {
+            engine.process(t).expect("Transaction to succeed");
+        }
+
+        let mut restored =
+            TransactionEngine::new().with_dispute_policy(Arc::new(WithdrawalsDisputable));
+        restored
+            .restore(engine.snapshot().expect("Snapshot to succeed"))
+            .expect("Restore to succeed");
+        assert!(matches!(
+            restored.process(transaction(
+                TransactionType::Deposit,
+                2,
+                2,
+                Some(Funds::new(dec!(1.0)))
+            )),
+            Err(TransactionEngineError::DuplicateTransaction(..)),
+        ));
+        restored
+            .process(transaction(TransactionType::Resolve, 1, 1, None))
+            .expect("Resolve to succeed");
+        restored
+            .process(transaction(TransactionType::Dispute, 1, 2, None))
+            .expect("Withdrawal dispute to succeed");
+        assert_eq!(
+            restored.accounts()[&1]
+                .balance(&Currency::default())
+                .available(),
+            Funds::new(dec!(1.0))
+        );
+        assert_eq!(
+            restored.accounts()[&1].balance(&Currency::default()).held(),
+            Funds::new(dec!(1.0))
+        );
+    }
+
+    #[test]
+    fn test_write_ahead_log_recovery() {
+        let path = std::env::temp_dir().join(format!("txk-engine-{}.wal", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let (wal, _) = WriteAheadLog::open(&path).expect("Open to succeed");
+        let mut engine = TransactionEngine::new().with_write_ahead_log(wal.shared());
+        engine
+            .process(transaction(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Funds::new(dec!(2.0))),
+            ))
+            .expect("Deposit to succeed");
+        let snapshot = engine.snapshot().expect("Snapshot to succeed");
+        engine
+            .process(transaction(
+                TransactionType::Withdrawal,
+                1,
+                2,
+                Some(Funds::new(dec!(0.5))),
+            ))
+            .expect("Withdrawal to succeed");
+        engine
+            .process(transaction(TransactionType::Dispute, 1, 1, None))
+            .expect("Dispute to succeed");
+        // Simulate a crash by dropping the engine without saving a new snapshot
+        drop(engine);
+
+        let (wal, tail) = WriteAheadLog::open(&path).expect("Open to succeed");
+        assert_eq!(tail.len(), 3);
+        let mut recovered = TransactionEngine::new();
+        recovered.restore(snapshot).expect("Restore to succeed");
+        for record in tail {
+            recovered.replay(record).expect("Replay to succeed");
+        }
+        let mut recovered = recovered.with_write_ahead_log(wal.shared());
+        assert_eq!(
+            recovered.accounts()[&1]
+                .balance(&Currency::default())
+                .available(),
+            Funds::new(dec!(-0.5))
+        );
+        assert_eq!(
+            recovered.accounts()[&1]
+                .balance(&Currency::default())
+                .held(),
+            Funds::new(dec!(2.0))
+        );
+
+        recovered
+            .process(transaction(TransactionType::Resolve, 1, 1, None))
+            .expect("Resolve to succeed");
+        assert_eq!(
+            recovered
+                .snapshot()
+                .expect("Snapshot to succeed")
+                .wal_sequence(),
+            4
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_write_ahead_log_replays_rejected() {
+        let path =
+            std::env::temp_dir().join(format!("txk-engine-{}-dedup.wal", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let deposit = || transaction(TransactionType::Deposit, 1, 1, Some(Funds::new(dec!(10.0))));
+        let dispute = || Transaction {
+            amount: Some(Funds::new(dec!(4.0))),
+            ..transaction(TransactionType::Dispute, 1, 1, None)
+        };
+
+        let (wal, _) = WriteAheadLog::open(&path).expect("Open to succeed");
+        let mut engine = TransactionEngine::new()
+            .with_write_ahead_log(wal.shared())
+            .with_deduplicator(Deduplicator::new(10).shared())
+            .with_clock(Arc::new(ManualClock::new(100)));
+        engine.process(deposit()).expect("Deposit to succeed");
+        engine.process(dispute()).expect("Dispute to succeed");
+        drop(engine);
+
+        // Recover from the log alone, then run the same unstamped input again
+        let (wal, tail) = WriteAheadLog::open(&path).expect("Open to succeed");
+        let mut recovered =
+            TransactionEngine::new().with_deduplicator(Deduplicator::new(10).shared());
+        for record in tail {
+            recovered.replay(record).expect("Replay to succeed");
+        }
+        let mut recovered = recovered.with_write_ahead_log(wal.shared());
+        for t in [deposit(), dispute()] {
+            assert!(matches!(
+                recovered.process(t),
+                Err(TransactionEngineError::Replay(1))
+            ));
+        }
+        let balance = recovered.accounts()[&1].balance(&Currency::default());
+        assert_eq!(balance.available(), Funds::new(dec!(6.0)));
+        assert_eq!(balance.held(), Funds::new(dec!(4.0)));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_disk_account_store() {
+        let path = std::env::temp_dir().join(format!("txk-engine-{}.accounts", std::process::id()));
+        let store = DiskAccountStore::create(&path, NonZeroUsize::new(1).expect("Non zero"))
+            .expect("Create to succeed");
+        let mut engine = TransactionEngine::new()
+            .with_store(store)
+            .with_dispute_policy(Arc::new(WithdrawalsDisputable));
+        for t in [
+            transaction(TransactionType::Deposit, 1, 1, Some(Funds::new(dec!(2.0)))),
+            transaction(
+                TransactionType::Withdrawal,
+                1,
+                2,
+                Some(Funds::new(dec!(1.0))),
+            ),
+            // Evicts client 1 from the cache
+            transaction(TransactionType::Deposit, 2, 3, Some(Funds::new(dec!(1.0)))),
+            // Only allowed if the dispute policy is applied to the account loaded from disk
+            transaction(TransactionType::Dispute, 1, 2, None),
+        ] {
+            engine.process(t).expect("Transaction to succeed");
+        }
+
+        let account = engine
+            .store_mut()
+            .get(1)
+            .expect("Get to succeed")
+            .expect("Account to exist");
+        assert_eq!(
+            account.balance(&Currency::default()).held(),
+            Funds::new(dec!(1.0))
+        );
+        assert_eq!(
+            engine
+                .snapshot()
+                .expect("Snapshot to succeed")
+                .accounts()
+                .len(),
+            2
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_failed_transactions_not_written_back() {
+        let path =
+            std::env::temp_dir().join(format!("txk-engine-failed-{}.accounts", std::process::id()));
+        let store = DiskAccountStore::create(&path, NonZeroUsize::new(1).expect("Non zero"))
+            .expect("Create to succeed");
+        let mut engine = TransactionEngine::new().with_store(store);
+        engine
+            .process(transaction(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Funds::new(dec!(1.0))),
+            ))
+            .expect("Deposit to succeed");
+        engine
+            .process(transaction(
+                TransactionType::Deposit,
+                2,
+                2,
+                Some(Funds::new(dec!(1.0))),
+            ))
+            .expect("Deposit to succeed");
+        engine.store_mut().flush().expect("Flush to succeed");
+        let before = std::fs::metadata(&path).expect("Store to exist").len();
+
+        // Alternating between the accounts evicts them on every transaction
+        for (id, client) in [(3, 1), (4, 2), (5, 1)] {
+            assert!(engine
+                .process(transaction(
+                    TransactionType::Withdrawal,
+                    client,
+                    id,
+                    Some(Funds::new(dec!(5.0))),
+                ))
+                .is_err());
+        }
+        engine.store_mut().flush().expect("Flush to succeed");
+        assert_eq!(
+            std::fs::metadata(&path).expect("Store to exist").len(),
+            before
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_replays_rejected() {
+        let mut engine = TransactionEngine::new().with_deduplicator(Deduplicator::new(10).shared());
+        let deposit = || transaction(TransactionType::Deposit, 1, 1, Some(Funds::new(dec!(2.0))));
+        let withdrawal = || {
+            transaction(
+                TransactionType::Withdrawal,
+                1,
+                2,
+                Some(Funds::new(dec!(1.0))),
+            )
+        };
+        let dispute = || transaction(TransactionType::Dispute, 1, 1, None);
+
+        engine.process(deposit()).expect("Deposit to succeed");
+        engine.process(withdrawal()).expect("Withdrawal to succeed");
+        engine.process(dispute()).expect("Dispute to succeed");
+        for t in [deposit(), withdrawal(), dispute()] {
+            assert!(matches!(
+                engine.process(t),
+                Err(TransactionEngineError::Replay(_))
+            ));
+        }
+        assert_eq!(
+            engine.accounts()[&1]
+                .balance(&Currency::default())
+                .available(),
+            Funds::new(dec!(-1.0))
+        );
+
+        // Transactions with the same idempotency key are replays even if their contents differ
+        let keyed = |amount| Transaction {
+            idempotency_key: Some("deposit-3".to_string()),
+            ..transaction(TransactionType::Deposit, 1, 3, Some(Funds::new(amount)))
+        };
+        engine
+            .process(keyed(dec!(1.0)))
+            .expect("Deposit to succeed");
+        assert!(matches!(
+            engine.process(keyed(dec!(5.0))),
+            Err(TransactionEngineError::Replay(3))
+        ));
+
+        // Replays are still detected after restoring a snapshot
+        let mut restored =
+            TransactionEngine::new().with_deduplicator(Deduplicator::new(10).shared());
+        restored
+            .restore(engine.snapshot().expect("Snapshot to succeed"))
+            .expect("Restore to succeed");
+        assert!(matches!(
+            restored.process(withdrawal()),
+            Err(TransactionEngineError::Replay(2))
+        ));
+    }
+
+    #[test]
+    fn test_repeated_partial_disputes() {
+        let mut engine = TransactionEngine::new().with_deduplicator(Deduplicator::new(10).shared());
+        engine
+            .process(transaction(
+                TransactionType::Deposit,
+                1,
+                7,
+                Some(Funds::new(dec!(20.0))),
+            ))
+            .expect("Deposit to succeed");
+        let dispute = |idempotency_key: Option<&str>, timestamp| Transaction {
+            idempotency_key: idempotency_key.map(str::to_string),
+            timestamp,
+            ..transaction(TransactionType::Dispute, 1, 7, Some(Funds::new(dec!(5.0))))
+        };
+
+        // Identical rows that are told apart by their idempotency key or timestamp all apply
+        for t in [
+            dispute(Some("dispute-7-a"), None),
+            dispute(Some("dispute-7-b"), None),
+            dispute(None, Some(100)),
+            dispute(None, Some(200)),
+        ] {
+            engine.process(t).expect("Dispute to succeed");
+        }
+        assert_eq!(
+            engine.accounts()[&1].balance(&Currency::default()).held(),
+            Funds::new(dec!(20.0))
+        );
+        // Without either they're the same transaction
+        assert!(matches!(
+            engine.process(dispute(None, Some(200))),
+            Err(TransactionEngineError::Replay(7))
+        ));
+    }
+
+    #[test]
+    fn test_multiple_assets() {
+        let mut engine = TransactionEngine::new().with_default_currency(Currency::new("EUR"));
+        let in_currency = |t: Transaction, currency: &str| Transaction {
+            currency: Some(Currency::new(currency)),
+            ..t
+        };
+        for t in [
+            transaction(TransactionType::Deposit, 1, 1, Some(Funds::new(dec!(2.0)))),
+            in_currency(
+                transaction(TransactionType::Deposit, 1, 2, Some(Funds::new(dec!(0.5)))),
+                "btc",
+            ),
+            // The currency of a dispute is ignored, it applies to the asset of the deposit
+            in_currency(transaction(TransactionType::Dispute, 1, 2, None), "EUR"),
+        ] {
+            engine.process(t).expect("Transaction to succeed");
+        }
+
+        let account = &engine.accounts()[&1];
+        assert_eq!(
+            account.balance(&Currency::new("EUR")).available(),
+            Funds::new(dec!(2.0))
+        );
+        assert_eq!(
+            account.balance(&Currency::new("BTC")).held(),
+            Funds::new(dec!(0.5))
+        );
+        assert_eq!(
+            account.balance(&Currency::new("BTC")).available(),
+            Funds::new(0)
+        );
+    }
+
+    #[test]
+    fn test_precision() {
+        let mut engine = TransactionEngine::new();
+        assert!(matches!(
+            engine.process(transaction(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Funds::new(dec!(1.00001)))
+            )),
+            Err(TransactionEngineError::InvalidAmount(
+                _,
+                FundsOpError::ExcessPrecision {
+                    scale: 5,
+                    max_scale: 4
+                }
+            ))
+        ));
+        assert!(engine.accounts().is_empty());
+
+        engine
+            .process(Transaction {
+                currency: Some(Currency::new("BTC")),
+                ..transaction(
+                    TransactionType::Deposit,
+                    1,
+                    1,
+                    Some(Funds::new(dec!(0.00000001))),
+                )
+            })
+            .expect("Deposit to succeed");
+    }
+
+    fn transfer(from: ClientID, to: ClientID, id: TransactionID, amount: Funds) -> Transaction {
+        Transaction {
+            to: Some(to),
+            ..transaction(TransactionType::Transfer, from, id, Some(amount))
+        }
+    }
+
+    #[test]
+    fn test_transfer() {
+        let mut engine = TransactionEngine::new();
+        engine
+            .process(transaction(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Funds::new(dec!(2.0))),
+            ))
+            .expect("Deposit to succeed");
+        engine
+            .process(transfer(1, 2, 2, Funds::new(dec!(1.5))))
+            .expect("Transfer to succeed");
+        let usd = Currency::default();
+        assert_eq!(
+            engine.accounts()[&1].balance(&usd).available(),
+            Funds::new(dec!(0.5))
+        );
+        // The transfer opened the receiving account
+        assert_eq!(
+            engine.accounts()[&2].balance(&usd).available(),
+            Funds::new(dec!(1.5))
+        );
+
+        // Neither account changes when one of the legs fails
+        assert!(matches!(
+            engine.process(transfer(1, 2, 3, Funds::new(dec!(1.0)))),
+            Err(TransactionEngineError::AccountUpdate(
+                1,
+                AccountUpdateError::ConstraintViolation(
+                    ConstraintViolation::OverdraftLimitExceeded(..)
+                )
+            ))
+        ));
+        engine
+            .process(Transaction {
+                reason: Some("fraud".to_string()),
+                level: Some(FreezeLevel::DepositsBlocked),
+                ..transaction(TransactionType::Lock, 2, 4, None)
+            })
+            .expect("Lock to succeed");
+        assert!(matches!(
+            engine.process(transfer(1, 2, 5, Funds::new(dec!(0.5)))),
+            Err(TransactionEngineError::AccountUpdate(
+                2,
+                AccountUpdateError::AccountIsFrozen
+            ))
+        ));
+        assert_eq!(
+            engine.accounts()[&1].balance(&usd).available(),
+            Funds::new(dec!(0.5))
+        );
+        assert_eq!(
+            engine.accounts()[&2].balance(&usd).available(),
+            Funds::new(dec!(1.5))
+        );
+        // Failed transfers don't claim their ID
+        assert!(engine.transaction(5).is_none());
+
+        assert!(matches!(
+            engine.process(transfer(1, 1, 6, Funds::new(dec!(0.5)))),
+            Err(TransactionEngineError::SelfTransfer(1))
+        ));
+        assert!(matches!(
+            engine.process(transaction(
+                TransactionType::Transfer,
+                1,
+                7,
+                Some(Funds::new(dec!(0.5)))
+            )),
+            Err(TransactionEngineError::MissingRecipient)
+        ));
+    }
+
+    #[test]
+    fn test_transfer_across_engines() {
+        let index = TransactionIndex::shared();
+        let mut sender = TransactionEngine::with_index(index.clone());
+        let mut receiver = TransactionEngine::with_index(index);
+        sender
+            .process(transaction(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Funds::new(dec!(2.0))),
+            ))
+            .expect("Deposit to succeed");
+        let usd = Currency::default();
+
+        let debit = sender
+            .prepare_transfer(transfer(1, 2, 2, Funds::new(dec!(1.5))), TransferLeg::Debit)
+            .expect("Debit to be prepared");
+        let credit = receiver
+            .prepare_transfer(
+                transfer(1, 2, 2, Funds::new(dec!(1.5))),
+                TransferLeg::Credit,
+            )
+            .expect("Credit to be prepared");
+        // Nothing is applied until the legs are committed
+        assert_eq!(
+            sender.accounts()[&1].balance(&usd).available(),
+            Funds::new(dec!(2.0))
+        );
+        assert!(receiver.accounts().is_empty());
+        sender.commit_transfer(debit).expect("Commit to succeed");
+        receiver.commit_transfer(credit).expect("Commit to succeed");
+        assert_eq!(
+            sender.accounts()[&1].balance(&usd).available(),
+            Funds::new(dec!(0.5))
+        );
+        assert_eq!(
+            receiver.accounts()[&2].balance(&usd).available(),
+            Funds::new(dec!(1.5))
+        );
+
+        // Aborting releases the ID claimed by the debit leg
+        let debit = sender
+            .prepare_transfer(transfer(1, 2, 3, Funds::new(dec!(0.5))), TransferLeg::Debit)
+            .expect("Debit to be prepared");
+        assert!(receiver.transaction(3).is_some());
+        sender.abort_transfer(debit);
+        assert!(receiver.transaction(3).is_none());
+        assert_eq!(
+            sender.accounts()[&1].balance(&usd).available(),
+            Funds::new(dec!(0.5))
+        );
+    }
+
+    #[test]
+    fn test_hold_expiry() {
+        let mut engine = TransactionEngine::new()
+            .with_hold_expiry(2)
+            .with_journal(Journal::new());
+        for t in [
+            transaction(TransactionType::Deposit, 1, 1, Some(Funds::new(dec!(5.0)))),
+            transaction(
+                TransactionType::Authorize,
+                1,
+                2,
+                Some(Funds::new(dec!(3.0))),
+            ),
+            transaction(TransactionType::Capture, 1, 2, Some(Funds::new(dec!(1.0)))),
+        ] {
+            engine.process(t).expect("Transaction to succeed");
+        }
+        let usd = Currency::default();
+        assert_eq!(
+            engine.accounts()[&1].balance(&usd).held(),
+            Funds::new(dec!(2.0))
+        );
+
+        // The hold expires before this transaction is applied, even though it fails
+        assert!(matches!(
+            engine.process(transaction(TransactionType::Void, 1, 2, None)),
+            Err(TransactionEngineError::AccountUpdate(
+                1,
+                AccountUpdateError::HoldNotOpen(2)
+            ))
+        ));
+        assert_eq!(
+            engine.accounts()[&1].balance(&usd).available(),
+            Funds::new(dec!(4.0))
+        );
+        assert_eq!(
+            engine.journal().iter().last().map(|e| e.event.name()),
+            Some("hold-expired")
+        );
+    }
+
+    #[test]
+    fn test_partial_dispute() {
+        let mut engine = TransactionEngine::new();
+        engine
+            .process(transaction(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Funds::new(dec!(2.0))),
+            ))
+            .expect("Deposit to succeed");
+        // Dispute amounts are checked against the precision of the deposit's asset
+        assert!(matches!(
+            engine.process(transaction(
+                TransactionType::Dispute,
+                1,
+                1,
+                Some(Funds::new(dec!(0.00001)))
+            )),
+            Err(TransactionEngineError::InvalidAmount(..))
+        ));
+        engine
+            .process(transaction(
+                TransactionType::Dispute,
+                1,
+                1,
+                Some(Funds::new(dec!(0.5))),
+            ))
+            .expect("Dispute to succeed");
+        let balance = engine.accounts()[&1].balance(&Currency::default());
+        assert_eq!(balance.available(), Funds::new(dec!(1.5)));
+        assert_eq!(balance.held(), Funds::new(dec!(0.5)));
+    }
+
+    #[test]
+    fn test_dispute_window() {
+        let clock = Arc::new(ManualClock::new(1_000));
+        let mut engine = TransactionEngine::new()
+            .with_clock(clock.clone())
+            .with_dispute_window(100);
+        engine
+            .process(transaction(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Funds::new(dec!(1.0))),
+            ))
+            .expect("Deposit to succeed");
+        // The timestamp column takes precedence over the clock
+        engine
+            .process(Transaction {
+                timestamp: Some(1_050),
+                ..transaction(TransactionType::Deposit, 1, 2, Some(Funds::new(dec!(1.0))))
+            })
+            .expect("Deposit to succeed");
+
+        clock.advance(101);
+        assert!(matches!(
+            engine.process(transaction(TransactionType::Dispute, 1, 1, None)),
+            Err(TransactionEngineError::AccountUpdate(
+                1,
+                AccountUpdateError::DisputeWindowClosed(1)
+            ))
+        ));
+        engine
+            .process(transaction(TransactionType::Dispute, 1, 2, None))
+            .expect("Dispute to succeed");
+    }
+
+    #[test]
+    fn test_dispute_expiry() {
+        let mut engine = TransactionEngine::new()
+            .with_dispute_expiry(DisputeExpiry::new(
+                DisputeDuration::Transactions(1),
+                ExpiryAction::Resolve,
+            ))
+            .with_journal(Journal::new());
+        engine
+            .process(transaction(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Funds::new(dec!(2.0))),
+            ))
+            .expect("Deposit to succeed");
+        engine
+            .process(transaction(TransactionType::Dispute, 1, 1, None))
+            .expect("Dispute to succeed");
+        assert!(engine.expired_disputes().is_empty());
+
+        // The dispute is settled before the withdrawal, which it would otherwise block
+        engine
+            .process(transaction(
+                TransactionType::Withdrawal,
+                1,
+                2,
+                Some(Funds::new(dec!(2.0))),
+            ))
+            .expect("Withdrawal to succeed");
+        assert_eq!(
+            engine.expired_disputes(),
+            &[ExpiredDispute {
+                client: 1,
+                transaction: 1,
+                action: ExpiryAction::Resolve,
+            }]
+        );
+        assert_eq!(
+            engine
+                .journal()
+                .iter()
+                .map(|e| e.event.name())
+                .collect::<Vec<_>>(),
+            vec![
+                "deposited",
+                "dispute-opened",
+                "dispute-expired",
+                "dispute-resolved",
+                "withdrew"
+            ]
+        );
+        assert_eq!(engine.accounts()[&1].expired_disputes(), 1);
+    }
+
+    #[test]
+    fn test_settle_due() {
+        let clock = Arc::new(ManualClock::new(1_000));
+        let mut engine = TransactionEngine::new()
+            .with_clock(clock.clone())
+            .with_dispute_expiry(DisputeExpiry::new(
+                DisputeDuration::Seconds(60),
+                ExpiryAction::Resolve,
+            ))
+            .with_journal(Journal::new());
+        engine
+            .process(transaction(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Funds::new(dec!(2.0))),
+            ))
+            .expect("Deposit to succeed");
+        engine
+            .process(transaction(TransactionType::Dispute, 1, 1, None))
+            .expect("Dispute to succeed");
+
+        clock.advance(59);
+        engine.settle_due().expect("Settling to succeed");
+        assert!(engine.expired_disputes().is_empty());
+
+        // The account sees no more transactions, but its dispute still expires
+        clock.advance(1);
+        engine.settle_due().expect("Settling to succeed");
+        assert_eq!(
+            engine.expired_disputes(),
+            &[ExpiredDispute {
+                client: 1,
+                transaction: 1,
+                action: ExpiryAction::Resolve,
+            }]
+        );
+        assert_eq!(
+            engine.accounts()[&1].balance(&Currency::default()).held(),
+            Funds::ZERO
+        );
+        assert_eq!(
+            engine.journal().iter().last().map(|e| e.event.name()),
+            Some("dispute-resolved")
+        );
+
+        engine.settle_due().expect("Settling to succeed");
+        assert!(engine.expired_disputes().is_empty());
+    }
+
+    #[test]
+    fn test_clearing_period() {
+        let clock = Arc::new(ManualClock::new(1_000));
+        let mut engine = TransactionEngine::new()
+            .with_clock(clock.clone())
+            .with_clearing_period(Currency::default(), 100);
+        engine
+            .process(transaction(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Funds::new(dec!(2.0))),
+            ))
+            .expect("Deposit to succeed");
+        // A deposit's own clearing period takes precedence over its asset's
+        engine
+            .process(Transaction {
+                clearing_period: Some(0),
+                ..transaction(TransactionType::Deposit, 1, 2, Some(Funds::new(dec!(1.0))))
+            })
+            .expect("Deposit to succeed");
+        engine
+            .process(transaction(
+                TransactionType::Deposit,
+                1,
+                3,
+                Some(Funds::new(dec!(4.0))),
+            ))
+            .expect("Deposit to succeed");
+        let balance = engine.accounts()[&1].balance(&Currency::default());
+        assert_eq!(balance.available(), Funds::new(dec!(1.0)));
+        assert_eq!(balance.pending(), Funds::new(dec!(6.0)));
+
+        engine
+            .process(transaction(TransactionType::Clear, 1, 3, None))
+            .expect("Clear to succeed");
+        clock.advance(100);
+        engine
+            .process(transaction(
+                TransactionType::Withdrawal,
+                1,
+                4,
+                Some(Funds::new(dec!(7.0))),
+            ))
+            .expect("Withdrawal to succeed");
+        let balance = engine.accounts()[&1].balance(&Currency::default());
+        assert_eq!(balance.available(), Funds::ZERO);
+        assert_eq!(balance.pending(), Funds::ZERO);
+
+        // Deposits clear without another transaction for the account once they're settled
+        engine
+            .process(transaction(
+                TransactionType::Deposit,
+                1,
+                5,
+                Some(Funds::new(dec!(3.0))),
+            ))
+            .expect("Deposit to succeed");
+        clock.advance(100);
+        engine.settle_due().expect("Settling to succeed");
+        let balance = engine.accounts()[&1].balance(&Currency::default());
+        assert_eq!(balance.available(), Funds::new(dec!(3.0)));
+        assert_eq!(balance.pending(), Funds::ZERO);
+    }
+
+    #[test]
+    fn test_deficit_policy() {
+        let mut engine = TransactionEngine::new().with_deficit_policy(DeficitPolicy::Reject);
+        engine
+            .process(transaction(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Funds::new(dec!(2.0))),
+            ))
+            .expect("Deposit to succeed");
+        engine
+            .process(transaction(
+                TransactionType::Withdrawal,
+                1,
+                2,
+                Some(Funds::new(dec!(1.0))),
+            ))
+            .expect("Withdrawal to succeed");
+        assert!(matches!(
+            engine.process(transaction(TransactionType::Dispute, 1, 1, None)),
+            Err(TransactionEngineError::AccountUpdate(
+                1,
+                AccountUpdateError::DisputeCausesDeficit(1)
+            ))
+        ));
+        let balance = engine.accounts()[&1].balance(&Currency::default());
+        assert_eq!(balance.available(), Funds::new(dec!(1.0)));
+        assert_eq!(balance.held(), Funds::ZERO);
+    }
+}
@@ -0,0 +1,118 @@
+use crate::transaction::ClientID;
+use crate::transaction::TransactionID;
+use crate::transaction::TransactionType;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+/// Who a transaction ID belongs to and what kind of transaction it identified
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub struct IndexedTransaction {
+    pub client: ClientID,
+    pub tx_type: TransactionType,
+}
+
+/// Index of every transaction ID that has been processed, across all clients
+///
+/// Only transactions that carry their own ID (deposits, withdrawals, transfers, authorizations
+/// and administrative transactions) are indexed. Transactions that act on an earlier one
+/// (e.g. disputes, refunds, captures or clears) reference its ID instead of claiming a new one.
+///
+/// IDs are reserved *before* a transaction is applied and released if applying it fails.
+/// This allows several engines (e.g. one per thread) to share the same index without
+/// holding a lock while the transaction is being processed.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct TransactionIndex {
+    transactions: HashMap<TransactionID, IndexedTransaction>,
+}
+
+pub type SharedTransactionIndex = Arc<Mutex<TransactionIndex>>;
+
+impl TransactionIndex {
+    pub fn new() -> Self {
+        Self {
+            transactions: HashMap::new(),
+        }
+    }
+
+    pub fn shared() -> SharedTransactionIndex {
+        Arc::new(Mutex::new(Self::new()))
+    }
+
+    pub fn get(&self, transaction_id: TransactionID) -> Option<IndexedTransaction> {
+        self.transactions.get(&transaction_id).copied()
+    }
+
+    /// Claims `transaction_id` for `entry`
+    ///
+    /// Fails with the existing entry if the ID has already been claimed
+    pub fn reserve(
+        &mut self,
+        transaction_id: TransactionID,
+        entry: IndexedTransaction,
+    ) -> Result<(), IndexedTransaction> {
+        match self.transactions.get(&transaction_id) {
+            Some(&existing) => Err(existing),
+            None => {
+                self.transactions.insert(transaction_id, entry);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn release(&mut self, transaction_id: TransactionID) {
+        self.transactions.remove(&transaction_id);
+    }
+
+    /// Adds all of `other`'s entries, overwriting existing ones with the same ID
+    pub fn extend(&mut self, other: TransactionIndex) {
+        self.transactions.extend(other.transactions);
+    }
+
+    pub fn len(&self) -> usize {
+        self.transactions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_reserve_duplicate() {
+        let mut index = TransactionIndex::new();
+        let deposit = IndexedTransaction {
+            client: 1,
+            tx_type: TransactionType::Deposit,
+        };
+        let withdrawal = IndexedTransaction {
+            client: 2,
+            tx_type: TransactionType::Withdrawal,
+        };
+
+        assert_eq!(index.reserve(1, deposit), Ok(()));
+        assert_eq!(index.reserve(1, withdrawal), Err(deposit));
+        assert_eq!(index.get(1), Some(deposit));
+    }
+
+    #[test]
+    fn test_release() {
+        let mut index = TransactionIndex::new();
+        let deposit = IndexedTransaction {
+            client: 1,
+            tx_type: TransactionType::Deposit,
+        };
+
+        index.reserve(1, deposit).expect("Reserve to succeed");
+        index.release(1);
+        assert_eq!(index.get(1), None);
+        assert!(index.is_empty());
+    }
+}
@@ -0,0 +1,295 @@
+use crate::deduplicator::Fingerprint;
+use crate::transaction::Transaction;
+use serde::Deserialize;
+use serde::Serialize;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::Mutex;
+use thiserror::Error;
+
+/// Size of the length and checksum prefix of every record
+const HEADER_LEN: usize = 8;
+
+#[derive(Debug, Error)]
+pub enum WalError {
+    #[error("Failed to access write-ahead log: {0}")]
+    Io(#[from] io::Error),
+    #[error("Failed to encode write-ahead log record: {0}")]
+    Encoding(#[from] serde_json::Error),
+}
+
+/// A transaction as recorded in the write-ahead log
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WalRecord {
+    /// Position of the record in the log. Keeps increasing after the log has been reset
+    pub sequence: u64,
+    pub transaction: Transaction,
+    /// Fingerprint of the transaction as it was received, before the engine stamped it, if it
+    /// was deduplicated
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<Fingerprint>,
+}
+
+/// Log of incoming transactions which are durably stored before they're applied
+///
+/// Together with a snapshot, this allows rebuilding the engine's state after a crash: the
+/// snapshot records the sequence number of the last transaction it includes, and the
+/// transactions after it are replayed from the log.
+///
+/// Each record is stored as its length and CRC32 checksum (little endian `u32`s) followed by
+/// the record as json. A crash in the middle of an append can leave a torn record at the end
+/// of the log. These are detected when opening the log and truncated, which is safe because the
+/// transaction in it was never applied.
+#[derive(Debug)]
+pub struct WriteAheadLog {
+    file: File,
+    /// Length of the valid records in the log
+    len: u64,
+    next_sequence: u64,
+}
+
+pub type SharedWriteAheadLog = Arc<Mutex<WriteAheadLog>>;
+
+impl WriteAheadLog {
+    /// Opens (or creates) the log at `path` and returns it with the records it already contains
+    ///
+    /// Everything from the first record that is incomplete or fails its checksum onwards is
+    /// truncated from the log
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<(Self, Vec<WalRecord>), WalError> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        let mut contents = vec![];
+        file.read_to_end(&mut contents)?;
+        let (records, valid_len) = Self::decode(&contents);
+        if valid_len < contents.len() {
+            file.set_len(valid_len as u64)?;
+            file.sync_data()?;
+        }
+        file.seek(SeekFrom::Start(valid_len as u64))?;
+
+        let next_sequence = records.last().map_or(1, |r| r.sequence + 1);
+        Ok((
+            Self {
+                file,
+                len: valid_len as u64,
+                next_sequence,
+            },
+            records,
+        ))
+    }
+
+    pub fn shared(self) -> SharedWriteAheadLog {
+        Arc::new(Mutex::new(self))
+    }
+
+    /// Makes sure records appended from now on are numbered after `sequence`
+    pub fn continue_after(&mut self, sequence: u64) {
+        self.next_sequence = self.next_sequence.max(sequence + 1);
+    }
+
+    /// Durably appends `transaction` to the log, returning its sequence number
+    pub fn append(
+        &mut self,
+        transaction: &Transaction,
+        fingerprint: Option<Fingerprint>,
+    ) -> Result<u64, WalError> {
+        #[derive(Serialize)]
+        struct RecordRef<'a> {
+            sequence: u64,
+            transaction: &'a Transaction,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            fingerprint: Option<Fingerprint>,
+        }
+
+        let sequence = self.next_sequence;
+        let payload = serde_json::to_vec(&RecordRef {
+            sequence,
+            transaction,
+            fingerprint,
+        })?;
+        let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+        frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        frame.extend_from_slice(&payload);
+
+        if let Err(e) = self
+            .file
+            .write_all(&frame)
+            .and_then(|_| self.file.sync_data())
+        {
+            // Don't leave a partial record behind, otherwise the records appended after it
+            // would be discarded as part of a torn tail when recovering
+            let _ = self.file.set_len(self.len);
+            let _ = self.file.seek(SeekFrom::Start(self.len));
+            return Err(e.into());
+        }
+        self.len += frame.len() as u64;
+        self.next_sequence += 1;
+
+        Ok(sequence)
+    }
+
+    /// Drops every record, e.g. after a snapshot including all of them has been saved
+    pub fn reset(&mut self) -> Result<(), WalError> {
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.sync_data()?;
+        self.len = 0;
+        Ok(())
+    }
+
+    /// Decodes all the valid records at the start of `contents`
+    ///
+    /// Also returns the length of the valid prefix
+    fn decode(contents: &[u8]) -> (Vec<WalRecord>, usize) {
+        let mut records = vec![];
+        let mut offset = 0;
+        while let Some(header) = contents.get(offset..offset + HEADER_LEN) {
+            let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+            let checksum = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+            let start = offset + HEADER_LEN;
+            let payload = match contents.get(start..start + len) {
+                Some(payload) if crc32fast::hash(payload) == checksum => payload,
+                _ => break,
+            };
+            match serde_json::from_slice(payload) {
+                Ok(record) => records.push(record),
+                Err(_) => break,
+            }
+            offset = start + len;
+        }
+
+        (records, offset)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::funds::Funds;
+    use crate::transaction::TransactionType;
+    use rust_decimal_macros::dec;
+    use std::path::PathBuf;
+
+    fn deposit(transaction: u32) -> Transaction {
+        Transaction {
+            tx_type: TransactionType::Deposit,
+            client: 1,
+            transaction,
+            amount: Some(Funds::new(dec!(1.5))),
+            ..Default::default()
+        }
+    }
+
+    fn log_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("txk-{}-{}.wal", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_reopen() {
+        let path = log_path("reopen");
+        let (mut wal, records) = WriteAheadLog::open(&path).expect("Open to succeed");
+        assert!(records.is_empty());
+        assert_eq!(wal.append(&deposit(1), None).expect("Append to succeed"), 1);
+        assert_eq!(wal.append(&deposit(2), None).expect("Append to succeed"), 2);
+        drop(wal);
+
+        let (mut wal, records) = WriteAheadLog::open(&path).expect("Open to succeed");
+        assert_eq!(
+            records,
+            vec![
+                WalRecord {
+                    sequence: 1,
+                    transaction: deposit(1),
+                    fingerprint: None,
+                },
+                WalRecord {
+                    sequence: 2,
+                    transaction: deposit(2),
+                    fingerprint: None,
+                },
+            ],
+        );
+        assert_eq!(wal.append(&deposit(3), None).expect("Append to succeed"), 3);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_torn_record_truncated() {
+        let path = log_path("torn");
+        let (mut wal, _) = WriteAheadLog::open(&path).expect("Open to succeed");
+        wal.append(&deposit(1), None).expect("Append to succeed");
+        wal.append(&deposit(2), None).expect("Append to succeed");
+        drop(wal);
+
+        // Simulate a crash halfway through writing the second record
+        let len = std::fs::metadata(&path).expect("Log to exist").len();
+        let file = OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .expect("Log to exist");
+        file.set_len(len - 3).expect("Truncate to succeed");
+        drop(file);
+
+        let (mut wal, records) = WriteAheadLog::open(&path).expect("Open to succeed");
+        assert_eq!(
+            records.iter().map(|r| r.sequence).collect::<Vec<_>>(),
+            vec![1]
+        );
+        // The torn record is replaced by the next append
+        assert_eq!(wal.append(&deposit(3), None).expect("Append to succeed"), 2);
+        drop(wal);
+
+        let (_, records) = WriteAheadLog::open(&path).expect("Open to succeed");
+        assert_eq!(
+            records
+                .iter()
+                .map(|r| r.transaction.transaction)
+                .collect::<Vec<_>>(),
+            vec![1, 3]
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_corrupt_record_truncated() {
+        let mut contents = vec![];
+        let payload = br#"{"sequence":1}"#;
+        contents.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        contents.extend_from_slice(&(crc32fast::hash(payload) ^ 1).to_le_bytes());
+        contents.extend_from_slice(payload);
+
+        assert_eq!(WriteAheadLog::decode(&contents), (vec![], 0));
+    }
+
+    #[test]
+    fn test_reset() {
+        let path = log_path("reset");
+        let (mut wal, _) = WriteAheadLog::open(&path).expect("Open to succeed");
+        wal.append(&deposit(1), None).expect("Append to succeed");
+        wal.reset().expect("Reset to succeed");
+        assert_eq!(wal.append(&deposit(2), None).expect("Append to succeed"), 2);
+        drop(wal);
+
+        let (_, records) = WriteAheadLog::open(&path).expect("Open to succeed");
+        assert_eq!(
+            records.iter().map(|r| r.sequence).collect::<Vec<_>>(),
+            vec![2]
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+}